@@ -0,0 +1,1095 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module holds every command a client can type. Each command is registered once in
+/// COMMANDS, with its aliases, usage, help text, and the layer that handles it. The text from the
+/// client is parsed into a typed Command here, so the connection, map & player only ever match on
+/// the enum instead of picking strings apart.
+
+use std::fmt;
+
+use conn::roles::Role;
+use game::characters::Ghost;
+
+/// Which part of the server handles a command
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Layer {
+    ///Handled by the Connection on the mio thread
+    Connection,
+    ///Handled by the GameMap on the game loop thread
+    Map,
+    ///Queued up on the Player and handled in its update
+    Player,
+}
+
+/// The tile a map edit, look or read applies to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EditTarget {
+    ///The tile the player is standing on
+    Here,
+    ///The tile dx dy away from the player
+    Offset(i32, i32),
+    ///The last tile the player clicked on
+    Clicked,
+}
+
+/// A parsed command, with its arguments
+#[derive(Clone, PartialEq, Debug)]
+pub enum Command {
+    EndKey,
+    Tile(i16),
+    Img(String),
+    Join(String, bool),
+    Shout(String),
+    Say(Option<u32>, String),
+    Tell(String, String),
+    ChannelJoin(String),
+    ChannelLeave(String),
+    ChannelSay(String, String),
+    Who(bool),
+    Reload,
+    Help(Option<String>),
+    Goto(Option<String>, u32, u32),
+    Summon(String),
+    Kick(String),
+    Mark(String),
+    Recall(String),
+    Where,
+    MapInfo,
+    Inspect(i32, i32),
+    ///None looks at the tiles around the player
+    Look(Option<EditTarget>),
+    Read(Option<EditTarget>),
+    ///None uses the first door next to the player
+    Open(Option<(i32, i32)>),
+    Close(Option<(i32, i32)>),
+    ///Answers an ask-map teleporter. None takes the teleporter's own map.
+    Answer(Option<String>),
+    ///Map editing. Paints terrain, places an arch, or erases the objects on a tile.
+    Paint(String, EditTarget),
+    Place(String, EditTarget),
+    Erase(EditTarget),
+    ///Adds a teleporter going to the map at x y
+    Teleporter(String, u32, u32, EditTarget),
+    Undo,
+    Redo,
+    SaveMap,
+    ///None toggles ghost mode on & off
+    Ghost(Option<Ghost>),
+    Mouse(i32, i32),
+    Skin(String),
+    Numpad(u32),
+    View(u8, u8),
+    ///Internal movement command. The player queues this up itself, so it can't be typed.
+    End(u32),
+}
+
+impl Command {
+    ///Whether the command changes the map. These can be undone.
+    pub fn is_edit(&self) -> bool {
+        match *self {
+            Command::Paint(..) | Command::Place(..) | Command::Erase(..) | Command::Teleporter(..) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Everything that can go wrong turning text into a command
+#[derive(Clone, PartialEq, Debug)]
+pub enum CommandError {
+    ///Nothing is registered under this name
+    Unknown(String),
+    ///The command exists, but the arguments didn't parse
+    Usage(&'static CommandSpec),
+    ///The user's role isn't high enough for the command
+    Denied(&'static CommandSpec),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Unknown(ref name) => {
+                write!(f, "Unknown command {}. Type help for a list of commands.", name)
+            },
+            CommandError::Usage(spec) => {
+                write!(f, "Usage: {}", spec.usage)
+            },
+            CommandError::Denied(spec) => {
+                write!(f, "You do not have permission to use {}", spec.name)
+            },
+        }
+    }
+}
+
+/// A single entry in the registry
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub layer: Layer,
+    ///The lowest role that can use it
+    pub role: Role,
+    ///Turns the arguments (everything after the name) into a command. None means bad arguments.
+    parse: fn(&str) -> Option<Command>,
+}
+
+impl PartialEq for CommandSpec {
+    fn eq(&self, other: &CommandSpec) -> bool {
+        self.name == other.name
+    }
+}
+
+impl fmt::Debug for CommandSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CommandSpec({})", self.name)
+    }
+}
+
+/// Every command the client can send
+pub static COMMANDS: &'static [CommandSpec] = &[
+    CommandSpec {
+        name: "help",
+        aliases: &["?", "commands"],
+        usage: "help [command]",
+        help: "Lists the commands, or shows the details of one",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_help,
+    },
+    CommandSpec {
+        name: "join",
+        aliases: &[],
+        usage: "join <map>[#instance] [private]",
+        help: "Moves to another map, or an instance of it",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_join,
+    },
+    CommandSpec {
+        name: "reload",
+        aliases: &[],
+        usage: "reload",
+        help: "Re-reads the current map and the art aliases from disk",
+        layer: Layer::Connection,
+        role: Role::Tester,
+        parse: parse_reload,
+    },
+    CommandSpec {
+        name: "skin",
+        aliases: &[],
+        usage: "skin <image>",
+        help: "Changes your character art, i.e. skin paladin or skin /monsters/dragon/snake_hydra",
+        layer: Layer::Player,
+        role: Role::Player,
+        parse: parse_skin,
+    },
+    CommandSpec {
+        name: "shout",
+        aliases: &["yell"],
+        usage: "shout <message>",
+        help: "Sends a message to everyone on the server",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_shout,
+    },
+    CommandSpec {
+        name: "say",
+        aliases: &[],
+        usage: "say [-r <tiles>] <message>",
+        help: "Talks to everyone on your map, or only those within a few tiles",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_say,
+    },
+    CommandSpec {
+        name: "tell",
+        aliases: &["msg", "whisper"],
+        usage: "tell <name> <message>",
+        help: "Sends a private message to one player",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_tell,
+    },
+    CommandSpec {
+        name: "channel",
+        aliases: &["ch"],
+        usage: "channel join <name> | channel leave <name> | channel <name> <message>",
+        help: "Joins, leaves or talks on a named chat channel",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_channel,
+    },
+    CommandSpec {
+        name: "who",
+        aliases: &[],
+        usage: "who [here]",
+        help: "Lists the players online, or just the ones on your map",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_who,
+    },
+    CommandSpec {
+        name: "mouse",
+        aliases: &[],
+        usage: "mouse <x> <y>",
+        help: "Walks to the clicked spot on the screen. Sent by the client.",
+        layer: Layer::Map,
+        role: Role::Player,
+        parse: parse_mouse,
+    },
+    CommandSpec {
+        name: "numpad",
+        aliases: &[],
+        usage: "numpad-<1-9>",
+        help: "Takes a step in a numpad direction. Sent by the client.",
+        layer: Layer::Player,
+        role: Role::Player,
+        parse: parse_numpad,
+    },
+    CommandSpec {
+        name: "#view",
+        aliases: &[],
+        usage: "#view <width> <height>",
+        help: "Changes the size of the screen sent down. Meant for custom clients.",
+        layer: Layer::Player,
+        role: Role::Player,
+        parse: parse_view,
+    },
+    CommandSpec {
+        name: "#tile",
+        aliases: &[],
+        usage: "#tile <number>",
+        help: "Asks for the path of a tile. Sent by the client.",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_tile,
+    },
+    CommandSpec {
+        name: "#img",
+        aliases: &[],
+        usage: "#img <image>",
+        help: "Asks for a custom image. Sent by the client.",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_img,
+    },
+    CommandSpec {
+        name: "end_key",
+        aliases: &[],
+        usage: "end_key",
+        help: "Sent by the client when the end key is hit",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_end_key,
+    },
+    CommandSpec {
+        name: "goto",
+        aliases: &["tp"],
+        usage: "goto [map] <x> <y>",
+        help: "Jumps to a spot on this map, or on another one",
+        layer: Layer::Connection,
+        role: Role::Admin,
+        parse: parse_goto,
+    },
+    CommandSpec {
+        name: "summon",
+        aliases: &[],
+        usage: "summon <name>",
+        help: "Brings another player to where you are standing",
+        layer: Layer::Connection,
+        role: Role::Admin,
+        parse: parse_summon,
+    },
+    CommandSpec {
+        name: "kick",
+        aliases: &[],
+        usage: "kick <name>",
+        help: "Disconnects a player",
+        layer: Layer::Connection,
+        role: Role::Admin,
+        parse: parse_kick,
+    },
+    CommandSpec {
+        name: "mark",
+        aliases: &[],
+        usage: "mark [name]",
+        help: "Saves where you are standing, so you can recall to it later",
+        layer: Layer::Connection,
+        role: Role::Admin,
+        parse: parse_mark,
+    },
+    CommandSpec {
+        name: "recall",
+        aliases: &[],
+        usage: "recall [name]",
+        help: "Jumps back to a marked spot",
+        layer: Layer::Connection,
+        role: Role::Admin,
+        parse: parse_recall,
+    },
+    CommandSpec {
+        name: "where",
+        aliases: &["loc"],
+        usage: "where",
+        help: "Shows the map & x y you are standing on",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_where,
+    },
+    CommandSpec {
+        name: "mapinfo",
+        aliases: &["info"],
+        usage: "mapinfo",
+        help: "Shows the name, size, danger level & other header settings of the map you are on",
+        layer: Layer::Map,
+        role: Role::Player,
+        parse: parse_mapinfo,
+    },
+    CommandSpec {
+        name: "inspect",
+        aliases: &[],
+        usage: "inspect [dx dy]",
+        help: "Shows what the server has on your tile, or the one dx dy away from you",
+        layer: Layer::Map,
+        role: Role::Tester,
+        parse: parse_inspect,
+    },
+    CommandSpec {
+        name: "look",
+        aliases: &["examine", "l"],
+        usage: "look [dx dy | click]",
+        help: "Describes the object on the tile dx dy away from you or the one you last clicked, or the closest one next to you",
+        layer: Layer::Map,
+        role: Role::Player,
+        parse: parse_look,
+    },
+    CommandSpec {
+        name: "read",
+        aliases: &[],
+        usage: "read [dx dy | click]",
+        help: "Reads a sign or anything else with writing on it, next to you, dx dy away or on the tile you last clicked",
+        layer: Layer::Map,
+        role: Role::Player,
+        parse: parse_read,
+    },
+    CommandSpec {
+        name: "answer",
+        aliases: &["go"],
+        usage: "answer [map]",
+        help: "Picks where a teleporter that asked you should go. Leave off the map to take its suggestion",
+        layer: Layer::Connection,
+        role: Role::Player,
+        parse: parse_answer,
+    },
+    CommandSpec {
+        name: "open",
+        aliases: &[],
+        usage: "open [dx dy]",
+        help: "Opens a door next to you",
+        layer: Layer::Map,
+        role: Role::Player,
+        parse: parse_open,
+    },
+    CommandSpec {
+        name: "close",
+        aliases: &["shut"],
+        usage: "close [dx dy]",
+        help: "Closes a door next to you",
+        layer: Layer::Map,
+        role: Role::Player,
+        parse: parse_close,
+    },
+    CommandSpec {
+        name: "paint",
+        aliases: &[],
+        usage: "paint <terrain> [dx dy | click]",
+        help: "Paints terrain, i.e. terrain/grass, on your tile, the one dx dy away, or the last one you clicked",
+        layer: Layer::Map,
+        role: Role::Tester,
+        parse: parse_paint,
+    },
+    CommandSpec {
+        name: "place",
+        aliases: &[],
+        usage: "place <arch> [dx dy | click]",
+        help: "Places an arch, i.e. scenery/willow or walls/castle_wall, on your tile, the one dx dy away, or the last one you clicked",
+        layer: Layer::Map,
+        role: Role::Tester,
+        parse: parse_place,
+    },
+    CommandSpec {
+        name: "erase",
+        aliases: &[],
+        usage: "erase [dx dy | click]",
+        help: "Removes the objects & teleporter from your tile, the one dx dy away, or the last one you clicked",
+        layer: Layer::Map,
+        role: Role::Tester,
+        parse: parse_erase,
+    },
+    CommandSpec {
+        name: "teleporter",
+        aliases: &[],
+        usage: "teleporter <map> <x> <y> [dx dy | click]",
+        help: "Adds a teleporter to x y on the map, on your tile, the one dx dy away, or the last one you clicked",
+        layer: Layer::Map,
+        role: Role::Tester,
+        parse: parse_teleporter,
+    },
+    CommandSpec {
+        name: "undo",
+        aliases: &[],
+        usage: "undo",
+        help: "Takes back the last edit made to this map",
+        layer: Layer::Map,
+        role: Role::Tester,
+        parse: parse_undo,
+    },
+    CommandSpec {
+        name: "redo",
+        aliases: &[],
+        usage: "redo",
+        help: "Puts back the last edit that was undone",
+        layer: Layer::Map,
+        role: Role::Tester,
+        parse: parse_redo,
+    },
+    CommandSpec {
+        name: "savemap",
+        aliases: &[],
+        usage: "savemap",
+        help: "Writes this map, with every edit, back to its file",
+        layer: Layer::Map,
+        role: Role::Tester,
+        parse: parse_savemap,
+    },
+    CommandSpec {
+        name: "ghost",
+        aliases: &["noclip"],
+        usage: "ghost [on|off|hidden]",
+        help: "Walks through walls & other players. hidden also keeps non-admins from seeing you",
+        layer: Layer::Player,
+        role: Role::Tester,
+        parse: parse_ghost,
+    },
+];
+
+///Finds the registry entry for a name or alias
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    for spec in COMMANDS.iter() {
+        if spec.name == name || spec.aliases.contains(&name) {
+            return Some(spec);
+        }
+    }
+    None
+}
+
+///Parses a line from the client into a command, checking the user is allowed to run it. The
+///registry entry comes back too, so the caller knows which layer handles the command.
+pub fn parse_spec(input: &str, role: Role) -> Result<(&'static CommandSpec, Command), CommandError> {
+    let input = input.trim();
+    //The numpad is sent as numpad-<key>, with no space
+    let (name, args) = if input.starts_with("numpad-") {
+        (&input[..6], &input[7..])
+    } else {
+        match input.find(char::is_whitespace) {
+            Some(i) => {
+                (&input[..i], &input[i..])
+            },
+            None => {
+                (input, "")
+            },
+        }
+    };
+    let args = args.trim();
+    match find(&name.to_lowercase()) {
+        Some(spec) if spec.role > role => {
+            Err(CommandError::Denied(spec))
+        },
+        Some(spec) => {
+            match (spec.parse)(args) {
+                Some(command) => Ok((spec, command)),
+                None => Err(CommandError::Usage(spec)),
+            }
+        },
+        None => {
+            Err(CommandError::Unknown(name.to_string()))
+        },
+    }
+}
+
+///Parses a line from the client into a command, checking the user is allowed to run it.
+pub fn parse_as(input: &str, role: Role) -> Result<Command, CommandError> {
+    parse_spec(input, role).map(|(_, command)| command)
+}
+
+///Parses a line from the client into a command, without checking permissions.
+pub fn parse(input: &str) -> Result<Command, CommandError> {
+    parse_as(input, Role::Admin)
+}
+
+///Builds the help text. With no topic it lists every command the role can use, otherwise it shows
+///the usage and aliases for the one command.
+pub fn help(topic: Option<&str>, role: Role) -> String {
+    match topic {
+        Some(name) => {
+            match find(&name.to_lowercase()) {
+                Some(spec) if spec.role <= role => {
+                    let mut lines = vec![format!("{} - {}", spec.name, spec.help),
+                                         format!("Usage: {}", spec.usage)];
+                    if spec.aliases.len() > 0 {
+                        lines.push(format!("Aliases: {}", spec.aliases.join(", ")));
+                    }
+                    lines.join("\n")
+                },
+                _ => {
+                    format!("No help for {}", name)
+                },
+            }
+        },
+        None => {
+            let mut lines = vec!["Commands:".to_string()];
+            for spec in COMMANDS.iter().filter(|s| s.role <= role) {
+                lines.push(format!("  {} - {}", spec.usage, spec.help));
+            }
+            lines.join("\n")
+        },
+    }
+}
+
+///Splits the first word off of the arguments
+fn first_word(args: &str) -> (&str, &str) {
+    match args.find(char::is_whitespace) {
+        Some(i) => {
+            (&args[..i], args[i..].trim())
+        },
+        None => {
+            (args, "")
+        },
+    }
+}
+
+fn parse_help(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        Some(Command::Help(None))
+    } else {
+        Some(Command::Help(Some(args.to_string())))
+    }
+}
+
+fn parse_join(args: &str) -> Option<Command> {
+    let (map, rest) = first_word(args);
+    if map.len() == 0 {
+        None
+    } else if rest.len() == 0 {
+        Some(Command::Join(map.to_string(), false))
+    } else if rest == "private" {
+        Some(Command::Join(map.to_string(), true))
+    } else {
+        None
+    }
+}
+
+fn parse_reload(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        Some(Command::Reload)
+    } else {
+        None
+    }
+}
+
+fn parse_skin(args: &str) -> Option<Command> {
+    if args.len() == 0 || args.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(Command::Skin(args.to_string()))
+    }
+}
+
+fn parse_shout(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        None
+    } else {
+        Some(Command::Shout(args.to_string()))
+    }
+}
+
+fn parse_say(args: &str) -> Option<Command> {
+    let (first, rest) = first_word(args);
+    if first == "-r" {
+        let (radius, message) = first_word(rest);
+        match radius.parse::<u32>() {
+            Ok(r) if message.len() > 0 => {
+                Some(Command::Say(Some(r), message.to_string()))
+            },
+            _ => {
+                None
+            },
+        }
+    } else if args.len() > 0 {
+        Some(Command::Say(None, args.to_string()))
+    } else {
+        None
+    }
+}
+
+fn parse_tell(args: &str) -> Option<Command> {
+    let (name, message) = first_word(args);
+    if name.len() == 0 || message.len() == 0 {
+        None
+    } else {
+        Some(Command::Tell(name.to_string(), message.to_string()))
+    }
+}
+
+fn parse_channel(args: &str) -> Option<Command> {
+    let (first, rest) = first_word(args);
+    if first.len() == 0 || rest.len() == 0 {
+        None
+    } else if first == "join" {
+        Some(Command::ChannelJoin(rest.to_lowercase()))
+    } else if first == "leave" {
+        Some(Command::ChannelLeave(rest.to_lowercase()))
+    } else {
+        Some(Command::ChannelSay(first.to_lowercase(), rest.to_string()))
+    }
+}
+
+fn parse_who(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        Some(Command::Who(false))
+    } else if args == "here" {
+        Some(Command::Who(true))
+    } else {
+        None
+    }
+}
+
+fn parse_mouse(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    if parts.len() < 2 {
+        return None;
+    }
+    match (parts[0].parse::<i32>(), parts[1].parse::<i32>()) {
+        (Ok(x), Ok(y)) => Some(Command::Mouse(x, y)),
+        _ => None,
+    }
+}
+
+fn parse_numpad(args: &str) -> Option<Command> {
+    match args.parse::<u32>() {
+        Ok(key) if key >= 1 && key <= 9 => Some(Command::Numpad(key)),
+        _ => None,
+    }
+}
+
+fn parse_view(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    if parts.len() != 2 {
+        return None;
+    }
+    match (parts[0].parse::<u8>(), parts[1].parse::<u8>()) {
+        (Ok(x), Ok(y)) => Some(Command::View(x, y)),
+        _ => None,
+    }
+}
+
+fn parse_tile(args: &str) -> Option<Command> {
+    match args.parse::<i16>() {
+        Ok(tile) => Some(Command::Tile(tile)),
+        Err(_) => None,
+    }
+}
+
+fn parse_img(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        None
+    } else {
+        Some(Command::Img(args.to_string()))
+    }
+}
+
+fn parse_end_key(_: &str) -> Option<Command> {
+    Some(Command::EndKey)
+}
+
+fn parse_goto(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    let (map, x, y) = match parts.len() {
+        2 => (None, parts[0], parts[1]),
+        3 => (Some(parts[0].to_string()), parts[1], parts[2]),
+        _ => {
+            return None;
+        },
+    };
+    match (x.parse::<u32>(), y.parse::<u32>()) {
+        (Ok(x), Ok(y)) => Some(Command::Goto(map, x, y)),
+        _ => None,
+    }
+}
+
+fn parse_summon(args: &str) -> Option<Command> {
+    if args.len() == 0 || args.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(Command::Summon(args.to_string()))
+    }
+}
+
+fn parse_kick(args: &str) -> Option<Command> {
+    if args.len() == 0 || args.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(Command::Kick(args.to_string()))
+    }
+}
+
+///Marks default to "default", so a bare mark & recall just work
+fn mark_name(args: &str) -> Option<String> {
+    if args.len() == 0 {
+        Some("default".to_string())
+    } else if args.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(args.to_lowercase())
+    }
+}
+
+fn parse_mark(args: &str) -> Option<Command> {
+    mark_name(args).map(Command::Mark)
+}
+
+fn parse_recall(args: &str) -> Option<Command> {
+    mark_name(args).map(Command::Recall)
+}
+
+fn parse_ghost(args: &str) -> Option<Command> {
+    match &args.to_lowercase()[..] {
+        "" => Some(Command::Ghost(None)),
+        "on" => Some(Command::Ghost(Some(Ghost::On))),
+        "off" => Some(Command::Ghost(Some(Ghost::Off))),
+        "hidden" => Some(Command::Ghost(Some(Ghost::Hidden))),
+        _ => None,
+    }
+}
+
+fn parse_where(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        Some(Command::Where)
+    } else {
+        None
+    }
+}
+
+fn parse_mapinfo(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        Some(Command::MapInfo)
+    } else {
+        None
+    }
+}
+
+fn parse_inspect(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    match parts.len() {
+        0 => Some(Command::Inspect(0, 0)),
+        2 => {
+            match (parts[0].parse::<i32>(), parts[1].parse::<i32>()) {
+                (Ok(dx), Ok(dy)) => Some(Command::Inspect(dx, dy)),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+///Reads an optional dx dy. The outer None means the arguments were bad.
+fn parse_offset(args: &str) -> Option<Option<(i32, i32)>> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    match parts.len() {
+        0 => Some(None),
+        2 => {
+            match (parts[0].parse::<i32>(), parts[1].parse::<i32>()) {
+                (Ok(dx), Ok(dy)) => Some(Some((dx, dy))),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+///Reads the optional tile after look & read. Without one, they check around the player.
+fn parse_look_target(args: &str) -> Option<Option<EditTarget>> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    if parts.len() == 0 {
+        Some(None)
+    } else {
+        parse_target(&parts).map(Some)
+    }
+}
+
+fn parse_look(args: &str) -> Option<Command> {
+    parse_look_target(args).map(Command::Look)
+}
+
+fn parse_read(args: &str) -> Option<Command> {
+    parse_look_target(args).map(Command::Read)
+}
+
+fn parse_answer(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    match parts.len() {
+        0 => Some(Command::Answer(None)),
+        1 => Some(Command::Answer(Some(parts[0].to_string()))),
+        _ => None,
+    }
+}
+
+fn parse_open(args: &str) -> Option<Command> {
+    parse_offset(args).map(Command::Open)
+}
+
+fn parse_close(args: &str) -> Option<Command> {
+    parse_offset(args).map(Command::Close)
+}
+
+///Reads the optional tile at the end of an edit command
+fn parse_target(parts: &[&str]) -> Option<EditTarget> {
+    match parts.len() {
+        0 => Some(EditTarget::Here),
+        1 if parts[0] == "click" => Some(EditTarget::Clicked),
+        2 => {
+            match (parts[0].parse::<i32>(), parts[1].parse::<i32>()) {
+                (Ok(dx), Ok(dy)) => Some(EditTarget::Offset(dx, dy)),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+fn parse_paint(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    if parts.len() == 0 {
+        return None;
+    }
+    parse_target(&parts[1..]).map(|target| Command::Paint(parts[0].to_string(), target))
+}
+
+fn parse_place(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    if parts.len() == 0 {
+        return None;
+    }
+    parse_target(&parts[1..]).map(|target| Command::Place(parts[0].to_string(), target))
+}
+
+fn parse_erase(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    parse_target(&parts).map(Command::Erase)
+}
+
+fn parse_teleporter(args: &str) -> Option<Command> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    if parts.len() < 3 {
+        return None;
+    }
+    match (parts[1].parse::<u32>(), parts[2].parse::<u32>(), parse_target(&parts[3..])) {
+        (Ok(x), Ok(y), Some(target)) => Some(Command::Teleporter(parts[0].to_string(), x, y, target)),
+        _ => None,
+    }
+}
+
+fn parse_undo(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        Some(Command::Undo)
+    } else {
+        None
+    }
+}
+
+fn parse_redo(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        Some(Command::Redo)
+    } else {
+        None
+    }
+}
+
+fn parse_savemap(args: &str) -> Option<Command> {
+    if args.len() == 0 {
+        Some(Command::SaveMap)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_simple_commands() {
+        assert_eq!(parse("reload"), Ok(Command::Reload));
+        assert_eq!(parse("who"), Ok(Command::Who(false)));
+        assert_eq!(parse("who here"), Ok(Command::Who(true)));
+        assert_eq!(parse("end_key"), Ok(Command::EndKey));
+        assert_eq!(parse("  shout hello there  "), Ok(Command::Shout("hello there".to_string())));
+    }
+
+    #[test]
+    fn names_private_instances() {
+        use game::Game;
+        let map = Game::private_instance("cave", "Sir Bob");
+        assert_eq!(map, "cave#private-sir-20bob");
+        assert!(Game::valid_instance(&map));
+        assert_eq!(Game::private_owner(&map), Some("sir-20bob"));
+        assert_eq!(Game::private_owner("cave#alice"), None);
+        assert_eq!(Game::private_owner("cave"), None);
+    }
+
+    #[test]
+    fn parses_arguments() {
+        assert_eq!(parse("join cave"), Ok(Command::Join("cave".to_string(), false)));
+        assert_eq!(parse("join cave private"), Ok(Command::Join("cave".to_string(), true)));
+        assert_eq!(parse("join cave#alice"), Ok(Command::Join("cave#alice".to_string(), false)));
+        assert_eq!(parse("skin /monsters/dragon/snake_hydra"),
+                   Ok(Command::Skin("/monsters/dragon/snake_hydra".to_string())));
+        assert_eq!(parse("mouse 3 -2"), Ok(Command::Mouse(3, -2)));
+        assert_eq!(parse("#view 21 15"), Ok(Command::View(21, 15)));
+        assert_eq!(parse("#tile 42"), Ok(Command::Tile(42)));
+        assert_eq!(parse("#img custom_tree"), Ok(Command::Img("custom_tree".to_string())));
+        assert_eq!(parse("goto 4 7"), Ok(Command::Goto(None, 4, 7)));
+        assert_eq!(parse("goto cave 4 7"), Ok(Command::Goto(Some("cave".to_string()), 4, 7)));
+        assert_eq!(parse("mark"), Ok(Command::Mark("default".to_string())));
+        assert_eq!(parse("recall Shop"), Ok(Command::Recall("shop".to_string())));
+        assert_eq!(parse("where"), Ok(Command::Where));
+        assert_eq!(parse("mapinfo"), Ok(Command::MapInfo));
+        assert_eq!(parse("mapinfo main"), Err(CommandError::Usage(find("mapinfo").unwrap())));
+        assert_eq!(parse("inspect"), Ok(Command::Inspect(0, 0)));
+        assert_eq!(parse("inspect -1 2"), Ok(Command::Inspect(-1, 2)));
+        assert_eq!(parse("ghost"), Ok(Command::Ghost(None)));
+        assert_eq!(parse("noclip hidden"), Ok(Command::Ghost(Some(Ghost::Hidden))));
+        assert_eq!(parse("look"), Ok(Command::Look(None)));
+        assert_eq!(parse("l 0 -1"), Ok(Command::Look(Some(EditTarget::Offset(0, -1)))));
+        assert_eq!(parse("look click"), Ok(Command::Look(Some(EditTarget::Clicked))));
+        assert_eq!(parse("read 1 0"), Ok(Command::Read(Some(EditTarget::Offset(1, 0)))));
+        assert_eq!(parse("read click"), Ok(Command::Read(Some(EditTarget::Clicked))));
+        assert!(parse("look 1").is_err());
+        assert_eq!(parse("open"), Ok(Command::Open(None)));
+        assert_eq!(parse("answer"), Ok(Command::Answer(None)));
+        assert_eq!(parse("go cave"), Ok(Command::Answer(Some("cave".to_string()))));
+        assert_eq!(parse("shut -1 0"), Ok(Command::Close(Some((-1, 0)))));
+        assert_eq!(parse("paint terrain/grass"), Ok(Command::Paint("terrain/grass".to_string(), EditTarget::Here)));
+        assert_eq!(parse("place scenery/willow 1 -2"),
+                   Ok(Command::Place("scenery/willow".to_string(), EditTarget::Offset(1, -2))));
+        assert_eq!(parse("erase click"), Ok(Command::Erase(EditTarget::Clicked)));
+        assert_eq!(parse("teleporter cave 3 4"),
+                   Ok(Command::Teleporter("cave".to_string(), 3, 4, EditTarget::Here)));
+        assert_eq!(parse("teleporter cave 3"), Err(CommandError::Usage(find("teleporter").unwrap())));
+        assert_eq!(parse("savemap"), Ok(Command::SaveMap));
+    }
+
+    #[test]
+    fn parses_numpad() {
+        assert_eq!(parse("numpad-8"), Ok(Command::Numpad(8)));
+        assert_eq!(parse("numpad-0"), Err(CommandError::Usage(find("numpad").unwrap())));
+        assert_eq!(parse("numpad-up"), Err(CommandError::Usage(find("numpad").unwrap())));
+    }
+
+    #[test]
+    fn parses_chat() {
+        assert_eq!(parse("say hi all"), Ok(Command::Say(None, "hi all".to_string())));
+        assert_eq!(parse("say -r 5 hi"), Ok(Command::Say(Some(5), "hi".to_string())));
+        assert_eq!(parse("tell Bob where are you"),
+                   Ok(Command::Tell("Bob".to_string(), "where are you".to_string())));
+        assert_eq!(parse("channel join Builders"), Ok(Command::ChannelJoin("builders".to_string())));
+        assert_eq!(parse("channel leave builders"), Ok(Command::ChannelLeave("builders".to_string())));
+        assert_eq!(parse("channel builders on my way"),
+                   Ok(Command::ChannelSay("builders".to_string(), "on my way".to_string())));
+    }
+
+    #[test]
+    fn resolves_aliases() {
+        assert_eq!(parse("msg Bob hi"), parse("tell Bob hi"));
+        assert_eq!(parse("yell hi"), Ok(Command::Shout("hi".to_string())));
+        assert_eq!(parse("?"), Ok(Command::Help(None)));
+        assert_eq!(parse("help tell"), Ok(Command::Help(Some("tell".to_string()))));
+    }
+
+    #[test]
+    fn reports_usage_errors() {
+        assert_eq!(parse("#view 21"), Err(CommandError::Usage(find("#view").unwrap())));
+        assert_eq!(parse("#view big small"), Err(CommandError::Usage(find("#view").unwrap())));
+        assert_eq!(parse("mouse left"), Err(CommandError::Usage(find("mouse").unwrap())));
+        assert_eq!(parse("say -r far hi"), Err(CommandError::Usage(find("say").unwrap())));
+        assert_eq!(parse("tell Bob"), Err(CommandError::Usage(find("tell").unwrap())));
+        assert_eq!(parse("join"), Err(CommandError::Usage(find("join").unwrap())));
+        assert_eq!(parse("who there"), Err(CommandError::Usage(find("who").unwrap())));
+        assert_eq!(parse("goto cave 4"), Err(CommandError::Usage(find("goto").unwrap())));
+        assert_eq!(parse("read 1"), Err(CommandError::Usage(find("read").unwrap())));
+    }
+
+    #[test]
+    fn reports_unknown_commands() {
+        assert_eq!(parse("dance"), Err(CommandError::Unknown("dance".to_string())));
+        assert_eq!(parse("end 12"), Err(CommandError::Unknown("end".to_string())));
+    }
+
+    #[test]
+    fn checks_roles() {
+        assert_eq!(parse_as("kick bob", Role::Player), Err(CommandError::Denied(find("kick").unwrap())));
+        assert_eq!(parse_as("kick bob", Role::Tester), Err(CommandError::Denied(find("kick").unwrap())));
+        assert_eq!(parse_as("kick bob", Role::Admin), Ok(Command::Kick("bob".to_string())));
+        assert_eq!(parse_as("reload", Role::Player), Err(CommandError::Denied(find("reload").unwrap())));
+        assert_eq!(parse_as("reload", Role::Tester), Ok(Command::Reload));
+        assert_eq!(parse_as("undo", Role::Player), Err(CommandError::Denied(find("undo").unwrap())));
+        assert_eq!(parse_as("undo", Role::Tester), Ok(Command::Undo));
+        assert_eq!(parse_as("say hi", Role::Player), Ok(Command::Say(None, "hi".to_string())));
+    }
+
+    #[test]
+    fn assigns_layers() {
+        let layer = |input| parse_spec(input, Role::Admin).unwrap().0.layer;
+        assert_eq!(layer("mouse 1 1"), Layer::Map);
+        assert_eq!(layer("inspect"), Layer::Map);
+        assert_eq!(layer("paint terrain/grass"), Layer::Map);
+        assert_eq!(layer("open"), Layer::Map);
+        assert_eq!(layer("numpad-2"), Layer::Player);
+        assert_eq!(layer("skin mage"), Layer::Player);
+        assert_eq!(layer("ghost"), Layer::Player);
+        assert_eq!(layer("shout hi"), Layer::Connection);
+        assert_eq!(layer("join cave"), Layer::Connection);
+        assert_eq!(layer("l 0 -1"), Layer::Map);
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let text = help(None, Role::Admin);
+        for spec in COMMANDS.iter() {
+            assert!(text.contains(spec.usage), "missing {}", spec.name);
+        }
+        assert!(help(Some("whisper"), Role::Player).contains("tell <name> <message>"));
+        assert_eq!(help(Some("dance"), Role::Player), "No help for dance");
+    }
+
+    #[test]
+    fn help_hides_commands_above_role() {
+        let text = help(None, Role::Player);
+        assert!(!text.contains("summon <name>"));
+        assert!(text.contains("who [here]"));
+        assert_eq!(help(Some("kick"), Role::Tester), "No help for kick");
+    }
+}
@@ -15,4 +15,7 @@ limitations under the License.*/
 
 ///This just declares a couple more modules
 pub mod server;
-pub mod api;
\ No newline at end of file
+pub mod api;
+pub mod store;
+pub mod command;
+pub mod roles;
\ No newline at end of file
@@ -0,0 +1,87 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module handles permission levels. Roles are handed out by name in roles.txt, one
+/// "name role" pair per line. Anyone not listed is a regular player.
+
+use std::io::prelude::*;
+use std::fs::File;
+use std::io::BufReader;
+
+const ROLES_FILE: &'static str = "roles.txt";
+
+/// What a user is allowed to do. Each role can do everything the ones before it can.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum Role {
+    Player,
+    Tester,
+    Admin,
+}
+
+impl Role {
+    ///Reads a role from its name in the roles file
+    pub fn from_name(name: &str) -> Option<Role> {
+        match &name.to_lowercase()[..] {
+            "player" => Some(Role::Player),
+            "tester" => Some(Role::Tester),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    ///Gets the name shown to users
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Role::Player => "player",
+            Role::Tester => "tester",
+            Role::Admin => "admin",
+        }
+    }
+
+    ///Looks up the role for a user. The file is read every time, so edits apply on the next login.
+    pub fn lookup(user: &str) -> Role {
+        let file = match File::open(ROLES_FILE) {
+            Ok(f) => f,
+            Err(_) => {
+                return Role::Player;
+            },
+        };
+        let reader = BufReader::new(file);
+        for line in reader.lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => {
+                    break;
+                },
+            };
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() == 2 && parts[0].to_lowercase() == user.to_lowercase() {
+                match Role::from_name(parts[1]) {
+                    Some(role) => {
+                        return role;
+                    },
+                    None => {
+                        println!("Unknown role {} for {}", parts[1], parts[0]);
+                    },
+                }
+            }
+        }
+        Role::Player
+    }
+}
@@ -18,9 +18,15 @@ extern crate time;
 extern crate slab;
 extern crate glob;
 
-use game::gamemap::MapScreen;
+use game::gamemap::{MapScreen, TileReport};
 use game::Game;
+use game::aliases;
 use conn::api::Api;
+use conn::store::{PlayerRecord, safe_name};
+use conn::command;
+use conn::command::{Command, Layer};
+use conn::roles::Role;
+use game::characters::Ghost;
 
 use glob::glob;
 use mio::tcp::*;
@@ -35,9 +41,12 @@ use flate2::write::ZlibEncoder;
 
 use std::io::prelude::*;
 use std::fs::File;
+use std::net::Shutdown;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::mpsc::{Sender, Receiver, channel};
+use std::time::{Duration, Instant};
 use std;
 
 /// This module contains all of the client facing code. It handles all of the MIO stuff, and user
@@ -52,23 +61,45 @@ use std;
 //Setting the server as the first token
 pub const SERVER: mio::Token = mio::Token(0);
 pub const TIMEOUT: mio::Token = mio::Token(1);
+//How often the images directory is checked for new or changed art
+const IMAGE_CHECK_SECS: u64 = 2;
+//Text styles for the chat commands, so the client can tell them apart
+const STYLE_SAY: u8 = 0;
+const STYLE_TELL: u8 = 1;
+const STYLE_CHANNEL: u8 = 2;
+//How often everyone's state gets saved, in case the server goes down without a clean quit
+const SAVE_SECS: u64 = 60;
 
 /// enum for the current state of the connection. Not Logged in, Logged in, and Closed.
 enum State {
     NotLoggedIn,
     LoggedIn,
+    //Kicked. Still flushing the last messages, but ignores anything the client sends.
+    Closed,
 }
 
 /// enum for passing messages between connection & game loop. These are handled in the notify
 /// method of the mio Handler
 pub enum Msg {
-    Command(mio::Token, String),
+    Command(mio::Token, Command),
     SendCommand(mio::Token, Sender<Msg>),
     TextOutput(mio::Token, u8, String),
     Shout(String),
+    Say(mio::Token, Option<u32>, String),
+    Tell(mio::Token, String, String),
+    Channel(mio::Token, String, String),
+    Who(mio::Token, bool),
+    Summon(mio::Token, String),
+    Kick(mio::Token, String),
     Screen(mio::Token, MapScreen),
+    Inspect(mio::Token, TileReport),
     Hp(mio::Token, i32),
-    Join(mio::Token, String, Option<(u8, u8)>),
+    Position(mio::Token, u32, u32),
+    Join(mio::Token, String, Option<(u32, u32)>),
+    ///The player stepped on an ask-map teleporter. Has the teleporter's map as a suggestion.
+    AskMap(mio::Token, String),
+    ///The player walked off the ask-map teleporter before answering
+    CancelAsk(mio::Token),
 }
 
 /// This server maintain holds the tcp server, as well as a collection of all the current client
@@ -79,6 +110,8 @@ pub struct Server {
     connections: Slab<Connection>,
     games: Arc<RefCell<Game>>,
     recv: Receiver<Msg>,
+    last_image_check: Instant,
+    last_save: Instant,
 }
 
 impl Server {
@@ -91,6 +124,178 @@ impl Server {
             connections: slab,
             games: Arc::new(RefCell::new(Game::new(s.clone()))),
             recv: r,
+            last_image_check: Instant::now(),
+            last_save: Instant::now(),
+        }
+    }
+
+    ///Sends a message to everyone on the same game loop as the speaker. With a radius, only
+    ///players within that many tiles hear it.
+    fn say(&mut self, event_loop: &mut mio::EventLoop<Server>, token: mio::Token, radius: Option<u32>, msg: &str) {
+        let m = format!("{} says: {}", self.connections[token].name, msg);
+        let map = self.connections[token].map.clone();
+        let position = self.connections[token].position;
+        let mut tokens = vec![];
+        for c in self.connections.iter() {
+            if !c.is_logged_in() || c.map != map {
+                continue;
+            }
+            let in_range = match (radius, position, c.position) {
+                (Some(r), Some((x, y)), Some((cx, cy))) => {
+                    (x as i32 - cx as i32).abs() as u32 <= r && (y as i32 - cy as i32).abs() as u32 <= r
+                },
+                (Some(_), _, _) => {
+                    c.token == token
+                },
+                (None, _, _) => {
+                    true
+                },
+            };
+            if in_range {
+                tokens.push(c.token);
+            }
+        }
+        for t in tokens {
+            self.connections[t].write_text_out(STYLE_SAY, &m);
+            self.connections[t].reregister_writable(event_loop);
+        }
+    }
+
+    ///Sends a private message to a single player, by name.
+    fn tell(&mut self, event_loop: &mut mio::EventLoop<Server>, token: mio::Token, name: &str, msg: &str) {
+        match self.find_by_name(name) {
+            Some(t) => {
+                let from = format!("{} tells you: {}", self.connections[token].name, msg);
+                let to = format!("You tell {}: {}", self.connections[t].name, msg);
+                self.connections[t].write_text_out(STYLE_TELL, &from);
+                self.connections[t].reregister_writable(event_loop);
+                self.connections[token].write_text_out(STYLE_TELL, &to);
+            },
+            None => {
+                self.connections[token].write_text_out(5, &format!("{} is not online", name));
+            },
+        }
+        self.connections[token].reregister_writable(event_loop);
+    }
+
+    ///Lists who is online & where. When here is set, it only lists the players in the same game
+    ///loop as the one asking.
+    fn who(&mut self, event_loop: &mut mio::EventLoop<Server>, token: mio::Token, here: bool) {
+        let mut lines = vec![];
+        let viewer = self.connections[token].role;
+        if here {
+            let map = self.connections[token].map.clone();
+            let members = match self.games.borrow().find_game_loop(&Game::loop_name(&map)) {
+                Some(game_loop) => {
+                    game_loop.borrow().members()
+                },
+                None => {
+                    vec![]
+                },
+            };
+            for member in members {
+                if self.connections.contains(member) && self.connections[member].is_listed_for(token, viewer) {
+                    lines.push(format!("  {}", self.connections[member].name));
+                }
+            }
+            lines.sort();
+            lines.insert(0, format!("Players on {} ({}):", map, lines.len()));
+        } else {
+            for c in self.connections.iter() {
+                if c.is_logged_in() && c.is_listed_for(token, viewer) {
+                    lines.push(format!("  {} - {}", c.name, c.map));
+                }
+            }
+            lines.sort();
+            lines.insert(0, format!("Players online ({}):", lines.len()));
+        }
+        self.connections[token].write_text_out(3, &lines.join("\n"));
+        self.connections[token].reregister_writable(event_loop);
+    }
+
+    ///Finds a logged in connection by name
+    fn find_by_name(&self, name: &str) -> Option<mio::Token> {
+        for c in self.connections.iter() {
+            if c.is_logged_in() && c.name.to_lowercase() == name.to_lowercase() {
+                return Some(c.token);
+            }
+        }
+        None
+    }
+
+    ///Pulls a player over to the summoner's map & position.
+    fn summon(&mut self, event_loop: &mut mio::EventLoop<Server>, token: mio::Token, name: &str) {
+        match self.find_by_name(name) {
+            Some(t) if t == token => {
+                self.connections[token].write_text_out(5, "You can't summon yourself");
+            },
+            Some(t) => {
+                let map = self.connections[token].map.clone();
+                let position = self.connections[token].position;
+                let by = format!("You were summoned by {}", self.connections[token].name);
+                let name = self.connections[t].name.clone();
+                match self.connections[t].join(&map, position) {
+                    Ok(_) => {
+                        self.connections[t].write_text_out(3, &by);
+                        self.connections[t].reregister_writable(event_loop);
+                        self.connections[token].write_text_out(3, &format!("Summoned {}", name));
+                    },
+                    Err(e) => {
+                        self.connections[token].write_text_out(5, &format!("Couldn't summon {}. {}", name, e));
+                    },
+                }
+            },
+            None => {
+                self.connections[token].write_text_out(5, &format!("{} is not online", name));
+            },
+        }
+        self.connections[token].reregister_writable(event_loop);
+    }
+
+    ///Disconnects a player. They get told why, then the client is sent the quit message.
+    fn kick(&mut self, event_loop: &mut mio::EventLoop<Server>, token: mio::Token, name: &str) {
+        match self.find_by_name(name) {
+            Some(t) if t == token => {
+                self.connections[token].write_text_out(5, "You can't kick yourself");
+            },
+            Some(t) => {
+                let by = format!("You were kicked by {}", self.connections[token].name);
+                let done = format!("Kicked {}", self.connections[t].name);
+                println!("{}", done);
+                self.connections[t].write_text_out(5, &by);
+                self.connections[t].write_quit();
+                self.connections[t].quit(event_loop);
+                self.connections[t].close(event_loop);
+                let _ = self.connections.remove(t);
+                self.connections[token].write_text_out(3, &done);
+            },
+            None => {
+                self.connections[token].write_text_out(5, &format!("{} is not online", name));
+            },
+        }
+        self.connections[token].reregister_writable(event_loop);
+    }
+
+    ///Picks up any images that were added or changed in images/. Everyone logged in gets the
+    ///new tile mappings, followed by the art itself.
+    fn refresh_images(&mut self, event_loop: &mut mio::EventLoop<Server>) {
+        let changed = self.games.borrow_mut().update_images();
+        if changed.len() == 0 {
+            return;
+        }
+        println!("Images changed: {:?}", changed);
+        let mut tokens = vec![];
+        for c in self.connections.iter() {
+            if c.is_logged_in() {
+                tokens.push(c.token);
+            }
+        }
+        for token in tokens {
+            self.connections[token].write_tile_mappings();
+            for image in changed.iter() {
+                self.connections[token].write_image(image);
+            }
+            self.connections[token].reregister_writable(event_loop);
         }
     }
 }
@@ -123,6 +328,12 @@ impl mio::Handler for Server {
                                 self.connections[token].reregister_writable(event_loop);
                             }
                         },
+                        Msg::Inspect(token, report) => {
+                            if self.connections.contains(token) {
+                                self.connections[token].write_inspect(report);
+                                self.connections[token].reregister_writable(event_loop);
+                            }
+                        },
                         Msg::Hp(token, hp) => {
                             if self.connections.contains(token) {
                                 self.connections[token].write_stat_all(hp, 500, 100, 100, 25, 1000000, 3000000, 6, 10);
@@ -131,6 +342,11 @@ impl mio::Handler for Server {
                                 self.connections[token].reregister_writable(event_loop);
                             }
                         },
+                        Msg::Position(token, x, y) => {
+                            if self.connections.contains(token) {
+                                self.connections[token].position = Some((x, y));
+                            }
+                        },
                         Msg::Shout(msg) => {
                             let mut tokens = vec![];
                             for t in self.connections.iter() {
@@ -143,14 +359,68 @@ impl mio::Handler for Server {
                                self.connections[token].reregister_writable(event_loop);
                             }
                         },
+                        Msg::Say(token, radius, msg) => {
+                            if self.connections.contains(token) {
+                                self.say(event_loop, token, radius, &msg);
+                            }
+                        },
+                        Msg::Tell(token, name, msg) => {
+                            if self.connections.contains(token) {
+                                self.tell(event_loop, token, &name, &msg);
+                            }
+                        },
+                        Msg::Channel(token, channel, msg) => {
+                            if self.connections.contains(token) {
+                                let m = format!("[{}] {}: {}", channel, self.connections[token].name, msg);
+                                let mut tokens = vec![];
+                                for c in self.connections.iter() {
+                                    if c.is_logged_in() && c.channels.contains(&channel) {
+                                        tokens.push(c.token);
+                                    }
+                                }
+                                for t in tokens {
+                                    self.connections[t].write_text_out(STYLE_CHANNEL, &m);
+                                    self.connections[t].reregister_writable(event_loop);
+                                }
+                            }
+                        },
+                        Msg::Who(token, here) => {
+                            if self.connections.contains(token) {
+                                self.who(event_loop, token, here);
+                            }
+                        },
+                        Msg::Summon(token, name) => {
+                            if self.connections.contains(token) {
+                                self.summon(event_loop, token, &name);
+                            }
+                        },
+                        Msg::Kick(token, name) => {
+                            if self.connections.contains(token) {
+                                self.kick(event_loop, token, &name);
+                            }
+                        },
                         Msg::Join(token, map, Some((x,y))) => {
                             if self.connections.contains(token) {
-                                self.connections[token].join(&map, Some((x,y)));
+                                self.connections[token].teleport(&map, Some((x,y)));
+                                self.connections[token].reregister_writable(event_loop);
                             }
                         },
                         Msg::Join(token, map, None) => {
                             if self.connections.contains(token) {
-                                self.connections[token].join(&map, None);
+                                self.connections[token].teleport(&map, None);
+                                self.connections[token].reregister_writable(event_loop);
+                            }
+                        },
+                        Msg::AskMap(token, suggestion) => {
+                            if self.connections.contains(token) {
+                                self.connections[token].ask_map(suggestion);
+                                self.connections[token].reregister_writable(event_loop);
+                            }
+                        },
+                        Msg::CancelAsk(token) => {
+                            if self.connections.contains(token) {
+                                self.connections[token].cancel_ask();
+                                self.connections[token].reregister_writable(event_loop);
                             }
                         },
                         _ => {
@@ -163,6 +433,16 @@ impl mio::Handler for Server {
                 },
             }
         }
+        if self.last_image_check.elapsed() >= Duration::from_secs(IMAGE_CHECK_SECS) {
+            self.last_image_check = Instant::now();
+            self.refresh_images(event_loop);
+        }
+        if self.last_save.elapsed() >= Duration::from_secs(SAVE_SECS) {
+            self.last_save = Instant::now();
+            for c in self.connections.iter() {
+                c.save();
+            }
+        }
         //Essentially this is acting as a coroutine to yield so other messages can be handled. 
         let _ = event_loop.timeout_ms(TIMEOUT, 1);
     }
@@ -219,7 +499,19 @@ struct Connection {
     games: Arc<RefCell<Game>>,
     name: String,
     skin: String,
+    profile: String,
     map: String,
+    //Last position reported by the game loop
+    position: Option<(u32, u32)>,
+    //Chat channels the user has joined
+    channels: Vec<String>,
+    role: Role,
+    //Spots saved with mark, by name
+    marks: HashMap<String, (String, (u32, u32))>,
+    //Kept here so ghost mode carries over to the next map
+    ghost: Ghost,
+    //Suggestion from an ask-map teleporter that is waiting on an answer
+    pending_map: Option<String>,
     socket: TcpStream,
     token: mio::Token,
     to_client_queue: Vec<ByteBuf>,
@@ -234,7 +526,14 @@ impl Connection{
             socket: socket,
             name: "".to_string(),
             skin: "".to_string(),
+            profile: "".to_string(),
             map: "main".to_string(),
+            position: None,
+            channels: vec![],
+            role: Role::Player,
+            marks: HashMap::new(),
+            ghost: Ghost::Off,
+            pending_map: None,
             token: token,
             to_client_queue: vec![],
             event_set: mio::EventSet::readable(),
@@ -242,47 +541,412 @@ impl Connection{
         }
     }
 
+    ///Whether the client has finished logging in
+    fn is_logged_in(&self) -> bool {
+        match self.state {
+            State::LoggedIn => true,
+            State::NotLoggedIn | State::Closed => false,
+        }
+    }
+
+    ///Whether who shows this player. Hidden ghosts are left out for anyone below admin, same as on
+    ///the map, but players always see themselves.
+    fn is_listed_for(&self, viewer: mio::Token, role: Role) -> bool {
+        self.ghost != Ghost::Hidden || self.token == viewer || role >= Role::Admin
+    }
+
     ///Handles some cleanup if the user disconnects.
     fn quit(&mut self, _: &mut mio::EventLoop<Server>) {
         println!("Quit parse");
-        match self.games.borrow_mut().get_or_create_game_loop(&format!("maps/{}.map", self.map)) {
-            Some(game_loop) => {
+        self.save();
+        match self.games.borrow_mut().get_or_create_game_loop(&Game::loop_name(&self.map)) {
+            Ok(game_loop) => {
                 game_loop.borrow_mut().remove(self.token.clone());
             },
-            None => {},
+            Err(_) => {},
+        }
+    }
+
+    ///Drops the socket. Whatever is queued for the client gets one try to go out first, so a kicked
+    ///player usually sees why. The connection should be removed from the server right after.
+    fn close(&mut self, event_loop: &mut mio::EventLoop<Server>) {
+        self.state = State::Closed;
+        while let Some(mut buf) = self.to_client_queue.pop() {
+            match self.socket.try_write_buf(&mut buf) {
+                Ok(Some(_)) if !buf.has_remaining() => {},
+                _ => {
+                    break;
+                },
+            }
+        }
+        let _ = event_loop.deregister(&self.socket);
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    ///Saves the map, position, skin & profile so the next login picks up where this one left off.
+    fn save(&self) {
+        if !self.is_logged_in() {
+            return;
+        }
+        let record = PlayerRecord {
+            map: self.map.clone(),
+            position: self.position,
+            skin: self.skin.clone(),
+            profile: self.profile.clone(),
+            marks: self.marks.clone(),
+        };
+        match record.save(&self.name) {
+            Ok(_) => {},
+            Err(e) => {
+                println!("Failed to save {}: {}", self.name, e);
+            },
         }
     }
 
     ///Joins a map. Handles leaving the old map gracefully. If it cannot join the new map,
-    ///it will attempt to rejoin
-    fn join(&mut self, map: &str, index: Option<(u8,u8)>) {
-        let ref mut games = self.games.borrow_mut();
-        match games.get_or_create_game_loop(&format!("maps/{}.map", map)) {
-            Some(game_loop) => {
-                match games.get_or_create_game_loop(&format!("maps/{}.map", self.map)) {
-                    Some(old_loop) => {
-                        old_loop.borrow_mut().remove(self.token.clone());
+    ///it will attempt to rejoin, and returns why the new map didn't load. Whoever asked for the
+    ///join tells the right person.
+    fn join(&mut self, map: &str, index: Option<(u32, u32)>) -> Result<(), String> {
+        //Only the owner gets into a private instance, though testers can drop in to help
+        match Game::private_owner(map) {
+            Some(owner) if owner != safe_name(&self.name) && self.role < Role::Tester => {
+                return Err(format!("Can't join {}: it is someone else's private instance", map));
+            },
+            _ => {},
+        }
+        //Going anywhere else means a teleporter's question no longer applies
+        self.pending_map = None;
+        let error = {
+            let ref mut games = self.games.borrow_mut();
+            match games.get_or_create_game_loop(&Game::loop_name(map)) {
+                Ok(game_loop) => {
+                    match games.get_or_create_game_loop(&Game::loop_name(&self.map)) {
+                        Ok(old_loop) => {
+                            old_loop.borrow_mut().remove(self.token.clone());
+                        },
+                        Err(_) => {
+                            println!("Failed to find {}", self.map);
+                        },
+                    }
+                    game_loop.borrow_mut().join(self.token.clone(),
+                    self.skin.clone(),
+                    index,
+                    self.role,
+                    self.ghost);
+                    self.map = map.to_string().clone();
+                    //The game loop reports the exact spot once the player is placed. Until then
+                    //the spot asked for is close enough to save, and no spot saves as the start.
+                    self.position = index;
+                    None
+                },
+                Err(e) =>{
+                    println!("Failed to find {}", map);
+                    match games.get_or_create_game_loop(&Game::loop_name(&self.map)) {
+                        Ok(old_loop) => {
+                            old_loop.borrow_mut().join(self.token.clone(),
+                            self.skin.clone(),
+                            None,
+                            self.role,
+                            self.ghost);
+                        },
+                        Err(_) =>{},
+                    }
+                    Some(e)
+                },
+            }
+        };
+        match error {
+            Some(e) => {
+                Err(format!("Can't join {}: {}", map, e))
+            },
+            None => {
+                Ok(())
+            },
+        }
+    }
+
+    ///Joins a map for the player's own command or a teleporter, telling them if it fails
+    fn join_or_explain(&mut self, map: &str, index: Option<(u32, u32)>) {
+        match self.join(map, index) {
+            Ok(_) => {},
+            Err(e) => {
+                self.write_text_out(5, &e);
+            },
+        }
+    }
+
+    ///Drops the teleporter's question once the player walks off it, so answer can't be used to
+    ///jump from anywhere later
+    fn cancel_ask(&mut self) {
+        if self.pending_map.take().is_some() {
+            self.write_text_out(3, "You stepped off the teleporter.");
+        }
+    }
+
+    ///Asks the player where an ask-map teleporter should send them. The answer command picks.
+    fn ask_map(&mut self, suggestion: String) {
+        let prompt = if suggestion.len() == 0 {
+            "Where do you want to go? Type answer <map>.".to_string()
+        } else {
+            format!("Where do you want to go? Type answer <map>, or just answer for {}.", suggestion)
+        };
+        self.pending_map = Some(suggestion);
+        self.write_text_out(3, &prompt);
+    }
+
+    ///Joins a map from a teleporter. If the player is in an instance, and the teleporter doesn't
+    ///name one, they stay in the same instance family on the new map. That way a private test run
+    ///of a few connected maps doesn't dump the tester back into the shared maps.
+    fn teleport(&mut self, map: &str, index: Option<(u32, u32)>) {
+        let target = match Game::split_instance(&self.map) {
+            (_, Some(instance)) => {
+                if map.contains("#") {
+                    map.to_string()
+                } else {
+                    format!("{}#{}", map, instance)
+                }
+            },
+            (_, None) => {
+                map.to_string()
+            },
+        };
+        self.join_or_explain(&target, index);
+    }
+
+    ///Runs a parsed command. Connection commands are handled right here, anything for the map or
+    ///player gets passed along to the game loop. The layer comes from the registry entry the command
+    ///was parsed with.
+    fn execute(&mut self, event_loop: &mut mio::EventLoop<Server>, layer: Layer, command: Command) {
+        match layer {
+            Layer::Map | Layer::Player => {
+                let command = match command {
+                    Command::Skin(ref skin) => {
+                        //Remember the skin so it survives map changes
+                        self.skin = skin.clone();
+                        command.clone()
+                    },
+                    Command::Ghost(ghost) => {
+                        //The toggle is worked out here, since this is where the mode is remembered
+                        self.ghost = match ghost {
+                            Some(g) => g,
+                            None if self.ghost == Ghost::Off => Ghost::On,
+                            None => Ghost::Off,
+                        };
+                        Command::Ghost(Some(self.ghost))
+                    },
+                    _ => command,
+                };
+                self.send_to_game_loop(command);
+                return;
+            },
+            Layer::Connection => {},
+        }
+        let send = self.games.borrow().send.clone();
+        match command {
+            Command::EndKey => {
+                println!("End key hit");
+            },
+            Command::Tile(tile) => {
+                //Sends any missing tile art to the client
+                self.write_tile(tile);
+            },
+            Command::Img(img) => {
+                self.write_image(&img);
+            },
+            Command::Join(map, private) => {
+                //Join a new map. "join cave#name" joins a named instance of the map,
+                //"join cave private" joins an instance only for this player.
+                let map = if private {
+                    Game::private_instance(&map, &self.name)
+                } else {
+                    map
+                };
+                if Game::valid_instance(&map) {
+                    self.join_or_explain(&map, None);
+                } else {
+                    self.write_text_out(5, "Instance names can only use letters, numbers, - and _");
+                }
+            },
+            Command::Shout(msg) => {
+                let m = format!("{} shouts: {} ", self.name, msg).to_string();
+                //Doing this the trivially easy way, just doing a notification for
+                //that gets pushed to everyone
+                let _ = send.send(Msg::Shout(m));
+            },
+            Command::Say(radius, msg) => {
+                let _ = send.send(Msg::Say(self.token, radius, msg));
+            },
+            Command::Tell(name, msg) => {
+                let _ = send.send(Msg::Tell(self.token, name, msg));
+            },
+            Command::ChannelJoin(name) => {
+                if !self.channels.contains(&name) {
+                    self.channels.push(name.clone());
+                }
+                self.write_text_out(STYLE_CHANNEL, &format!("Joined channel {}", name));
+            },
+            Command::ChannelLeave(name) => {
+                if self.channels.contains(&name) {
+                    self.channels.retain(|c| *c != name);
+                    self.write_text_out(STYLE_CHANNEL, &format!("Left channel {}", name));
+                } else {
+                    self.write_text_out(5, &format!("You are not on channel {}", name));
+                }
+            },
+            Command::ChannelSay(name, msg) => {
+                if self.channels.contains(&name) {
+                    let _ = send.send(Msg::Channel(self.token, name, msg));
+                } else {
+                    self.write_text_out(5, &format!("You are not on channel {}. Try channel join {}", name, name));
+                }
+            },
+            Command::Who(here) => {
+                let _ = send.send(Msg::Who(self.token, here));
+            },
+            Command::Reload => {
+                //Re-reads the art aliases, then the current map from disk, so the map is built with
+                //any new fixes. Every other map picks up the new art without losing its edits.
+                match aliases::reload() {
+                    Ok(count) => {
+                        self.games.borrow().refresh_art();
+                        self.write_text_out(3, &format!("Read {} art aliases", count));
+                    },
+                    Err(e) => {
+                        self.write_text_out(5, &format!("Kept the old art aliases, {}", e));
+                    },
+                }
+                match self.games.borrow_mut().get_or_create_game_loop(&Game::loop_name(&self.map)) {
+                    Ok(game_loop) => {
+                        game_loop.borrow_mut().reload();
+                    },
+                    Err(_) => {},
+                }
+            },
+            Command::Help(topic) => {
+                let text = command::help(topic.as_ref().map(|t| &t[..]), self.role);
+                self.write_text_out(3, &text);
+            },
+            Command::Goto(map, x, y) => {
+                //Without a map, this is a jump on the current one. It goes the same way as a
+                //teleporter, so the player is pulled off and placed back on at the closest open
+                //spot, and stays in their instance.
+                let map = map.unwrap_or(self.map.clone());
+                if Game::valid_instance(&map) {
+                    let _ = send.send(Msg::Join(self.token, map, Some((x, y))));
+                } else {
+                    self.write_text_out(5, "Instance names can only use letters, numbers, - and _");
+                }
+            },
+            Command::Summon(name) => {
+                let _ = send.send(Msg::Summon(self.token, name));
+            },
+            Command::Kick(name) => {
+                let _ = send.send(Msg::Kick(self.token, name));
+            },
+            Command::Mark(name) => {
+                match self.position {
+                    Some(position) => {
+                        self.marks.insert(name.clone(), (self.map.clone(), position));
+                        self.write_text_out(3, &format!("Marked {} at {} {} {}", name, self.map, position.0, position.1));
                     },
                     None => {
-                        println!("Failed to find {}", self.map);
+                        self.write_text_out(5, "You haven't been placed on the map yet");
                     },
                 }
-                game_loop.borrow_mut().join(self.token.clone(),
-                self.skin.clone(),
-                index);
-                self.map = map.to_string().clone();
-            },
-            None =>{
-                println!("Failed to find {}", self.map);
-                match games.get_or_create_game_loop(&format!("maps/{}.map", self.map)) {
-                    Some(old_loop) => {
-                        old_loop.borrow_mut().join(self.token.clone(),
-                        self.skin.clone(),
-                        None);
+            },
+            Command::Where => {
+                match self.position {
+                    Some((x, y)) => {
+                        let text = format!("You are on {} ({}) at {} {}", self.map, Game::loop_name(&self.map), x, y);
+                        self.write_text_out(3, &text);
+                    },
+                    None => {
+                        self.write_text_out(5, "You haven't been placed on the map yet");
                     },
-                    None =>{},
                 }
             },
+            Command::Answer(answer) => {
+                match self.pending_map.take() {
+                    Some(suggestion) => {
+                        let map = answer.unwrap_or(suggestion.clone());
+                        if map.len() == 0 {
+                            self.pending_map = Some(suggestion);
+                            self.write_text_out(5, "The teleporter didn't suggest anywhere. Type answer <map>");
+                        } else if !Game::valid_instance(&map) {
+                            self.pending_map = Some(suggestion);
+                            self.write_text_out(5, &format!("{} isn't a valid map", map));
+                        } else {
+                            self.teleport(&map, None);
+                        }
+                    },
+                    None => {
+                        self.write_text_out(5, "Nothing is waiting for an answer");
+                    },
+                }
+            },
+            Command::Recall(name) => {
+                match self.marks.get(&name).cloned() {
+                    Some((map, position)) => {
+                        self.join_or_explain(&map, Some(position));
+                    },
+                    None => {
+                        self.write_text_out(5, &format!("No mark named {}", name));
+                    },
+                }
+            },
+            _ => {
+                println!("Command went to the wrong layer");
+            },
+        }
+        self.reregister_writable(event_loop);
+    }
+
+    ///Writes out an inspect report. Tile ids are looked up here, so custom images that were
+    ///added since the map loaded still show the id the client was sent.
+    fn write_inspect(&mut self, report: TileReport) {
+        let (terrain_id, objects) = {
+            let ref mappings = self.games.borrow().mappings;
+            let id = |path: &str| {
+                match mappings.get(path) {
+                    Some(tile) => format!("tile {}", tile),
+                    None => "no tile, shows as placeholder".to_string(),
+                }
+            };
+            let mut objects = vec![];
+            for &(ref kind, ref path, ref art, ref properties) in report.objects.iter() {
+                objects.push(format!("  {} {} -> {} ({})", kind, path, art, id(art)));
+                if properties.len() > 0 {
+                    objects.push(format!("    {}", properties));
+                }
+            }
+            (id(&report.converted), objects)
+        };
+        let mut lines = vec![format!("Tile {} {} on {}", report.x, report.y, self.map),
+                             format!("Terrain: {} -> {} ({}, priority {})", report.terrain, report.converted,
+                                     terrain_id, report.priority),
+                             format!("Blocked: terrain {}, objects {}", report.blocked, report.object_blocked)];
+        if objects.len() == 0 {
+            lines.push("Objects: none".to_string());
+        } else {
+            lines.push("Objects:".to_string());
+            lines.extend(objects);
+        }
+        match report.teleporter {
+            Some(destination) => {
+                lines.push(format!("Teleporter: to {}", destination));
+            },
+            None => {},
+        }
+        self.write_text_out(3, &lines.join("\n"));
+    }
+
+    ///Queues a command up on the game loop for the current map
+    fn send_to_game_loop(&mut self, command: Command) {
+        match self.games.borrow_mut().get_or_create_game_loop(&Game::loop_name(&self.map)) {
+            Ok(game_loop) => {
+                game_loop.borrow_mut().send_command(Msg::Command(self.token.clone(), command));
+            },
+            Err(_) => {},
         }
     }
 
@@ -306,88 +970,50 @@ impl Connection{
                     self.readable(event_loop);
                 }
             },
+            State::Closed => {
+                if self.event_set.is_writable() {
+                    self.event_set.remove(mio::EventSet::writable());
+                    self.writable(event_loop);
+                }
+            },
         }
     }
     
-    ///Reads commands from the client. Can handle commands up to 4k in length. Redirects based on the text of the command.
+    ///Reads commands from the client. Can handle commands up to 4k in length. Each one is parsed
+    ///through the command registry & then executed.
     fn readable(&mut self, event_loop: &mut mio::EventLoop<Server>) {
         let mut read = Vec::with_capacity(4096);
         match self.socket.try_read_buf(&mut read) {
             Ok(Some(0)) => {
                 self.reregister_readable(event_loop);
             },
-            Ok(Some(mut n)) => {
+            Ok(Some(n)) => {
                 //println!("Read {}", n);
-                //Read strings. Write to game_loop
-                loop {
-                    if n >= 3 {
-                        //Must be over 3 in length. 
-                        let length_slice = &read[..2];
-                        let length = length_slice.iter().fold(0usize,| total, x | total  << 8 | *x as
-                                                             usize);
-                        if n >= 2+ length {
-                            match std::str::from_utf8(&read[2..2+length]) {
-                                Ok(command) => {
-                                    println!("{}", command);
-                                    //Because I took a shortcut and use the command "end <index>" as an internal command
-                                    //I had to intercept the end_key early.
-                                    if command.starts_with("end_key") {
-                                        println!("End key hit");
-                                    } else if command.starts_with("#tile") {
-                                        //Sends any missing tile art to the client
-                                        match command.split(" ").next().unwrap().parse() {
-                                            Ok(tile) => {
-                                                self.write_tile(tile);
-                                            },
-                                            _ => {},
-                                        }
-                                    } else if command.starts_with("#img") && command.len() > 5 {
-                                        let (_, ref img) = command.split_at(5);
-                                        println!("{}", img);
-                                        self.write_image(img);
-                                    } else if command.starts_with("skin ") && command.len() > 5 {
-                                        //Changes the character skin. Passes on to game loop so the map can change
-                                        //it on the player object as well.
-                                        let (_, ref skin) = command.split_at(5);
-                                        self.skin = skin.to_string();
-                                        match
-                                            self.games.borrow_mut().get_or_create_game_loop(&format!("maps/{}.map", self.map)) {
-                                            Some(game_loop) => {
-                                                game_loop.borrow_mut().send_command(Msg::Command(self.token.clone(),
-                                                command.to_string()));
-                                            },
-                                            None => {},
-                                        }
-                                    } else if command.starts_with("join ") && command.len() > 5 {
-                                        //Join a new map
-                                        let (_, ref map) = command.split_at(5);
-                                        self.join(map, None);
-                                    } else if command.starts_with("shout ") && command.len() > 6 {
-                                        //Shouts to all users. 
-                                        let (_, ref msg) = command.split_at(6);
-                                        let m = format!("{} shouts: {} ", self.name, msg).to_string();
-                                        //Doing this the trivially easy way, just doing a notification for
-                                        //that gets pushed to everyone
-                                        let send = self.games.borrow_mut().send.clone();
-                                        let _ = send.send(Msg::Shout(m));
-                                    } else {
-                                        //println!("Readable parse");
-                                        match self.games.borrow_mut().get_or_create_game_loop(&format!("maps/{}.map",self.map)) {
-                                            Some(game_loop) => {
-                                                game_loop.borrow_mut().send_command(Msg::Command(self.token.clone(),
-                                                command.to_string()));
-                                            },
-                                            None => {},
-                                        }
-                                    }
-                                    n = n - (2 + length);
-                                },
-                                Err(_) => {},
-                            };
-                        }
-                    } else {
+                //Read strings. Each one is a 2 byte length, followed by the text.
+                let mut start = 0;
+                while n >= start + 3 {
+                    let length_slice = &read[start..start+2];
+                    let length = length_slice.iter().fold(0usize,| total, x | total  << 8 | *x as
+                                                         usize);
+                    if n < start + 2 + length {
                         break;
                     }
+                    match std::str::from_utf8(&read[start+2..start+2+length]) {
+                        Ok(text) => {
+                            println!("{}", text);
+                            match command::parse_spec(text, self.role) {
+                                Ok((spec, command)) => {
+                                    self.execute(event_loop, spec.layer, command);
+                                },
+                                Err(e) => {
+                                    self.write_text_out(5, &e.to_string());
+                                    self.reregister_writable(event_loop);
+                                },
+                            }
+                        },
+                        Err(_) => {},
+                    };
+                    start = start + 2 + length;
                 }
                 self.reregister_readable(event_loop);
             },
@@ -480,7 +1106,7 @@ impl Connection{
                         if self.name.starts_with("Rizato") {
                             //Writing paladin specific stats
                             self.skin = "paladin".to_string();
-                            self.write_stat_name("Rizato the Paladin");
+                            self.profile = "Rizato the Paladin".to_string();
                             self.write_inv_add("Shield Of Reflection","sell", "armor/shield/shield_of_reflection", 0,0);
                             self.write_inv_add("Ring of Protection","sell", "magic/ring_emerald", 0,0);
                             self.write_inv_add("Crystal Platemail","sell", "armor/armor/crystal_platemail.1", 0,0);
@@ -491,7 +1117,7 @@ impl Connection{
                             self.write_ground_add("Q - Magic Shield","cast", "armor/shield/magical_shield", 0,0);
                         } else if self.name.starts_with("Cama") {
                             self.skin = "mage".to_string();
-                            self.write_stat_name("Cama the Arch Mage");
+                            self.profile = "Cama the Arch Mage".to_string();
                             self.write_inv_add("Shirt of Fire","sell", "armor/armor/cloth_fire", 0,0);
                             self.write_inv_add("Ring of Fire","sell", "magic/ring_ruby", 0,0);
                             self.write_inv_add("Samhoc Crown","sell", "armor/helmet/samhoc_crown", 0,0);
@@ -503,7 +1129,7 @@ impl Connection{
                             self.write_ground_add("Q - Fear","cast", "spells/fear.1", 0,0);
                         } else if self.name.starts_with("Romin") {
                             self.skin = "panther_male".to_string();
-                            self.write_stat_name("Romin the Warrior");
+                            self.profile = "Romin the Warrior".to_string();
                             self.write_inv_add("Claws","sell", "weapons/claws/knop/claws_hunter", 0,0);
                             self.write_inv_add("Black Dragon Mail","sell", "armor/armor/black_dragon_mail", 0,0);
                             self.write_inv_add("Lion Cloak","sell", "armor/cloak/lion_cloak", 0,0);
@@ -514,16 +1140,38 @@ impl Connection{
                             self.write_ground_add("Q - Fog","cast", "spells/fog/fog", 0,0);
                         } else if self.name.starts_with("Sarabi") {
                             self.skin = "female_rogue".to_string();
-                            self.write_stat_name("Sarabi the Reborn Ninja");
+                            self.profile = "Sarabi the Reborn Ninja".to_string();
                             self.write_inv_add("Claws","sell", "weapons/claws/knop/claws_hunter", 0,0);
                             self.write_inv_add("Black Dragon Mail","sell", "armor/armor/black_dragon_mail", 0,0);
                             self.write_inv_add("Lion Cloak","sell", "armor/cloak/lion_cloak", 0,0);
                             //Writing pally spells
                         } else {
-                            let name = self.name.clone();
                             self.skin = self.name.clone();
-                            self.write_stat_name(&format!("{} the Wonderful Player", name));
+                            self.profile = format!("{} the Wonderful Player", self.name);
+                        }
+                        //Anything saved from the last session wins over the defaults
+                        let mut position = None;
+                        match PlayerRecord::load(&self.name) {
+                            Some(record) => {
+                                println!("Restoring {} to {}", self.name, record.map);
+                                self.map = record.map;
+                                position = record.position;
+                                if record.skin.len() > 0 {
+                                    self.skin = record.skin;
+                                }
+                                if record.profile.len() > 0 {
+                                    self.profile = record.profile;
+                                }
+                                self.marks = record.marks;
+                            },
+                            None => {},
+                        }
+                        self.role = Role::lookup(&self.name);
+                        if self.role != Role::Player {
+                            println!("{} logged in as {}", self.name, self.role.name());
                         }
+                        let profile = self.profile.clone();
+                        self.write_stat_name(&profile);
                         for entry in glob("images/**/*.gif").unwrap() {
                             match entry {
                                 Ok(img) => {
@@ -540,13 +1188,30 @@ impl Connection{
                         self.reregister_writable(event_loop);
                         println!("Writable");
                         println!("Login parse");
-                        match self.games.borrow_mut().get_or_create_game_loop(&format!("maps/{}.map", self.map)) {
-                            Some(game_loop) => {
+                        let mut game_loop = self.games.borrow_mut().get_or_create_game_loop(&Game::loop_name(&self.map));
+                        let failed = match game_loop {
+                            Ok(_) => None,
+                            Err(ref e) => Some(e.clone()),
+                        };
+                        match failed {
+                            Some(e) => {
+                                //The saved map is gone or broken, so start over on main
+                                self.write_text_out(5, &format!("Can't load {}: {}", self.map, e));
+                                self.map = "main".to_string();
+                                position = None;
+                                game_loop = self.games.borrow_mut().get_or_create_game_loop(&Game::loop_name(&self.map));
+                            },
+                            None => {},
+                        }
+                        match game_loop {
+                            Ok(game_loop) => {
                                 game_loop.borrow_mut().join(self.token.clone(), self.skin.clone(),
-                                None);
+                                position, self.role, self.ghost);
                                 println!("Looped");
                             },
-                            None => {},
+                            Err(e) => {
+                                self.write_text_out(5, &format!("Can't load main: {}", e));
+                            },
                         }
                         //This is here only while it is a single user. Normally, these would be added to the game_loop, not set.
                         self.reregister_readable(event_loop);
@@ -0,0 +1,154 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module saves player state between logins. Each player gets a small text file in saves/
+/// with one key=value pair per line. Nothing fancy, but it is easy to read & fix by hand.
+
+use std::io::prelude::*;
+use std::io;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::collections::HashMap;
+
+const SAVE_DIR: &'static str = "saves";
+
+///Everything about a player that survives a reconnect
+#[derive(Clone)]
+pub struct PlayerRecord {
+    pub map: String,
+    pub position: Option<(u32, u32)>,
+    pub skin: String,
+    pub profile: String,
+    ///Named spots saved with mark. Each one is a map & position.
+    pub marks: HashMap<String, (String, (u32, u32))>,
+}
+
+impl PlayerRecord {
+    ///Reads the saved record for a name, if there is one.
+    pub fn load(name: &str) -> Option<PlayerRecord> {
+        let file = match File::open(PlayerRecord::path(name)) {
+            Ok(f) => f,
+            Err(_) => {
+                return None;
+            },
+        };
+        let mut record = PlayerRecord {
+            map: "main".to_string(),
+            position: None,
+            skin: String::new(),
+            profile: String::new(),
+            marks: HashMap::new(),
+        };
+        let mut x = None;
+        let mut y = None;
+        let reader = BufReader::new(file);
+        for line in reader.lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => {
+                    return None;
+                },
+            };
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim().to_string();
+            let value = parts.next().unwrap_or("").trim().to_string();
+            if key == "map" {
+                record.map = value;
+            } else if key == "x" {
+                x = value.parse::<u32>().ok();
+            } else if key == "y" {
+                y = value.parse::<u32>().ok();
+            } else if key == "skin" {
+                record.skin = value;
+            } else if key == "profile" {
+                record.profile = value;
+            } else if key == "mark" {
+                //mark=<name> <map> <x> <y>
+                let parts: Vec<&str> = value.split_whitespace().collect();
+                if parts.len() == 4 {
+                    match (parts[2].parse::<u32>(), parts[3].parse::<u32>()) {
+                        (Ok(mx), Ok(my)) => {
+                            record.marks.insert(parts[0].to_string(), (parts[1].to_string(), (mx, my)));
+                        },
+                        _ => {},
+                    }
+                }
+            }
+        }
+        if let (Some(x), Some(y)) = (x, y) {
+            record.position = Some((x, y));
+        }
+        Some(record)
+    }
+
+    ///Writes the record out, replacing whatever was saved before.
+    pub fn save(&self, name: &str) -> io::Result<()> {
+        fs::create_dir_all(SAVE_DIR)?;
+        let mut file = File::create(PlayerRecord::path(name))?;
+        writeln!(file, "map={}", self.map)?;
+        match self.position {
+            Some((x, y)) => {
+                writeln!(file, "x={}", x)?;
+                writeln!(file, "y={}", y)?;
+            },
+            None => {},
+        }
+        writeln!(file, "skin={}", self.skin)?;
+        writeln!(file, "profile={}", self.profile)?;
+        for (name, &(ref map, (x, y))) in self.marks.iter() {
+            writeln!(file, "mark={} {} {} {}", name, map, x, y)?;
+        }
+        Ok(())
+    }
+
+    ///Builds the file name for a player
+    fn path(name: &str) -> String {
+        format!("{}/{}.txt", SAVE_DIR, safe_name(name))
+    }
+}
+
+///Lower cases a player name & swaps out anything that isn't a plain character, so a name can't
+///point somewhere outside the save directory, and always makes a valid instance name. Anything
+///else becomes - and its bytes in hex, i.e. "a.b" is a-2eb. - is only ever used for that, so two
+///different names never end up with the same file or private instance.
+pub fn safe_name(name: &str) -> String {
+    let mut safe = String::new();
+    for c in name.to_lowercase().chars() {
+        if c.is_alphanumeric() || c == '_' {
+            safe.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                safe.push_str(&format!("-{:02x}", byte));
+            }
+        }
+    }
+    safe
+}
+
+#[cfg(test)]
+mod tests {
+    use super::safe_name;
+
+    #[test]
+    fn keeps_names_apart() {
+        assert_eq!(safe_name("Paladin_2"), "paladin_2");
+        assert_eq!(safe_name("a.b"), "a-2eb");
+        assert_eq!(safe_name("a-b"), "a-2db");
+        assert_eq!(safe_name("../etc"), "-2e-2e-2fetc");
+        assert!(safe_name("a.b") != safe_name("a_b"));
+    }
+}
@@ -0,0 +1,239 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module turns paths from map files into the art the client is sent. The map editor's paths
+/// don't always match the image files, so aliases.txt lists the fixes. Every path goes through
+/// resolve, and the table can be re-read while the server is running.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::sync::RwLock;
+
+///The file with the alias rules
+pub const ALIAS_FILE: &'static str = "aliases.txt";
+
+lazy_static! {
+    //Shared by every game loop. Missing or broken files leave the table empty, so maps still load.
+    static ref ALIASES: RwLock<Aliases> = RwLock::new(match Aliases::load(ALIAS_FILE) {
+        Ok(aliases) => aliases,
+        Err(e) => {
+            println!("No art aliases: {}", e);
+            Aliases::new()
+        },
+    });
+}
+
+///Gives the art for a path from a map file. Paths without an alias are their own art.
+pub fn resolve(path: &str) -> String {
+    ALIASES.read().unwrap().resolve(path)
+}
+
+///Re-reads the alias file. If it can't be read, the old table is kept. Returns how many rules
+///were loaded.
+pub fn reload() -> Result<usize, String> {
+    let aliases = Aliases::load(ALIAS_FILE)?;
+    let count = aliases.len();
+    *ALIASES.write().unwrap() = aliases;
+    Ok(count)
+}
+
+///The alias rules. Exact rules are checked first, then patterns in the order they are in the
+///file. Only the first rule that matches is used, so rules never chain into each other.
+pub struct Aliases {
+    exact: HashMap<String, String>,
+    patterns: Vec<Pattern>,
+}
+
+impl Aliases {
+    pub fn new() -> Aliases {
+        Aliases {
+            exact: HashMap::new(),
+            patterns: vec![],
+        }
+    }
+
+    ///Reads rules from a file. Each line is "path = art". A * in the path matches anything, and
+    ///is filled back in at the * in the art, i.e. "structures/* = statics/*". Blank lines & lines
+    ///starting with # are skipped.
+    pub fn load(file: &str) -> Result<Aliases, String> {
+        match File::open(file) {
+            Ok(f) => Aliases::read(file, BufReader::new(f)),
+            Err(e) => {
+                Err(format!("couldn't open {}: {}", file, e))
+            },
+        }
+    }
+
+    ///Reads rules from anything, with file used in errors
+    fn read<R: BufRead>(file: &str, reader: R) -> Result<Aliases, String> {
+        let mut aliases = Aliases::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => {
+                    return Err(format!("couldn't read {}: {}", file, e));
+                },
+            };
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") {
+                continue;
+            }
+            let parts: Vec<&str> = line.split('=').map(|p| p.trim()).collect();
+            if parts.len() != 2 || parts[0].len() == 0 || parts[1].len() == 0 {
+                return Err(format!("{} line {} should be \"path = art\", not \"{}\"", file, number + 1, line));
+            }
+            match aliases.add(parts[0], parts[1]) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("{} line {}: {}", file, number + 1, e));
+                },
+            }
+        }
+        Ok(aliases)
+    }
+
+    ///Adds a rule. Paths with a * become patterns, anything else is an exact rule.
+    pub fn add(&mut self, from: &str, to: &str) -> Result<(), String> {
+        if from.contains('*') {
+            let pattern = Pattern {
+                from: from.split('*').map(|p| p.to_string()).collect(),
+                to: to.split('*').map(|p| p.to_string()).collect(),
+            };
+            if pattern.to.len() > pattern.from.len() {
+                return Err(format!("{} has more * than {}", to, from));
+            }
+            self.patterns.push(pattern);
+        } else if to.contains('*') {
+            return Err(format!("{} has a * but {} doesn't", to, from));
+        } else {
+            self.exact.insert(from.to_string(), to.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn resolve(&self, path: &str) -> String {
+        match self.exact.get(path) {
+            Some(art) => {
+                return art.clone();
+            },
+            None => {},
+        }
+        for pattern in self.patterns.iter() {
+            match pattern.apply(path) {
+                Some(art) => {
+                    return art;
+                },
+                None => {},
+            }
+        }
+        path.to_string()
+    }
+
+    ///How many rules there are
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.patterns.len()
+    }
+}
+
+///A rule with wildcards, kept as the text around each *
+struct Pattern {
+    from: Vec<String>,
+    to: Vec<String>,
+}
+
+impl Pattern {
+    ///Matches the path & builds the art from what each * matched. Each * takes as little as it
+    ///can, except the last, which takes whatever is left before the ending text.
+    fn apply(&self, path: &str) -> Option<String> {
+        let first = &self.from[0];
+        let last = &self.from[self.from.len() - 1];
+        if !path.starts_with(&first[..]) || path.len() < first.len() + last.len() || !path.ends_with(&last[..]) {
+            return None;
+        }
+        let mut rest = &path[first.len()..path.len() - last.len()];
+        let mut matched = vec![];
+        for piece in self.from[1..self.from.len() - 1].iter() {
+            match rest.find(&piece[..]) {
+                Some(i) => {
+                    matched.push(&rest[..i]);
+                    rest = &rest[i + piece.len()..];
+                },
+                None => {
+                    return None;
+                },
+            }
+        }
+        matched.push(rest);
+        let mut art = self.to[0].clone();
+        for (i, piece) in self.to[1..].iter().enumerate() {
+            art.push_str(matched[i]);
+            art.push_str(piece);
+        }
+        Some(art)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Aliases;
+
+    fn aliases(text: &str) -> Aliases {
+        Aliases::read("aliases.txt", text.as_bytes()).unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        Aliases::read("aliases.txt", text.as_bytes()).err().unwrap()
+    }
+
+    #[test]
+    fn resolves_exact_paths() {
+        let aliases = aliases("# comment\n\nscenery/sign1 = scenery/signpost\n");
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(aliases.resolve("scenery/sign1"), "scenery/signpost");
+        assert_eq!(aliases.resolve("scenery/sign12"), "scenery/sign12");
+        assert_eq!(aliases.resolve("terrain/grass"), "terrain/grass");
+    }
+
+    #[test]
+    fn resolves_prefixes_infixes_and_suffixes() {
+        let aliases = aliases("structures/* = statics/*\n\
+                               *earth_block* = *earth_wall*\n\
+                               *_old = *\n");
+        assert_eq!(aliases.resolve("structures/well"), "statics/well");
+        assert_eq!(aliases.resolve("walls/dark_earth_blockNS"), "walls/dark_earth_wallNS");
+        assert_eq!(aliases.resolve("scenery/tree_old"), "scenery/tree");
+        //Only the first rule that matches is used
+        assert_eq!(aliases.resolve("structures/earth_block_old"), "statics/earth_block_old");
+        assert_eq!(aliases.resolve("scenery/tree"), "scenery/tree");
+    }
+
+    #[test]
+    fn checks_exact_rules_before_patterns() {
+        let aliases = aliases("structures/* = statics/*\nstructures/well = statics/well_full\n");
+        assert_eq!(aliases.resolve("structures/well"), "statics/well_full");
+        assert_eq!(aliases.resolve("structures/hut"), "statics/hut");
+    }
+
+    #[test]
+    fn refuses_malformed_lines() {
+        assert_eq!(error("scenery/sign1\n"), "aliases.txt line 1 should be \"path = art\", not \"scenery/sign1\"");
+        assert_eq!(error("a = b\n = c\n"), "aliases.txt line 2 should be \"path = art\", not \"= c\"");
+        assert_eq!(error("a = b = c\n"), "aliases.txt line 1 should be \"path = art\", not \"a = b = c\"");
+        assert_eq!(error("a = *\n"), "aliases.txt line 1: * has a * but a doesn't");
+        assert_eq!(error("a* = *b*\n"), "aliases.txt line 1: *b* has more * than a*");
+    }
+}
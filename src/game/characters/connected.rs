@@ -18,7 +18,11 @@ extern crate mio;
 
 use game::characters::Controllable;
 use game::characters::ControllableType;
+use game::aliases;
 use game::gamemap::GameMap;
+use game::properties::Properties;
+use conn::command::Command;
+use conn::roles::Role;
 
 use std::collections::HashMap;
 
@@ -29,14 +33,19 @@ use std::collections::HashMap;
 #[derive(Clone)]
 pub struct RoadWall{
     pub tile: String,
+    //The path from the map file
+    path: String,
     index: u32,
+    properties: Properties,
 }
 
 impl RoadWall {
     pub fn new(tile: String, tiles: &HashMap<String, i16>, index: u32) -> RoadWall {
         RoadWall{
+            path: tile.clone(),
             tile: RoadWall::find_corrected_tile(tile, tiles),
             index: index,
+            properties: Properties::new(),
         }
     }
 
@@ -56,23 +65,18 @@ impl RoadWall {
 
     ///This section is hideous. The path attributes from the 
     ///mapmaker do not match 1:1 with image paths. As a
-    ///result, I have to translate between them. While I 
-    ///would prefer this to be a lookup, there are just too
-    ///many files for that. Sure, I could script it, if
-    ///I had a way of getting all the map maker paths.
-    ///Instead, I try to generate names where I can, and hardcode
-    ///the known exceptions. Ugly AF.
-    fn create_tile_options(tile: &str) -> Vec<String> {
-        //Some hardcoded values when they are way off
-        if tile == "roads/twisty_road" {
-            println!("Had twisty_road");
-            return vec!["roads/TwistyMntRoad".to_string();1];
-        }
-        
+    ///result, I have to translate between them. The known
+    ///exceptions are in aliases.txt. For everything else,
+    ///I try to generate names from the usual directory &
+    ///case patterns. Ugly AF.
+    pub fn create_tile_options(tile: &str) -> Vec<String> {
         let mut options = vec![];
-        let mut t = tile.to_string();
-        t = t.replace("earth_block", "earth_wall");
+        let t = aliases::resolve(tile);
         options.push(t.clone());
+        //Guesses need roads/ or walls/ & a name after it. An alias can point anywhere.
+        if t.len() <= 6 || !t.is_char_boundary(6) {
+            return options;
+        }
         let (root, temp) = t.split_at(6);
         let mut r = root.to_string();
         if t.contains("walls/") {
@@ -84,36 +88,45 @@ impl RoadWall {
             } else if temp.contains("wall") {
                 parts = temp.split("wall").collect();
             }
-            r.push_str(parts[0]);
-            r.push_str("/");
-            options.push(format!("{}{}", r, temp));
+            //Full art paths like walls/castle/CastleDoor don't split into anything
+            if parts.len() > 0 {
+                r.push_str(parts[0]);
+                r.push_str("/");
+                options.push(format!("{}{}", r, temp));
+            }
         }
         if temp.contains("_") {
             let mut capital_case= String::new();
             let mut lower_case = String::new();
             for word in temp.split("_") {
-                let (first, rest) = word.split_at(1);
-                capital_case.push_str(&first.to_uppercase());
-                capital_case.push_str(&rest);
-                lower_case.push_str(&first);
-                lower_case.push_str(&rest);
+                capital_case.push_str(&capitalize(word));
+                lower_case.push_str(word);
             }
             options.push(format!("{}{}", r, capital_case));
             options.push(format!("{}{}", r, lower_case));
         }
         //Pushing again just capitalizing the first letter
-        let mut built = String::new();
-        let (first, rest) = temp.split_at(1);
-        built.push_str(&first.to_uppercase());
-        built.push_str(&rest);
-        options.push(format!("{}{}", r, built));
+        options.push(format!("{}{}", r, capitalize(temp)));
         options
     }
 }
 
+///Upper cases the first letter. Empty words stay empty.
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => {
+            format!("{}{}", first.to_uppercase().collect::<String>(), chars.as_str())
+        },
+        None => {
+            String::new()
+        },
+    }
+}
+
 impl Controllable for RoadWall{
     
-    fn update(&mut self, _: u8, _: u8, _: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
+    fn update(&mut self, _: u32, _: u32, _: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
         None
     }
     
@@ -126,13 +139,12 @@ impl Controllable for RoadWall{
     }
 
     
-    fn modify_connected_tiles(&mut self, width: u8, height: u8, roadwalls: &Vec<bool>) {
-        if self.tile.to_lowercase().contains("bridge") 
-            || self.tile.to_lowercase().contains("door") {
+    fn modify_connected_tiles(&mut self, width: u32, height: u32, roadwalls: &Vec<bool>) {
+        if self.tile.to_lowercase().contains("bridge") {
             return
         }
-        let x = self.index % width as u32;
-        let y = self.index / width as u32;
+        let x = self.index % width;
+        let y = self.index / width;
         let mut connected_map: u8 = 0;
         for dx in 0..3 {
             for dy in 0..3 {
@@ -142,10 +154,10 @@ impl Controllable for RoadWall{
                 let current_x = (x as i32) + (dx as i32) -1;
                 let current_y = (y as i32) + (dy as i32) -1;
                 if current_x >= 0 && current_y >= 0 {
-                    if current_x as u32 >= width  as u32 || current_y as u32 >= height as u32 {
+                    if current_x as u32 >= width || current_y as u32 >= height {
                         continue;
                     }
-                    let i = (current_y as u32) * width as u32 + (current_x as u32);
+                    let i = (current_y as u32) * width + (current_x as u32);
                     if roadwalls[i as usize] {
                         if dx == 0 && dy == 1 {
                             //West
@@ -208,8 +220,7 @@ impl Controllable for RoadWall{
         if self.index == index {
             match self.get_type() {
                 ControllableType::Wall => {
-                    !self.tile.contains("door") && !self.tile.contains("Door")
-                    
+                    true
                 }, 
                 _ => {
                     false
@@ -220,14 +231,14 @@ impl Controllable for RoadWall{
         }
     }
  
-    fn is_visible(&self, _: &GameMap) -> bool {
+    fn is_visible(&self, _: &GameMap, _: Role) -> bool {
         true
     }
 
     fn hurt(&mut self, _: i32) {
     }
 
-    fn push_command(&mut self, _: String) {
+    fn push_command(&mut self, _: Command) {
     }
     
     fn set_movement(&mut self, _: u32) {
@@ -236,4 +247,42 @@ impl Controllable for RoadWall{
     fn get_viewport(&self) -> (u8, u8) {
         (0,0)
     }
+
+    fn get_path(&self) -> String {
+        self.path.clone()
+    }
+
+    fn get_properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+    }
+
+    fn refresh_art(&mut self, tiles: &HashMap<String, i16>) {
+        self.tile = RoadWall::find_corrected_tile(self.path.clone(), tiles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RoadWall;
+
+    #[test]
+    fn guesses_art_from_the_path() {
+        let options = RoadWall::create_tile_options("walls/stone_wall");
+        assert!(options.contains(&"walls/stone/stone_wall".to_string()), "{:?}", options);
+        assert!(options.contains(&"walls/stone/StoneWall".to_string()), "{:?}", options);
+    }
+
+    #[test]
+    fn keeps_short_or_odd_paths_as_they_are() {
+        assert_eq!(RoadWall::create_tile_options("x"), vec!["x"]);
+        assert_eq!(RoadWall::create_tile_options("roads/"), vec!["roads/"]);
+        let options = RoadWall::create_tile_options("walls/a__b");
+        assert_eq!(options[0], "walls/a__b");
+        let options = RoadWall::create_tile_options("roads/éa_b");
+        assert!(options.contains(&"roads/ÉaB".to_string()), "{:?}", options);
+    }
 }
@@ -0,0 +1,293 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+/// This module declares the door object.
+
+extern crate mio;
+
+use game::characters::Controllable;
+use game::characters::ControllableType;
+use game::characters::connected::RoadWall;
+use game::gamemap::GameMap;
+use game::properties::Properties;
+use conn::command::Command;
+use conn::roles::Role;
+
+use std::collections::HashMap;
+
+///Whether a door can be walked through. Locked doors are drawn closed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DoorState {
+    Open,
+    Closed,
+    Locked,
+}
+
+///A door in a wall. The art is the wall set's door, i.e. walls/castle/CastleDoor, followed by
+///EW or NS for the way the wall runs, and 1 for closed or 2 for open.
+#[derive(Clone)]
+pub struct Door {
+    base: String,
+    //The path from the map file
+    path: String,
+    index: u32,
+    state: DoorState,
+    //EW for a door in a wall running east to west, NS for north to south
+    orientation: &'static str,
+    properties: Properties,
+}
+
+impl Door {
+    pub fn new(tile: String, tiles: &HashMap<String, i16>, index: u32) -> Door {
+        //The orientation & state are worked out here, so anything the editor tacked on is dropped
+        let mut orientation = "EW";
+        let mut trimmed = tile.trim_matches(|c: char| c.is_digit(10)).to_string();
+        for o in ["EW", "NS"].iter() {
+            if trimmed.ends_with(o) {
+                orientation = o;
+                let len = trimmed.len() - o.len();
+                trimmed.truncate(len);
+            }
+        }
+        Door {
+            base: Door::find_base(&trimmed, tiles),
+            path: tile,
+            index: index,
+            state: DoorState::Closed,
+            orientation: orientation,
+            properties: Properties::new(),
+        }
+    }
+
+    ///Works out the art for the door using the same guesses as walls. The first one that has
+    ///closed east-west art wins.
+    fn find_base(tile: &str, tiles: &HashMap<String, i16>) -> String {
+        if tiles.contains_key(&format!("{}EW1", tile)) {
+            return tile.to_string();
+        }
+        for option in RoadWall::create_tile_options(tile) {
+            if tiles.contains_key(&format!("{}EW1", option)) {
+                return option;
+            }
+        }
+        tile.to_string()
+    }
+}
+
+impl Controllable for Door {
+
+    fn update(&mut self, _: u32, _: u32, _: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
+        None
+    }
+
+    fn get_location(&self) -> u32 {
+        self.index
+    }
+
+    fn get_tile(&self) -> String {
+        let state = match self.state {
+            DoorState::Open => "2",
+            DoorState::Closed | DoorState::Locked => "1",
+        };
+        format!("{}{}{}", self.base, self.orientation, state)
+    }
+
+    ///Doors don't change art for the walls next to them, but they do turn to match them
+    fn modify_connected_tiles(&mut self, width: u32, height: u32, walls: &Vec<bool>) {
+        let x = self.index % width;
+        let y = self.index / width;
+        let west = x > 0 && walls[(self.index - 1) as usize];
+        let east = x + 1 < width && walls[(self.index + 1) as usize];
+        let north = y > 0 && walls[(self.index - width) as usize];
+        let south = y + 1 < height && walls[(self.index + width) as usize];
+        if west || east {
+            self.orientation = "EW";
+        } else if north || south {
+            self.orientation = "NS";
+        }
+    }
+
+    fn get_type(&self) -> ControllableType {
+        ControllableType::Door
+    }
+
+    fn get_size(&self) -> (u32, u32) {
+        (1,1)
+    }
+
+    fn get_token(&self) -> Option<mio::Token> {
+        None
+    }
+
+    fn get_hp(&self) -> Option<i32> {
+        None
+    }
+
+    fn set_location(&mut self, index: u32) {
+        self.index = index;
+    }
+
+    fn does_block_index(&self, index: u32) -> bool {
+        self.index == index && self.state != DoorState::Open
+    }
+
+    fn is_visible(&self, _: &GameMap, _: Role) -> bool {
+        true
+    }
+
+    fn hurt(&mut self, _: i32) {
+    }
+
+    fn push_command(&mut self, _: Command) {
+    }
+
+    fn set_movement(&mut self, _: u32) {
+    }
+
+    fn get_viewport(&self) -> (u8, u8) {
+        (0,0)
+    }
+
+    fn get_path(&self) -> String {
+        self.path.clone()
+    }
+
+    fn get_properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    ///Doors start open if the designer ticked open, and locked if they ticked locked
+    fn set_properties(&mut self, properties: Properties) {
+        if properties.get_bool("locked") {
+            self.state = DoorState::Locked;
+        } else if properties.get_bool("open") {
+            self.state = DoorState::Open;
+        }
+        self.properties = properties;
+    }
+
+    ///Only the art is worked out again, so open doors stay open
+    fn refresh_art(&mut self, tiles: &HashMap<String, i16>) {
+        self.base = Door::new(self.path.clone(), tiles, self.index).base;
+    }
+
+    ///Locked doors only open for testers & admins, so designers can get behind them. The key
+    ///property names what a player would need.
+    fn open(&mut self, open: bool, role: Role) -> Result<String, String> {
+        match (self.state, open) {
+            (DoorState::Open, true) => {
+                Err("The door is already open".to_string())
+            },
+            (DoorState::Closed, false) | (DoorState::Locked, false) => {
+                Err("The door is already closed".to_string())
+            },
+            (DoorState::Locked, true) if role < Role::Tester => {
+                match self.properties.get_string("key") {
+                    Some(key) => Err(format!("The door is locked. It needs the {}.", key)),
+                    None => Err("The door is locked".to_string()),
+                }
+            },
+            (DoorState::Locked, true) => {
+                self.state = DoorState::Open;
+                Ok("You unlock the door & open it".to_string())
+            },
+            (DoorState::Closed, true) => {
+                self.state = DoorState::Open;
+                Ok("You open the door".to_string())
+            },
+            (DoorState::Open, false) => {
+                //Doors that started locked lock again when closed
+                self.state = if self.properties.get_bool("locked") {
+                    DoorState::Locked
+                } else {
+                    DoorState::Closed
+                };
+                Ok("You close the door".to_string())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Door, DoorState};
+    use game::characters::Controllable;
+    use game::properties::{Properties, Property};
+    use conn::roles::Role;
+
+    use std::collections::HashMap;
+
+    fn tiles() -> HashMap<String, i16> {
+        let mut tiles = HashMap::new();
+        for (i, art) in ["walls/brick1/Brick1DoorEW1", "walls/brick1/Brick1DoorEW2",
+                         "walls/brick1/Brick1DoorNS1", "walls/brick1/Brick1DoorNS2"].iter().enumerate() {
+            tiles.insert(art.to_string(), i as i16);
+        }
+        tiles
+    }
+
+    fn locked_door() -> Door {
+        let mut door = Door::new("walls/brick1/Brick1DoorEW1".to_string(), &tiles(), 0);
+        let mut properties = Properties::new();
+        properties.insert("locked", Property::Boolean(true));
+        properties.insert("key", Property::String("brass key".to_string()));
+        door.set_properties(properties);
+        door
+    }
+
+    #[test]
+    fn reads_orientation_and_state_from_the_path() {
+        let door = Door::new("walls/brick1/Brick1DoorNS2".to_string(), &tiles(), 0);
+        assert_eq!(door.base, "walls/brick1/Brick1Door");
+        assert_eq!(door.orientation, "NS");
+        //The 2 from the editor is dropped. Doors start closed unless the open property is set.
+        assert_eq!(door.state, DoorState::Closed);
+        assert_eq!(door.get_tile(), "walls/brick1/Brick1DoorNS1");
+        assert_eq!(door.get_path(), "walls/brick1/Brick1DoorNS2");
+        let door = Door::new("walls/brick1/Brick1DoorEW1".to_string(), &tiles(), 0);
+        assert_eq!(door.orientation, "EW");
+    }
+
+    #[test]
+    fn opens_and_closes() {
+        let mut door = Door::new("walls/brick1/Brick1DoorEW1".to_string(), &tiles(), 0);
+        assert!(door.does_block_index(0));
+        assert_eq!(door.open(false, Role::Player), Err("The door is already closed".to_string()));
+        assert_eq!(door.open(true, Role::Player), Ok("You open the door".to_string()));
+        assert!(!door.does_block_index(0));
+        assert_eq!(door.get_tile(), "walls/brick1/Brick1DoorEW2");
+        assert_eq!(door.open(true, Role::Player), Err("The door is already open".to_string()));
+        assert_eq!(door.open(false, Role::Player), Ok("You close the door".to_string()));
+        assert_eq!(door.state, DoorState::Closed);
+    }
+
+    #[test]
+    fn only_testers_open_locked_doors() {
+        let mut door = locked_door();
+        assert_eq!(door.open(true, Role::Player), Err("The door is locked. It needs the brass key.".to_string()));
+        assert_eq!(door.state, DoorState::Locked);
+        assert!(door.does_block_index(0));
+        assert_eq!(door.open(true, Role::Tester), Ok("You unlock the door & open it".to_string()));
+        assert_eq!(door.state, DoorState::Open);
+    }
+
+    #[test]
+    fn locked_doors_lock_again_when_closed() {
+        let mut door = locked_door();
+        door.open(true, Role::Admin).unwrap();
+        assert_eq!(door.open(false, Role::Player), Ok("You close the door".to_string()));
+        assert_eq!(door.state, DoorState::Locked);
+        assert!(door.open(true, Role::Player).is_err());
+    }
+}
@@ -19,32 +19,167 @@ extern crate mio;
 use game::characters::Controllable;
 use game::characters::ControllableType;
 use game::gamemap::GameMap;
+use game::aliases;
+use game::properties::Properties;
+use conn::command::Command;
+use conn::roles::Role;
+
+use glob::glob;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::RwLock;
+
+///Pixels per tile in the art. Anything bigger covers more than one tile.
+pub const TILE_PIXELS: u32 = 32;
+
+///Where the editor's stock art is looked for, unless --art says otherwise
+pub const DEFAULT_ART_DIR: &'static str = "art";
+
+lazy_static! {
+    //Set once at startup by the server or the render tool. The art isn't in the repo, so it can
+    //live wherever the editor was unpacked.
+    static ref ART_DIR: RwLock<String> = RwLock::new(DEFAULT_ART_DIR.to_string());
+}
+
+///The directory objects are sized from
+pub fn art_dir() -> String {
+    ART_DIR.read().unwrap().clone()
+}
 
+///Changes where the stock art is read from. Maps loaded after this use the new directory.
+pub fn set_art_dir(dir: &str) {
+    *ART_DIR.write().unwrap() = dir.to_string();
+}
 
 ///Defines the Item struct. This is used for any non-special item on the map.
 ///Special items are roads, walls, teleporters, and players
 #[derive(Clone)]
 pub struct Item {
     pub tile: String,
+    //The path from the map file
+    path: String,
     index: u32,
+    //Tiles covered, going right & down from index
+    size: (u32, u32),
+    //Width of the map, to tell which indexes are under a big item
+    map_width: u32,
+    properties: Properties,
 }
 
 impl Item {
     pub fn new(tile: String, index: u32) -> Item {
-        //Random tiles that need to be hardcoded
-        let mut t = tile.replace("structures", "statics");
-        t = t.replace("volcano", "volcano.1");
-        t = t.replace("wiz/wyvern/hack/dungeon", "statics/well");
         Item {
-            tile: t,
+            tile: aliases::resolve(&tile),
+            path: tile,
             index: index,
+            size: (1, 1),
+            map_width: 1,
+            properties: Properties::new(),
+        }
+    }
+
+    ///Creates an item that covers a w x h block of tiles, with index at the top left
+    pub fn sized(tile: String, index: u32, size: (u32, u32), map_width: u32) -> Item {
+        let mut item = Item::new(tile, index);
+        item.size = size;
+        item.map_width = map_width;
+        item
+    }
+
+    ///Whether index is one of the tiles this item sits on
+    fn covers(&self, index: u32) -> bool {
+        if self.size == (1, 1) {
+            return self.index == index;
+        }
+        if index < self.index {
+            return false;
+        }
+        let dx = (index % self.map_width) as i64 - (self.index % self.map_width) as i64;
+        let dy = (index / self.map_width) as i64 - (self.index / self.map_width) as i64;
+        dx >= 0 && dy >= 0 && dx < self.size.0 as i64 && dy < self.size.1 as i64
+    }
+}
+
+///Looks up how many tiles an archetype covers, from the width & height in its gif header.
+///Custom images come from images/, stock art from the art directory. Anything that can't be read is one
+///tile. Sizes are cached, since most maps use the same few objects over and over.
+pub struct ArchetypeSizes {
+    dir: String,
+    custom: HashMap<String, String>,
+    sizes: HashMap<String, (u32, u32)>,
+}
+
+impl ArchetypeSizes {
+    pub fn new() -> ArchetypeSizes {
+        let mut custom = HashMap::new();
+        for entry in glob("images/**/*.gif").unwrap() {
+            match entry {
+                Ok(img) => {
+                    let name = img.file_stem().unwrap().to_str().unwrap().to_string();
+                    custom.insert(name, img.to_str().unwrap().to_string());
+                },
+                _ => {},
+            }
         }
+        ArchetypeSizes {
+            dir: art_dir(),
+            custom: custom,
+            sizes: HashMap::new(),
+        }
+    }
+
+    ///Gets the size in tiles for the path from the map file
+    pub fn get(&mut self, path: &str) -> (u32, u32) {
+        //Sized by the art the client is actually sent
+        let art = aliases::resolve(path);
+        if !self.sizes.contains_key(&art) {
+            let file = match self.custom.get(&art) {
+                Some(file) => file.clone(),
+                None => format!("{}/{}.gif", self.dir, art),
+            };
+            let size = match ArchetypeSizes::read_gif_size(&file) {
+                Some((w, h)) => {
+                    ((w + TILE_PIXELS - 1) / TILE_PIXELS, (h + TILE_PIXELS - 1) / TILE_PIXELS)
+                },
+                None => (1, 1),
+            };
+            //An empty image still takes up its spot
+            let size = (if size.0 == 0 { 1 } else { size.0 }, if size.1 == 0 { 1 } else { size.1 });
+            self.sizes.insert(art.clone(), size);
+        }
+        self.sizes[&art]
+    }
+
+    ///Reads the logical screen size out of a gif header. Bytes 6-9 are the little endian width
+    ///& height.
+    fn read_gif_size(file: &str) -> Option<(u32, u32)> {
+        let mut header = [0u8; 10];
+        match File::open(file) {
+            Ok(mut f) => {
+                match f.read_exact(&mut header) {
+                    Ok(_) => {},
+                    Err(_) => {
+                        return None;
+                    },
+                }
+            },
+            Err(_) => {
+                return None;
+            },
+        }
+        if &header[0..3] != b"GIF" {
+            return None;
+        }
+        let width = header[6] as u32 | (header[7] as u32) << 8;
+        let height = header[8] as u32 | (header[9] as u32) << 8;
+        Some((width, height))
     }
 }
 
 impl Controllable for Item {
     
-    fn update(&mut self, _: u8, _: u8, _: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
+    fn update(&mut self, _: u32, _: u32, _: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
         None
     }
 
@@ -57,7 +192,7 @@ impl Controllable for Item {
     }
 
     fn get_size(&self) -> (u32, u32) {
-        (1,1)
+        self.size
     }
 
     fn get_token(&self) -> Option<mio::Token> {
@@ -73,23 +208,23 @@ impl Controllable for Item {
     }
 
     fn does_block_index(&self, index: u32) -> bool {
-        self.index == index
+        self.covers(index)
     }
 
-    fn is_visible(&self, _: &GameMap) -> bool {
+    fn is_visible(&self, _: &GameMap, _: Role) -> bool {
         true
     }
 
     fn hurt(&mut self, _: i32) {
     }
 
-    fn push_command(&mut self, _: String) {
+    fn push_command(&mut self, _: Command) {
     }
     
     fn set_movement(&mut self, _: u32) {
     }
 
-    fn modify_connected_tiles(&mut self, _: u8, _: u8,  _: &Vec<bool>) {}
+    fn modify_connected_tiles(&mut self, _: u32, _: u32, _: &Vec<bool>) {}
 
     fn get_type(&self) -> ControllableType {
         ControllableType::Item
@@ -98,4 +233,20 @@ impl Controllable for Item {
     fn get_viewport(&self) -> (u8, u8) {
         (0,0)
     }
+
+    fn get_path(&self) -> String {
+        self.path.clone()
+    }
+
+    fn get_properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+    }
+
+    fn refresh_art(&mut self, _: &HashMap<String, i16>) {
+        self.tile = aliases::resolve(&self.path);
+    }
 }
@@ -20,8 +20,14 @@ pub mod player;
 pub mod connected;
 pub mod item;
 pub mod teleporter;
+pub mod door;
 
 use game::gamemap::GameMap;
+use game::properties::Properties;
+use conn::command::Command;
+use conn::roles::Role;
+
+use std::collections::HashMap;
 
 
 /// Enum for the direction that a moveable object just went. Gets sent to the connection when
@@ -39,6 +45,28 @@ pub enum Direction {
     SouthEast,
 }
 
+/// Ghost mode for designers. Ghosts walk through anything and don't block anyone. Hidden ghosts
+/// also can't be seen by anyone below admin.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ghost {
+    Off,
+    On,
+    Hidden,
+}
+
+///Lists every tile index an object sits on, going right & down from its location by its size.
+pub fn footprint(object: &Controllable, width: u32) -> Vec<u32> {
+    let location = object.get_location();
+    let (w, h) = object.get_size();
+    let mut covered = Vec::with_capacity((w * h) as usize);
+    for dy in 0..h {
+        for dx in 0..w {
+            covered.push(location + dy * width + dx);
+        }
+    }
+    covered
+}
+
 ///This is used to indicate the type, since there is no instanceOf in rust
 pub enum ControllableType{
     Road,
@@ -46,12 +74,27 @@ pub enum ControllableType{
     Player,
     Item,
     Teleporter,
+    Door,
+}
+
+impl ControllableType {
+    ///Gets a readable name for the type, for debugging output
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ControllableType::Road => "Road",
+            ControllableType::Wall => "Wall",
+            ControllableType::Player => "Player",
+            ControllableType::Item => "Item",
+            ControllableType::Teleporter => "Teleporter",
+            ControllableType::Door => "Door",
+        }
+    }
 }
 
 ///This trait is used to define a set of functions for moveable objects. Helps with pathfinding.
 pub trait Controllable {
     ///Called every game loop to update it
-    fn update(&mut self, width: u8, height: u8, blocked: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>>; 
+    fn update(&mut self, width: u32, height: u32, blocked: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>>; 
     ///Used when drawing the screen
     fn get_location(&self) -> u32;
     ///Gets the artwork
@@ -66,19 +109,36 @@ pub trait Controllable {
     fn set_location(&mut self, index: u32);
     ///true if blocks player movement
     fn does_block_index(&self, index: u32) -> bool;
-    ///whether or not to try to draw this on the MapScreen
-    fn is_visible(&self, map: &GameMap) -> bool;
+    ///whether or not to try to draw this on the MapScreen, for a viewer with the given role
+    fn is_visible(&self, map: &GameMap, viewer: Role) -> bool;
     ///Reduces a targets HP by damage (if supported)
     fn hurt(&mut self, damage: i32);
     ///Sets the end location for some movement
     fn set_movement(&mut self, end: u32); 
     ///Adds a command to the queue
-    fn push_command(&mut self, command: String);
+    fn push_command(&mut self, command: Command);
     ///Gets the correct direction tile for roads. This well connect any RoadWall objects
     ///of the same Road or Wall type.
-    fn modify_connected_tiles(&mut self, width: u8, height: u8,  objects : &Vec<bool>);
+    fn modify_connected_tiles(&mut self, width: u32, height: u32,  objects : &Vec<bool>);
     ///Returns a type because rust does not have instanceOf.
     fn get_type(&self) -> ControllableType;
     ///Returns the dimensions of the screen to send down
     fn get_viewport(&self) -> (u8, u8);
+    ///Gets the path the arch had in the map file, before any fixes for the art. Used when writing
+    ///the map back out.
+    fn get_path(&self) -> String {
+        self.get_tile()
+    }
+    ///Gets the properties the designer set on the arch in the map
+    fn get_properties(&self) -> &Properties;
+    ///Replaces the properties. The map does this once the arch's children have been read.
+    fn set_properties(&mut self, properties: Properties);
+    ///Opens or closes the object, for things like doors. Returns what to tell the player.
+    fn open(&mut self, _: bool, _: Role) -> Result<String, String> {
+        Err("That can't be opened".to_string())
+    }
+    ///Works the art out again from the path, after the art aliases are re-read. Roads & walls
+    ///need connecting again afterwards.
+    fn refresh_art(&mut self, _: &HashMap<String, i16>) {
+    }
 }
@@ -22,8 +22,12 @@ extern crate mio;
 
 use game::characters::Controllable;
 use game::characters::Direction;
+use game::characters::Ghost;
 use game::characters::ControllableType;
 use game::gamemap::GameMap;
+use game::properties::Properties;
+use conn::command::Command;
+use conn::roles::Role;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
@@ -45,7 +49,9 @@ pub struct Player{
     movement: Option<u32>,
     movement_ticks: u8,
     direction: Direction,
-    commands: Vec<String>,
+    commands: Vec<Command>,
+    pub ghost: Ghost,
+    properties: Properties,
 }
 /// This defines the custom functions for player. This handles things like getting commands & mouse movement
 impl Player {
@@ -65,23 +71,28 @@ impl Player {
             index: 0,
             movement: None,
             movement_ticks: 0,
+            ghost: Ghost::Off,
+            properties: Properties::new(),
         }
     }
     
     
     ///This is an assisting function for moveable and the A* algorithm. It basically just tries to
-    ///find the lowest value in the open tiles in the A* algorithm
-    fn lowest_estimate(open: &HashSet<u32>, estimates: &mut HashMap<u32, u32>) -> u32{
-        let mut min = 9999;
-        let mut index_min = 0;
+    ///find the lowest value in the open tiles in the A* algorithm. Estimates on big maps go well
+    ///past any small sentinel, so the minimum starts empty instead.
+    fn lowest_estimate(open: &HashSet<u32>, estimates: &HashMap<u32, u32>) -> Option<u32> {
+        let mut min: Option<(u32, u32)> = None;
         for node in open.iter() {
-            let val = estimates.entry(*node).or_insert(255); 
-            if  *val < min {
-                min = val.clone();
-                index_min = node.clone();
+            //Every open node is given an estimate when it is added. One without sorts last.
+            let val = estimates.get(node).cloned().unwrap_or(u32::MAX);
+            match min {
+                Some((lowest, _)) if lowest <= val => {},
+                _ => {
+                    min = Some((val, *node));
+                },
             }
         }
-        index_min
+        min.map(|(_, node)| node)
     }
     
     ///Given a map of tiles to the tile that led to it and the ending tile, it will go back through
@@ -105,7 +116,7 @@ impl Player {
         current
     }
     ///Computes the shortest path according to the A* algorithm. Gives the next step in the found path 
-fn path_next(width: u8, height: u8, blocked: &Vec<bool>, start: u32, end: u32) -> Option<u32> {
+fn path_next(width: u32, height: u32, blocked: &Vec<bool>, start: u32, end: u32) -> Option<u32> {
     //println!("Path!");
     //A* algorithm
     let mut closed = HashSet::new();
@@ -122,7 +133,12 @@ fn path_next(width: u8, height: u8, blocked: &Vec<bool>, start: u32, end: u32) -
     estimate_to.insert(start.clone(), Player::hueristic(width.clone(), start.clone(), end.clone()));
     while open.len() > 0 {
         //Grab start with the smallest estimate
-        let current = Player::lowest_estimate(&open, &mut estimate_to);
+        let current = match Player::lowest_estimate(&open, &estimate_to) {
+            Some(current) => current,
+            None => {
+                break;
+            },
+        };
         if current == end {
             //return the index of the first move 
             //println!("Finished! {} {}", current, end);
@@ -171,24 +187,25 @@ fn path_next(width: u8, height: u8, blocked: &Vec<bool>, start: u32, end: u32) -
 }
 
 ///Gives a hueristic estimate by just doing the pythagorean theorem.
-fn hueristic(width: u8, start: u32, end: u32) -> u32{
+fn hueristic(width: u32, start: u32, end: u32) -> u32{
     //Just using pythagorean theorem to compute the shortest path.
-    let dx = ((start % width as u32) as i32 - (end % width as u32) as i32).abs();
-    let dy = ((start / width as u32) as i32 - (end / width as u32) as i32).abs();
+    let dx = ((start % width) as i32 - (end % width) as i32).abs();
+    let dy = ((start / width) as i32 - (end / width) as i32).abs();
     if dy == 0 {
         dx as u32
     } else if dx == 0 {
         dy as u32
     } else {
         //println!("heuristic vals {} {}", dx, dy);
-        ((dx * dx + dy * dy) as f64).sqrt() as u32
+        //Squared in f64, since the distances on a big map overflow an i32
+        ((dx as f64) * (dx as f64) + (dy as f64) * (dy as f64)).sqrt() as u32
     }
 }
 
 ///Returns the found neighbors to a given index. Does one up, down, left and right.
-    fn find_neighbors(index: u32, width: u8, height: u8, blocked: &Vec<bool>) -> Vec<u32> {
-        let x = index % width as u32;
-        let y = index / width as u32;
+    fn find_neighbors(index: u32, width: u32, height: u32, blocked: &Vec<bool>) -> Vec<u32> {
+        let x = index % width;
+        let y = index / width;
         let mut neighbors = vec![];
         for dx in 0..3 {
             for dy in 0..3 {
@@ -198,10 +215,10 @@ fn hueristic(width: u8, start: u32, end: u32) -> u32{
                 let current_x = (x as i32) + (dx as i32) -1;
                 let current_y = (y as i32) + (dy as i32) -1;
                 if current_x >=0 && current_y >=0 {
-                    if current_x as u32 >= width  as u32 || current_y as u32 >= height as u32 {
+                    if current_x as u32 >= width || current_y as u32 >= height {
                         continue;
                     }
-                    let i = (current_y as u32) * width as u32 + (current_x as u32);
+                    let i = (current_y as u32) * width + (current_x as u32);
                     //println!("neighbor {}", i);
                     //if not blocked, add to neighbors
                     if !blocked[i as usize] {
@@ -235,7 +252,7 @@ fn hueristic(width: u8, start: u32, end: u32) -> u32{
     /// This checks the number of ticks, against a threshold. If it is greater or equal, and there is a
     ///movement goal set, it will always do movement. Otherwise, it will increment ticks, and grab
     ///the top command from the queue.
-    fn get_command(&mut self) -> Option<String> {
+    fn get_command(&mut self) -> Option<Command> {
         let has_movement = match self.movement{
             Some(_) =>  {true},
             None => {false},
@@ -245,7 +262,7 @@ fn hueristic(width: u8, start: u32, end: u32) -> u32{
             let end = self.movement.unwrap();
              self.movement_ticks = 0;
              //println!("got command {}", end);
-            Some(format!("end {}", end))
+            Some(Command::End(end))
         } else if self.commands.len() > 0 {
             self.movement_ticks = if self.movement_ticks == 255 {
                 self.movement_ticks 
@@ -267,113 +284,115 @@ fn hueristic(width: u8, start: u32, end: u32) -> u32{
 
 ///Implements the controllable trait for the player
 impl Controllable for Player {
-    fn update(&mut self, width: u8, height: u8, blocked: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
+    fn update(&mut self, width: u32, height: u32, blocked: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
+       //Ghosts path as if nothing on the map is blocked
+       let open;
+       let blocked = if self.ghost == Ghost::Off {
+           blocked
+       } else {
+           open = vec![false; blocked.len()];
+           &open
+       };
        let c = self.get_command();
        match c {
            Some(command) => {
-              println!("{}", command);
-              if command.starts_with("end") {
-                  let parts: Vec<&str> = command.split_whitespace().collect();
-                  let end = parts[1].parse::<u32>().unwrap();
-                  println!("Execute path: {} {}", self.index, end);
-                  let e = Player::path_next(width.clone(), height.clone(), &blocked, self.index.clone(), end);
-                  match e {
-                      Some(user_end) => {
-                          let x = self.index % width as u32;
-                          let y = self.index / width as u32;
-                          let dx = user_end % width as u32;
-                          let dy = user_end / width as u32;
-                          // Since the primary objective is east/west I will lean towards e/w when moving diagonally
-                          let mut dir = Direction::South;
-                          if dx > x as u32  {
-                              dir = Direction::East;
-                          } else if dx < x as u32 {
-                              dir = Direction::West;
-                          } else if dy < y as u32 {
-                              dir = Direction::North;
-                          } 
+              println!("{:?}", command);
+              match command {
+                  Command::End(end) => {
+                      println!("Execute path: {} {}", self.index, end);
+                      let e = Player::path_next(width.clone(), height.clone(), &blocked, self.index.clone(), end);
+                      match e {
+                          Some(user_end) => {
+                              let x = self.index % width;
+                              let y = self.index / width;
+                              let dx = user_end % width;
+                              let dy = user_end / width;
+                              // Since the primary objective is east/west I will lean towards e/w when moving diagonally
+                              let mut dir = Direction::South;
+                              if dx > x as u32  {
+                                  dir = Direction::East;
+                              } else if dx < x as u32 {
+                                  dir = Direction::West;
+                              } else if dy < y as u32 {
+                                  dir = Direction::North;
+                              } 
 
-                          if blocked[user_end as usize] {
-                              return Some(vec![(self.token.clone(), 5,  "Path Blocked".to_string()); 1]);
-                          }
-                          self.index = user_end;
-                          self.direction = dir;
-                          self.clear_movement_if_at_destination(user_end);
-                          None
-                      },
-                      None => {
-                          Some(vec![(self.token.clone(), 5,  "No Path Found".to_string()); 1])
-                      },
-                  }
-              } else if command.starts_with("numpad-") {
-                  let parts: Vec<&str> = command.split("-").collect();
-                  if parts.len() > 1 {
-                      match parts[1].parse::<u32>() {
-                          Ok(numpad) => {
-                              let mut x: u32 = self.index % width as u32;
-                              let mut y: u32 = self.index / width as u32;
-                              match numpad {
-                                  1 => {
-                                      x -= 1;
-                                      y += 1;
-                                  },
-                                  2 => {
-                                      y += 1;
-                                  },
-                                  3 => {
-                                      x += 1;
-                                      y += 1;
-                                  },
-                                  4 => {
-                                      x -= 1;
-                                  },
-                                  6 => {
-                                      x += 1;
-                                  },
-                                  7 => {
-                                      x -= 1;
-                                      y -= 1;
-                                  },
-                                  8 => {
-                                      y -= 1;
-                                  },
-                                  9 => {
-                                      x += 1;
-                                      y -= 1;
-                                  },
-                                  _ => {},
-                              };
-                              self.movement = Some(y * width as u32 + x);
+                              if blocked[user_end as usize] {
+                                  return Some(vec![(self.token.clone(), 5,  "Path Blocked".to_string()); 1]);
+                              }
+                              self.index = user_end;
+                              self.direction = dir;
+                              self.clear_movement_if_at_destination(user_end);
+                              None
+                          },
+                          None => {
+                              Some(vec![(self.token.clone(), 5,  "No Path Found".to_string()); 1])
+                          },
+                      }
+                  },
+                  Command::Numpad(numpad) => {
+                      let mut x: u32 = self.index % width;
+                      let mut y: u32 = self.index / width;
+                      match numpad {
+                          1 => {
+                              x -= 1;
+                              y += 1;
+                          },
+                          2 => {
+                              y += 1;
+                          },
+                          3 => {
+                              x += 1;
+                              y += 1;
+                          },
+                          4 => {
+                              x -= 1;
+                          },
+                          6 => {
+                              x += 1;
+                          },
+                          7 => {
+                              x -= 1;
+                              y -= 1;
+                          },
+                          8 => {
+                              y -= 1;
+                          },
+                          9 => {
+                              x += 1;
+                              y -= 1;
                           },
-                          Err(_) => {},
+                          _ => {},
                       };
-                  }
-                  None
-              } else if command.starts_with("skin "){
-                  let parts: Vec<&str> = command.split(" ").collect();
-                  if parts.len() > 1 {
-                      if parts[1].starts_with("/") {
-                          self.tile = format!("{}.", parts[1].to_string()[1..].to_string());
+                      self.movement = Some(y * width + x);
+                      None
+                  },
+                  Command::Skin(skin) => {
+                      if skin.starts_with("/") {
+                          self.tile = format!("{}.", skin[1..].to_string());
                       } else {
-                          self.tile = format!("players/{}.", parts[1]);
+                          self.tile = format!("players/{}.", skin);
                       }
                       Some(vec![(self.token.clone(), 3,  "Skin Changed".to_string()); 1])
-                  } else {
-                      None
-                  }
-              } else if command.starts_with("#view ") {
-                  let parts: Vec<&str> = command.split(" ").collect();
-                  if parts.len() > 2 {
-                      self.viewport_x = parts[1].parse::<u8>().unwrap();
-                      self.viewport_y = parts[2].parse::<u8>().unwrap();
+                  },
+                  Command::Ghost(Some(ghost)) => {
+                      self.ghost = ghost;
+                      let text = match ghost {
+                          Ghost::Off => "Ghost mode off",
+                          Ghost::On => "Ghost mode on",
+                          Ghost::Hidden => "Ghost mode on, hidden from non-admins",
+                      };
+                      Some(vec![(self.token.clone(), 3,  text.to_string()); 1])
+                  },
+                  Command::View(x, y) => {
+                      self.viewport_x = x;
+                      self.viewport_y = y;
                       Some(vec![(self.token.clone(), 3,  format!("View Changed {} {}", self.viewport_x, self.viewport_y).to_string()); 1])
-                  } else {
-                      None
-                  }
-              }else {
-                  //System message
-                  println!("{}", command);
-                  Some(vec![(self.token.clone(), 5,  "Bad command".to_string()); 1])
+                  },
+                  _ => {
+                      //System message
+                      Some(vec![(self.token.clone(), 5,  "Bad command".to_string()); 1])
+                  },
               }
            },
            None => { 
@@ -418,18 +437,18 @@ impl Controllable for Player {
     }
 
     fn does_block_index(&self, index: u32) -> bool {
-        self.index == index
+        self.ghost == Ghost::Off && self.index == index
     }
 
-    fn is_visible(&self, _: &GameMap) -> bool {
-        true
+    fn is_visible(&self, _: &GameMap, viewer: Role) -> bool {
+        self.ghost != Ghost::Hidden || viewer >= Role::Admin
     }
 
     fn hurt(&mut self, damage: i32) {
         self.hp = if self.hp > damage {self.hp - damage} else {self.max_hp};
     }
 
-    fn push_command(&mut self, command: String) {
+    fn push_command(&mut self, command: Command) {
         self.commands.insert(0, command);
     }
     
@@ -437,7 +456,7 @@ impl Controllable for Player {
         self.movement = Some(end);
     }
 
-    fn modify_connected_tiles(&mut self, _: u8, _: u8, _: &Vec<bool>) {}
+    fn modify_connected_tiles(&mut self, _: u32, _: u32, _: &Vec<bool>) {}
 
     fn get_type(&self) -> ControllableType {
         ControllableType::Player
@@ -446,4 +465,33 @@ impl Controllable for Player {
     fn get_viewport(&self) -> (u8, u8) {
         (self.viewport_x, self.viewport_y)
     }
+
+    fn get_properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Player;
+
+    #[test]
+    fn paths_along_a_long_corridor() {
+        //Scores past 9999 used to send the search back to tile 0, forever
+        let width = 12000;
+        let blocked = vec![false; width as usize];
+        assert_eq!(Player::path_next(width, 1, &blocked, 0, width - 1), Some(1));
+    }
+
+    #[test]
+    fn gives_up_on_unreachable_tiles() {
+        let width = 12000;
+        let mut blocked = vec![false; width as usize];
+        blocked[(width - 2) as usize] = true;
+        assert_eq!(Player::path_next(width, 1, &blocked, 0, width - 1), None);
+    }
 }
@@ -19,6 +19,9 @@ extern crate mio;
 use game::characters::Controllable;
 use game::characters::ControllableType;
 use game::gamemap::GameMap;
+use game::properties::{Properties, Property};
+use conn::command::Command;
+use conn::roles::Role;
 
 
 ///Defines the Item struct.
@@ -27,33 +30,69 @@ pub struct Teleporter {
     pub map: String,
     index: u32,
     use_default: bool,
-    x: u8, 
-    y: u8, 
+    x: u32, 
+    y: u32, 
+    properties: Properties,
 }
 
 impl Teleporter {
-    pub fn new(map: String, index: u32, default: bool, x: u8, y: u8) -> Teleporter {
+    pub fn new(map: String, index: u32, default: bool, x: u32, y: u32) -> Teleporter {
         Teleporter{
             map: map,
             index: index,
             use_default: default,
             x: x, 
             y: y, 
+            properties: Properties::new(),
         }
     }
 
-    pub fn teleport(&self, token: mio::Token) -> (mio::Token, String, Option<(u8,u8)>){
+    ///Gets the x,y on the target map, or None if it drops players at the map's start point
+    pub fn target(&self) -> Option<(u32, u32)> {
         if self.use_default {
-            (token.clone(), self.map.clone(), None)
+            None
         } else {
-            (token.clone(), self.map.clone(), Some((self.x, self.y)))
+            Some((self.x, self.y))
         }
     }
+
+    ///Describes where the teleporter goes
+    pub fn destination(&self) -> String {
+        if self.use_default {
+            format!("{} at its start point", self.map)
+        } else {
+            format!("{} at {} {}", self.map, self.x, self.y)
+        }
+    }
+
+    ///ask-map teleporters ask the player where to go, instead of sending them straight to map
+    pub fn asks(&self) -> bool {
+        self.use_default
+    }
+
+    ///The properties to write to a map file. Teleporters that weren't read from a file get a
+    ///destination bean like the editor makes.
+    pub fn map_properties(&self) -> Properties {
+        if !self.properties.is_empty() {
+            return self.properties.clone();
+        }
+        let mut destination = Properties::new();
+        destination.set_class("wyvern.lib.Location");
+        destination.insert("map", Property::String(self.map.clone()));
+        destination.insert("x", Property::Int(self.x as i64));
+        destination.insert("y", Property::Int(self.y as i64));
+        let mut properties = Properties::new();
+        properties.insert("destination", Property::Bean(destination));
+        if self.use_default {
+            properties.insert("ask-map", Property::Boolean(true));
+        }
+        properties
+    }
 }
 
 impl Controllable for Teleporter{
     
-    fn update(&mut self, _: u8, _: u8, _: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
+    fn update(&mut self, _: u32, _: u32, _: &Vec<bool>) -> Option<Vec<(mio::Token, u8, String)>> {
         None
     }
 
@@ -84,20 +123,20 @@ impl Controllable for Teleporter{
         false
     }
  
-    fn is_visible(&self, _: &GameMap) -> bool {
+    fn is_visible(&self, _: &GameMap, _: Role) -> bool {
         false
     }
 
     fn hurt(&mut self, _: i32) {
     }
 
-    fn push_command(&mut self, _: String) {
+    fn push_command(&mut self, _: Command) {
     }
     
     fn set_movement(&mut self, _: u32) {
     }
 
-    fn modify_connected_tiles(&mut self, _: u8, _: u8,  _: &Vec<bool>) {}
+    fn modify_connected_tiles(&mut self, _: u32, _: u32, _: &Vec<bool>) {}
 
     fn get_type(&self) -> ControllableType {
         ControllableType::Teleporter
@@ -106,4 +145,12 @@ impl Controllable for Teleporter{
     fn get_viewport(&self) -> (u8, u8) {
         (0,0)
     }
+
+    fn get_properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+    }
 }
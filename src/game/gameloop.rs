@@ -26,13 +26,23 @@ extern crate mio;
 use std::sync::mpsc::Sender;
 use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use std::fs;
+use std::collections::HashMap;
 use std::sync::RwLock;
 use std::sync::Mutex;
 use std::sync::Arc;
 
-use game::gamemap::GameMap;
+use game::gamemap::{GameMap, Teleport};
+use game::tiled;
 use conn::server::Msg;
+use conn::command::Command;
+use conn::roles::Role;
+use game::characters::Ghost;
+
+///Number of loop iterations between checks of the map file for changes. At 20ms a tick, this is
+///about once a second.
+const RELOAD_CHECK_TICKS: u32 = 50;
 
 /// This struct holds the map name, and a list of tokens that are connected to this game loop. 
 /// This structure creates the background thread that operates the actual game loop.
@@ -40,24 +50,27 @@ pub struct GameLoop {
     //Map with all items & tiles
     game_map: String,
     connections: Arc<RwLock<Vec<mio::Token>>>, 
-    add_connections: Arc<RwLock<Vec<(mio::Token, String, Option<(u8,u8)>)>>>, 
+    add_connections: Arc<RwLock<Vec<(mio::Token, String, Option<(u32, u32)>, Role, Ghost)>>>, 
     remove_connections: Arc<RwLock<Vec<mio::Token>>>, 
     command_queue: Arc<Mutex<Vec<Msg>>>, 
+    reload_requested: Arc<RwLock<bool>>,
+    //Set when the art aliases change, so the objects' art is worked out again
+    art_requested: Arc<RwLock<bool>>,
     to_game_send: Sender<Msg>,
 }
 
 impl GameLoop {
-    ///creates a new game loop
-    pub fn new(mapname : &str, send: Sender<Msg>) -> Option<GameLoop> {
+    ///creates a new game loop. If the map can't be loaded, the error says why.
+    pub fn new(mapname : &str, send: Sender<Msg>) -> Result<GameLoop, String> {
         if mapname.contains("..") {
             println!("Attempted relative path: {}", mapname);
-            None
+            Err("Map names can't contain ..".to_string())
         } else {
             let map = GameMap::new(&mapname);
             match map {
-                Err(s) => {
-                    println!("{}", s);
-                    None
+                Err(e) => {
+                    println!("{}: {}", mapname, e);
+                    Err(format!("{}: {}", mapname, e))
                 }, 
                 Ok(_) => {
                     let mut gloop = GameLoop {
@@ -66,10 +79,12 @@ impl GameLoop {
                         add_connections: Arc::new(RwLock::new(vec![])),
                         remove_connections: Arc::new(RwLock::new(vec![])),
                         command_queue: Arc::new(Mutex::new(vec![])),
+                        reload_requested: Arc::new(RwLock::new(false)),
+                        art_requested: Arc::new(RwLock::new(false)),
                         to_game_send: send,
                     };
                     gloop.start();
-                    Some(gloop)
+                    Ok(gloop)
                 }
             }
         }
@@ -84,58 +99,156 @@ impl GameLoop {
         let add = self.add_connections.clone();
         let remove = self.remove_connections.clone();
         let commands = self.command_queue.clone();
+        let reload = self.reload_requested.clone();
+        let art = self.art_requested.clone();
         let to_mio = self.to_game_send.clone();
         let map = self.game_map.clone();
         thread::spawn(move || {
+           let path = map.clone();
            let game_map = GameMap::new(&map);
            match game_map {
                Ok(mut map) => {
+                   let mut modified = GameLoop::last_modified(&path);
+                   let mut ticks: u32 = 0;
+                   //The last position sent for each player, by token, so it only goes out when it changes
+                   let mut positions: HashMap<usize, (u32, u32)> = HashMap::new();
                    loop {
                        thread::sleep(Duration::from_millis(20));
-                       //Have to do this inside a custom scope so the mutex will release
+                       //Checks whether the map file was saved since it was loaded, or if someone asked for a
+                       //reload. If so, the map is swapped out under the connected players.
+                       ticks = ticks.wrapping_add(1);
                        {
-                           let mut a = add.write().unwrap();
-                           let mut conn = connections.write().unwrap();
-                           for i in 0..a.len() {
-                               let mut exists = false;
-                               let (t, name, index) = a[i].clone();
-                               for c in 0..conn.len() {
-                                   if t.as_usize() == conn[c].as_usize() {
-                                       exists = true;
-                                       break;
-                                   }
+                           let mut requested = reload.write().unwrap();
+                           let mut changed = false;
+                           if ticks % RELOAD_CHECK_TICKS == 0 {
+                               let current = GameLoop::last_modified(&path);
+                               if current != modified {
+                                   modified = current;
+                                   changed = true;
                                }
-                               if !exists {
-                                   map.add_player(t, name, index);
-                                   conn.push(t);
+                           }
+                           if changed || *requested {
+                               *requested = false;
+                               let conn = connections.read().unwrap();
+                               match map.reload(&path) {
+                                   Ok(stuck) => {
+                                       for c in conn.iter() {
+                                           let _ = to_mio.send(Msg::TextOutput(c.clone(), 3, "The map has been reloaded".to_string()));
+                                       }
+                                       for c in stuck {
+                                           let _ = to_mio.send(Msg::TextOutput(c, 5, "There was no open spot for you on the reloaded map, so you are on the start point".to_string()));
+                                       }
+                                   },
+                                   Err(e) => {
+                                       println!("Failed to reload {}: {}", path, e);
+                                       for c in conn.iter() {
+                                           let _ = to_mio.send(Msg::TextOutput(c.clone(), 5, format!("Map reload failed: {}", e)));
+                                       }
+                                   },
                                }
                            }
-                           a.clear();
                        }
-                       //Have to do this inside a custom scope so the mutex will release
+                       //If the map is busy, the flag stays set & it is tried again next tick
+                       {
+                           let mut requested = art.write().unwrap();
+                           if *requested && map.refresh_art().is_ok() {
+                               *requested = false;
+                           }
+                       }
+                       //Removes go before adds, so someone jumping to another spot on the same map
+                       //gets taken off and put back on in a single tick. Both queues are locked
+                       //together so a remove can't slip in after its matching add was skipped.
                        {
                            let mut r = remove.write().unwrap();
+                           let mut a = add.write().unwrap();
                            let mut conn = connections.write().unwrap();
                            for i in 0..r.len() {
                                let t = r[i].clone();
                                map.remove_player(t);
+                               //Anyone coming straight back gets their new spot sent
+                               positions.remove(&t.as_usize());
                                for c in 0..conn.len() {
                                    if conn[c] == t{
-                                       conn.remove(i);
+                                       conn.remove(c);
                                        break;
                                    }
                                }
                            }
                            r.clear();
+                           for i in 0..a.len() {
+                               let mut exists = false;
+                               let (t, name, index, role, ghost) = a[i].clone();
+                               for c in 0..conn.len() {
+                                   if t.as_usize() == conn[c].as_usize() {
+                                       exists = true;
+                                       break;
+                                   }
+                               }
+                               if !exists {
+                                   map.add_player(t, name, index, role, ghost);
+                                   conn.push(t);
+                               }
+                           }
+                           a.clear();
                        }
                        //Putting this in a scope so that the commands can be repopulated when it is executing other parts
                        {
                             let mut c = commands.lock().unwrap();
                             for m in c.drain(..) {
                                 match m {
+                                    Msg::Command(token, Command::Inspect(dx, dy)) => {
+                                        match map.inspect(token, dx, dy) {
+                                            Ok(report) => {
+                                                let _ = to_mio.send(Msg::Inspect(token, report));
+                                            },
+                                            Err(e) => {
+                                                let _ = to_mio.send(Msg::TextOutput(token, 5, e));
+                                            },
+                                        }
+                                    },
+                                    Msg::Command(token, Command::MapInfo) => {
+                                        GameLoop::respond(&to_mio, token, Ok(map.header.to_string()));
+                                    },
+                                    Msg::Command(token, Command::Look(target)) => {
+                                        GameLoop::respond(&to_mio, token, map.look(token, target, false));
+                                    },
+                                    Msg::Command(token, Command::Read(target)) => {
+                                        GameLoop::respond(&to_mio, token, map.look(token, target, true));
+                                    },
+                                    Msg::Command(token, Command::Open(target)) => {
+                                        GameLoop::respond(&to_mio, token, map.open_door(token, target, true));
+                                    },
+                                    Msg::Command(token, Command::Close(target)) => {
+                                        GameLoop::respond(&to_mio, token, map.open_door(token, target, false));
+                                    },
+                                    Msg::Command(token, Command::Undo) => {
+                                        GameLoop::respond(&to_mio, token, map.undo());
+                                    },
+                                    Msg::Command(token, Command::Redo) => {
+                                        GameLoop::respond(&to_mio, token, map.redo());
+                                    },
+                                    Msg::Command(token, Command::SaveMap) if tiled::is_tiled(&path) => {
+                                        GameLoop::respond(&to_mio, token, Err(format!("{} was made in Tiled. Edits can't be written back to it.", path)));
+                                    },
+                                    Msg::Command(token, Command::SaveMap) => {
+                                        match map.save(&path) {
+                                            Ok(_) => {
+                                                //Caught up on the file's time, so the loop doesn't reload what it just wrote
+                                                modified = GameLoop::last_modified(&path);
+                                                GameLoop::respond(&to_mio, token, Ok(format!("Saved {}", path)));
+                                            },
+                                            Err(e) => {
+                                                GameLoop::respond(&to_mio, token, Err(format!("Couldn't save {}: {}", path, e)));
+                                            },
+                                        }
+                                    },
                                     Msg::Command(token, command) => {
-                                        println!("{}", command);
-                                        &map.push_command(token.clone(), command.clone()); 
+                                        println!("{:?}", command);
+                                        if command.is_edit() {
+                                            GameLoop::respond(&to_mio, token, map.edit(token, command));
+                                        } else {
+                                            map.push_command(token.clone(), command.clone());
+                                        }
                                     },
                                     _ => {},
                                 }
@@ -157,6 +270,15 @@ impl GameLoop {
                                if hp.is_some() {
                                    let _ = to_mio.send(Msg::Hp(conn.clone(), hp.unwrap()));
                                }
+                               match map.get_position(conn.clone()) {
+                                   Some((x, y)) => {
+                                       if positions.get(&conn.as_usize()) != Some(&(x, y)) {
+                                           positions.insert(conn.as_usize(), (x, y));
+                                           let _ = to_mio.send(Msg::Position(conn.clone(), x, y));
+                                       }
+                                   },
+                                   None => {},
+                               }
                                let screen = map.send_portion(conn.clone());
                                match screen {
                                    Some(s) => {
@@ -170,17 +292,26 @@ impl GameLoop {
                        //if they are on a teleporter it sends a Join message, and removes them from
                        //this loop & its map.
                        let teleports = map.do_teleports();
-                       for i in 0..teleports.len() {
-                           let (token, join, index) = teleports[i].clone();
-                           map.remove_player(token);
-                           let mut conn = connections.write().unwrap();
-                           for i in 0..conn.len() {
-                               if conn[i] == token{
-                                   conn.remove(i);
-                                   break;
-                               }
+                       for teleport in teleports {
+                           match teleport {
+                               Teleport::Join(token, join, index) => {
+                                   map.remove_player(token);
+                                   let mut conn = connections.write().unwrap();
+                                   for i in 0..conn.len() {
+                                       if conn[i] == token{
+                                           conn.remove(i);
+                                           break;
+                                       }
+                                   }
+                                   let _ = to_mio.send(Msg::Join(token, join, index));
+                               },
+                               Teleport::Ask(token, suggestion) => {
+                                   let _ = to_mio.send(Msg::AskMap(token, suggestion));
+                               },
+                               Teleport::Cancel(token) => {
+                                   let _ = to_mio.send(Msg::CancelAsk(token));
+                               },
                            }
-                           let _ = to_mio.send(Msg::Join(token, join, index));
                        }
                    }
                }, 
@@ -190,9 +321,9 @@ impl GameLoop {
     }
     
     ///Adds a token to be added
-    pub fn join(&mut self, token: mio::Token, name: String, index: Option<(u8, u8)>) {
+    pub fn join(&mut self, token: mio::Token, name: String, index: Option<(u32, u32)>, role: Role, ghost: Ghost) {
         let mut conn = self.add_connections.write().unwrap();
-        conn.push((token, name, index));
+        conn.push((token, name, index, role, ghost));
     }
 
     ///Adds a token to be removed.
@@ -201,8 +332,45 @@ impl GameLoop {
         conn.push(token);
     }
 
+    ///Lists the tokens of every connection currently in this game loop
+    pub fn members(&self) -> Vec<mio::Token> {
+        self.connections.read().unwrap().clone()
+    }
+
     ///Passes a command to the game loop
     pub fn send_command(&mut self, message: Msg) {
         self.command_queue.lock().unwrap().push(message);
     }
+
+    ///Asks the game loop to re-read its map file on the next tick
+    pub fn reload(&mut self) {
+        let mut requested = self.reload_requested.write().unwrap();
+        *requested = true;
+    }
+
+    ///Asks the game loop to work out its objects' art again on the next tick
+    pub fn refresh_art(&mut self) {
+        let mut requested = self.art_requested.write().unwrap();
+        *requested = true;
+    }
+
+    ///Sends the player the text from a command, or the reason it failed
+    fn respond(to_mio: &Sender<Msg>, token: mio::Token, result: Result<String, String>) {
+        let _ = match result {
+            Ok(text) => to_mio.send(Msg::TextOutput(token, 3, text)),
+            Err(e) => to_mio.send(Msg::TextOutput(token, 5, e)),
+        };
+    }
+
+    ///Gets the last modified time of the map file, if the filesystem supports it
+    fn last_modified(path: &str) -> Option<SystemTime> {
+        match fs::metadata(path) {
+            Ok(meta) => {
+                meta.modified().ok()
+            },
+            Err(_) => {
+                None
+            },
+        }
+    }
 }
\ No newline at end of file
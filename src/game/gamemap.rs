@@ -19,22 +19,44 @@ extern crate xml;
 
 use game::characters::Controllable;
 use game::characters::ControllableType;
-use game::characters::Direction;
+use game::characters::Ghost;
+use game::characters::footprint;
 use game::characters::player::Player;
-use game::characters::item::Item;
+use game::characters::item::{Item, ArchetypeSizes};
 use game::characters::connected::RoadWall;
 use game::characters::teleporter::Teleporter;
+use game::characters::door::Door;
 use game::Game;
+use game::maperror::MapError;
+use game::mapheader::MapHeader;
+use game::properties::{Properties, Property};
+use game::tiled;
+use game::aliases;
+use conn::command::{Command, EditTarget};
+use conn::roles::Role;
 
 use std::sync::Arc;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::Read;
 use std::collections::HashMap;
+use std::cmp;
+use std::ops::Range;
+use std::mem;
 
 use xml::reader::{EventReader, XmlEvent};
+use xml::attribute::OwnedAttribute;
+use xml::common::{Position, TextPosition};
 
 use self::slab::Index;
 
+///Edits each map remembers for undo
+const MAX_UNDO: usize = 100;
+
+///Most tiles a map can have, i.e. 2048x2048. Each tile is about 16 bytes, and every tick builds a
+///bool for each one, so this keeps a big map to tens of megabytes.
+pub const MAX_TILES: u32 = 1 << 22;
+
 /// This module holds all the map related stuff. It has the GameMap itself, along with the
 /// MapScreen, ScreenObjects, ScreenTerrain etc.  
 
@@ -43,20 +65,42 @@ use self::slab::Index;
 ///values
 #[derive(Clone)]
 pub struct GameMap {
-    pub width: u8,
-    pub height: u8,
+    pub width: u32,
+    pub height: u32,
     pub tiles: Arc<Vec<MapTile>>,
     pub objects: Arc<Vec<Box<Controllable>>>,
     pub teleporter: HashMap<u32,Teleporter>,
-    start_x: u8,
-    start_y: u8, 
+    //Everything from the map's <header>
+    pub header: MapHeader,
+    //Path of the map file, used to spot teleporters that go somewhere on the same map
+    pub name: String,
+    //Players who have been asked where an ask-map teleporter should take them, and which
+    //teleporter. They aren't asked again until they step off it.
+    asked: HashMap<usize, u32>,
+    //Role of each player on the map, by token. Decides who can see hidden ghosts.
+    roles: HashMap<usize, Role>,
+    //Tile names from file_full & images/, for working out road, wall & door art
+    tile_mappings: HashMap<String, i16>,
+    //Art for the out of bounds terrain, resolved along with the tiles
+    oob_art: Arc<String>,
+    //The last tile each player clicked on, by token. Edits can be aimed at it.
+    clicked: HashMap<usize, u32>,
+    //Edits that can be taken back, and ones that were, newest last. Each holds the changes
+    //that reverse it.
+    undo: Vec<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    start_x: u32,
+    start_y: u32, 
 }
 
 impl GameMap {
     ///This attemps to parse a file 
-    pub fn new(mapname: &str) -> Result<GameMap, String> {
+    pub fn new(mapname: &str) -> Result<GameMap, MapError> {
         if !GameMap::maps_exist(mapname) {
-            return Err("Map Not Found".to_string());
+            return Err(MapError::NotFound(mapname.to_string()));
+        }
+        if tiled::is_tiled(mapname) {
+            return tiled::load(mapname);
         }
         GameMap::parse_tiles(mapname)
     }
@@ -77,302 +121,507 @@ impl GameMap {
     ///parts.
     ///Basically, it opens the xml map file and parses it out. There are a few special sections that
     ///it handles. Header, Terrain, Roads and Teleporters. 
-    fn parse_tiles(path: &str) -> Result<GameMap, String>{
+    ///
+    ///Anything it can't read is returned as a MapError with the position in the file.
+    fn parse_tiles(path: &str) -> Result<GameMap, MapError>{
         println!("Parsing! {}", path);
         match File::open(path) {
             Err(_) => {
-               Err("Failed to parse".to_string()) 
+               Err(MapError::NotFound(path.to_string()))
             },
             Ok(file) => {
-                //The map struct variables
-                let mut tiles: Vec<MapTile> = vec![];
-                let mut objects: Vec<Box<Controllable>> = vec![];
-                let mut teleporters: HashMap<u32, Teleporter>= HashMap::new();
-                let mut width: u8 = 0;
-                let mut height: u8 = 0;
-                let mut start_x: u8 = 0;
-                let mut start_y: u8 = 0;
-                
-                //Declaring a bunch of variables to temporarily store values that cannot
-                //be turned into structs until an object closes.
-                
-                //Because headers & teleporters are not complete until the object closes, they store the 
-                //required children values in these variables.
-                
-                //header
-                let mut header = false;
-                let mut out_of_bounds_tile = String::new();
-                
-                //teleporter
-                let mut teleporter = false;
-                let mut teleporter_index = 0;
-                let mut teleporter_use_default = false;
-                let mut teleporter_x = 0;
-                let mut teleporter_y = 0;
-                let mut teleporter_height = 1;
-                let mut teleporter_width = 1;
-                let mut teleporter_map = String::new();
-                
-                //Values needed for the parser
-                let tile_mappings = Game::create_mappings();
-                let buf = BufReader::new(file);
-                let parser = EventReader::new(buf);
-                for event in parser {
-                    match event { 
-                        Ok(XmlEvent::StartElement {name, attributes, ..}) => {
-                            if name.local_name == "header" {
-                                println!("Header");
-                                header = true;
-                                for attr in attributes {
-                                    if attr.name.local_name == "width" {
-                                        match attr.value.parse::<u8>() {
-                                            Ok(w) => {
-                                                width = w;
-                                                println!("Width {}", width);
-                                            },
-                                            Err(_) => {
-                                                return Err("Bad Width".to_string());
-                                            },
-                                        }
-                                    } else if attr.name.local_name == "height" {
-                                        match attr.value.parse::<u8>() {
-                                            Ok(h) => {
-                                                height = h;
-                                                println!("Height {}", height);
-                                            },
-                                            Err(_) => {
-                                                return Err("Bad Height".to_string());
-                                            },
-                                        }
-                                    }
-                                }
-                                //Define header behavior
-                                if height > 0 && width > 0 {
+                GameMap::parse(path, BufReader::new(file))
+            },
+        }
+    }
 
-                                } else {
-                                    return Err("Invalid dimensions".to_string());
+    ///Parses a map from XML that is already in memory, like a map that was just written out. The
+    ///name is used like the path of a map file.
+    pub fn from_xml(name: &str, xml: &str) -> Result<GameMap, MapError> {
+        GameMap::parse(name, xml.as_bytes())
+    }
+
+    ///Starts a grass map for tests, written out the way the editor does so it goes through
+    ///from_xml like a real one
+    #[cfg(test)]
+    pub fn test(width: u32, height: u32) -> TestMap {
+        TestMap {
+            width: width,
+            height: height,
+            terrain: "terrain/grass".to_string(),
+            oob_terrain: None,
+            start: None,
+            body: String::new(),
+        }
+    }
+
+    ///Does the actual parsing for parse_tiles & from_xml
+    fn parse<R: Read>(path: &str, source: R) -> Result<GameMap, MapError> {
+        //The map struct variables
+        let mut tiles: Vec<MapTile> = vec![];
+        let mut objects: Vec<Box<Controllable>> = vec![];
+        let mut teleporters: HashMap<u32, Teleporter>= HashMap::new();
+        let mut width: u32 = 0;
+        let mut height: u32 = 0;
+        let mut start_x: u32 = 0;
+        let mut start_y: u32 = 0;
+        
+        //Declaring a bunch of variables to temporarily store values that cannot
+        //be turned into structs until an object closes.
+        
+        //Because headers & teleporters are not complete until the object closes, they store the 
+        //required children values in these variables.
+        
+        //header
+        let mut header = false;
+        let mut map_header = MapHeader::new();
+        
+        //teleporter
+        let mut teleporter = false;
+        let mut teleporter_index = 0;
+        let mut teleporter_height = 1;
+        let mut teleporter_width = 1;
+
+        //Properties of every arch & bean that is still open, innermost last. Arches
+        //remember which objects they made, so the properties can be handed over when
+        //the arch closes.
+        let mut open_properties: Vec<PropertyFrame> = vec![];
+        
+        //Values needed for the parser
+        let tile_mappings = Game::create_mappings();
+        //Big maps reuse a handful of terrain paths, so every tile points at a shared copy
+        let mut paths = TerrainPaths::new();
+        let mut sizes = ArchetypeSizes::new();
+        let mut parser = EventReader::new(source);
+        loop {
+            let event = parser.next();
+            let position = parser.position();
+            match event { 
+                Ok(XmlEvent::EndDocument) => {
+                    break;
+                },
+                Err(e) => {
+                    return Err(MapError::Xml {
+                        message: e.msg().to_string(),
+                        position: e.position(),
+                    });
+                },
+                Ok(XmlEvent::StartElement {name, attributes, ..}) => {
+                    if name.local_name == "header" {
+                        println!("Header");
+                        header = true;
+                        //The header's properties are collected like an arch's
+                        let mut frame = PropertyFrame::new(String::new(), 0..0);
+                        let class = attribute(&attributes, "class");
+                        if class.len() > 0 {
+                            frame.properties.set_class(&class);
+                        }
+                        open_properties.push(frame);
+                        for attr in attributes {
+                            if attr.name.local_name == "width" {
+                                match attr.value.parse::<u32>() {
+                                    Ok(w) => {
+                                        width = w;
+                                        println!("Width {}", width);
+                                    },
+                                    Err(_) => {
+                                        return Err(MapError::bad_value("header", "width", &attr.value, position));
+                                    },
                                 }
-                            } else if name.local_name == "bean" {
-                                //We don't do anything with this
-                            } else if name.local_name == "boolean" {
-                                if teleporter {
-                                    for attr in attributes {
-                                        if attr.name.local_name == "name" 
-                                            && attr.value == "ask-map" {
-                                            teleporter_use_default = true;
-                                        }
-                                    }
+                            } else if attr.name.local_name == "height" {
+                                match attr.value.parse::<u32>() {
+                                    Ok(h) => {
+                                        height = h;
+                                        println!("Height {}", height);
+                                    },
+                                    Err(_) => {
+                                        return Err(MapError::bad_value("header", "height", &attr.value, position));
+                                    },
                                 }
-                            } else if name.local_name == "string" {
-                                let mut is_map = false;
-                                let mut name = String::new();
-                                if teleporter {
-                                    for attr in attributes {
-                                        if attr.name.local_name == "name" 
-                                            && attr.value =="map" {
-                                                is_map = true;
-                                        } else if attr.name.local_name == "value" {
-                                            name = attr.value.to_string();
-                                        }
-                                    }
-                                    if is_map {
-                                        teleporter_map = name;
-                                    }
+                            }
+                        }
+                        //Define header behavior
+                        if height == 0 || width == 0 {
+                            return Err(MapError::Invalid {
+                                element: "header".to_string(),
+                                message: "needs a width and height above 0".to_string(),
+                                position: position,
+                            });
+                        }
+                        //Checked before anything is made for the tiles. checked_mul also catches
+                        //sizes that wouldn't fit a u32 tile index.
+                        match width.checked_mul(height) {
+                            Some(tiles) if tiles <= MAX_TILES => {},
+                            _ => {
+                                return Err(MapError::Invalid {
+                                    element: "header".to_string(),
+                                    message: format!("{}x{} is too big, maps can have up to {} tiles", width, height, MAX_TILES),
+                                    position: position,
+                                });
+                            },
+                        }
+                    } else if name.local_name == "bean" {
+                        //Beans only mean something inside an object's arch
+                        if open_properties.len() > 0 {
+                            let mut frame = PropertyFrame::new(attribute(&attributes, "name"), 0..0);
+                            let class = attribute(&attributes, "class");
+                            if class.len() > 0 {
+                                frame.properties.set_class(&class);
+                            }
+                            open_properties.push(frame);
+                        }
+                    } else if name.local_name == "boolean" {
+                        //No value means the box was ticked in the editor
+                        let value = match attributes.iter().find(|a| a.name.local_name == "value") {
+                            Some(attr) => attr.value != "false",
+                            None => true,
+                        };
+                        match open_properties.last_mut() {
+                            Some(frame) => {
+                                frame.properties.insert(&attribute(&attributes, "name"), Property::Boolean(value));
+                            },
+                            None => {},
+                        }
+                    } else if name.local_name == "string" {
+                        match open_properties.last_mut() {
+                            Some(frame) => {
+                                frame.properties.insert(&attribute(&attributes, "name"),
+                                                        Property::String(attribute(&attributes, "value")));
+                            },
+                            None => {},
+                        }
+                    } else if name.local_name == "arch" {
+                        if header {
+                            let mut terrain = String::new();
+                            let mut is_terrain = false;
+                            let mut is_oob = false;
+                            for attr in attributes {
+                                if attr.name.local_name == "name" &&
+                                    attr.value == "terrain" {
+                                        is_terrain = true;
+                                } else if attr.name.local_name == "path" { 
+                                    terrain = attr.value.clone();
+                                } else if attr.name.local_name == "name" &&
+                                 attr.value == "oob-terrain" {
+                                    is_oob = true;
                                 }
-                            } else if name.local_name == "arch" {
-                                if header {
-                                    let mut terrain = String::new();
-                                    let mut is_terrain = false;
-                                    let mut is_oob = false;
-                                    for attr in attributes {
-                                        if attr.name.local_name == "name" &&
-                                            attr.value == "terrain" {
-                                                is_terrain = true;
-                                        } else if attr.name.local_name == "path" { 
-                                            terrain = attr.value.clone();
-                                        } else if attr.name.local_name == "name" &&
-                                         attr.value == "oob-terrain" {
-                                            is_oob = true;
-                                        }
+                            }
+                            if is_terrain {
+                                let size = width as usize * height as usize;
+                                map_header.terrain = terrain.clone();
+                                tiles = vec![paths.tile(&terrain); size];
+                            } else if is_oob {
+                                map_header.oob_terrain = terrain;
+                            }
+                        } else {
+                            //Read the loc. 
+                            //If it is a rectangle, apply that tile to the
+                            //  entire area as the terrain.
+                            //Else add it to the array of map items  (need to refactor maps)
+                            let mut tile: String = "".to_string();
+                            let mut rect_x: u32 = 0;
+                            let mut rect_y: u32 = 0;
+                            let mut rect_w: u32 = 0;
+                            let mut rect_h: u32 = 0;
+                            let mut loc = String::new();
+                            for attr in attributes {
+                                if attr.name.local_name == "path" {
+                                    tile= attr.value;
+                                } else if attr.name.local_name =="loc" {
+                                    loc = attr.value.clone();
+                                    let split: Result<Vec<u32>, _> = attr.value.split_whitespace()
+                                        .map(|v| v.parse::<u32>()).collect();
+                                    match split {
+                                        Ok(ref v) if v.len() == 4 => {
+                                            rect_x = v[0];
+                                            rect_y = v[1];
+                                            rect_w = v[2];
+                                            rect_h = v[3];
+                                        },
+                                        Ok(ref v) if v.len() == 2 => {
+                                            rect_x = v[0];
+                                            rect_y = v[1];
+                                            rect_w = 1;
+                                            rect_h = 1;
+                                        },
+                                        _ => {
+                                            return Err(MapError::bad_value("arch", "loc", &attr.value, position));
+                                        },
                                     }
-                                    if is_terrain {
-                                        let size = width as u32 * height as u32;
-                                        for _ in 0..size {
-                                            let tile = MapTile::new(terrain.clone());
-                                            tiles.push(tile);
-                                        }
-                                    } else if is_oob {
-                                        out_of_bounds_tile = terrain;
+                                }
+                            }
+                            //Anything hanging off the edge would index past the tiles
+                            //Widened so a huge loc can't wrap around
+                            if rect_x as u64 + rect_w as u64 > width as u64
+                                || rect_y as u64 + rect_h as u64 > height as u64 {
+                                return Err(MapError::OutOfBounds {
+                                    element: "arch".to_string(),
+                                    value: loc,
+                                    width: width,
+                                    height: height,
+                                    position: position,
+                                });
+                            }
+                            if tiles.len() != width as usize * height as usize {
+                                return Err(MapError::Invalid {
+                                    element: "arch".to_string(),
+                                    message: format!("{} was placed before the header terrain", tile),
+                                    position: position,
+                                });
+                            }
+                            let first_object = objects.len();
+                            if tile.contains("terrain") {
+                                let terrain = paths.tile(&tile);
+                                for x in rect_x..(rect_x+rect_w) {
+                                    for y in rect_y..(rect_y+rect_h) {
+                                        let index: usize = y as usize * width as usize + x as usize;
+                                        tiles[index] = terrain.clone();
                                     }
+                                }
+                            } else if tile == "special/teleporter".to_string() {
+                                println!("Started teleporter");
+                                let index: u32 = rect_y * width + rect_x;
+                                teleporter = true;
+                                teleporter_index = index.clone();
+                                teleporter_height = rect_h.clone();
+                                teleporter_width = rect_w.clone();
+                                println!("Finished teleporter");
+                            } else {
+                                let connected = tile.contains("roads") || tile.contains("walls");
+                                //Objects bigger than a tile are placed once per footprint across
+                                //the rect, so a 3x3 building on a 3x3 loc is one building.
+                                let (step_w, step_h) = if connected {
+                                    (1, 1)
                                 } else {
-                                    //Read the loc. 
-                                    //If it is a rectangle, apply that tile to the
-                                    //  entire area as the terrain.
-                                    //Else add it to the array of map items  (need to refactor maps)
-                                    let mut tile: String = "".to_string();
-                                    let mut rect_x: u8 = 0;
-                                    let mut rect_y: u8 = 0;
-                                    let mut rect_w: u8 = 0;
-                                    let mut rect_h: u8 = 0;
-                                    for attr in attributes {
-                                        if attr.name.local_name == "path" {
-                                            tile= attr.value;
-                                        } else if attr.name.local_name =="loc" {
-                                            let split: Vec<&str>= attr.value.split(" ").collect();
-                                            if split.len() == 4 {
-                                                rect_x = split[0].parse::<u8>().unwrap();
-                                                rect_y = split[1].parse::<u8>().unwrap();
-                                                rect_w = split[2].parse::<u8>().unwrap();
-                                                rect_h = split[3].parse::<u8>().unwrap();
-                                            } else if split.len() == 2 {
-                                                rect_x = split[0].parse::<u8>().unwrap();
-                                                rect_y = split[1].parse::<u8>().unwrap();
-                                                rect_w = 1;
-                                                rect_h = 1;
-                                            }
-                                        }
-                                    }
-                                    if tile.contains("terrain") {
-                                        for x in rect_x..(rect_x+rect_w) {
-                                            for y in rect_y..(rect_y+rect_h) {
-                                                let index: usize = (y as usize * width as usize) as usize + x as usize;
-                                                tiles[index].tile = tile.clone();
-                                                tiles[index].blocked = false;
-                                            }
+                                    sizes.get(&tile)
+                                };
+                                let mut x = rect_x;
+                                while x < rect_x + rect_w {
+                                    let mut y = rect_y;
+                                    while y < rect_y + rect_h {
+                                        let index: u32 = y * width + x;
+                                        //This is a special case. The map editor treats the
+                                        //main road as terrain
+                                        if tile.contains("main_road") {
+                                            tiles[index as usize].blocked = false;
                                         }
-                                    } else if tile == "special/teleporter".to_string() {
-                                        println!("Started teleporter");
-                                        let index: u32 = rect_y as u32 * width as u32 + rect_x as u32;
-                                        teleporter = true;
-                                        teleporter_index = index.clone();
-                                        teleporter_height = rect_h.clone();
-                                        teleporter_width = rect_w.clone();
-                                        println!("Finished teleporter");
-                                    } else {
-                                        for x in rect_x..(rect_x+rect_w) {
-                                            for y in rect_y..(rect_y+rect_h) {
-                                                let index: u32 = (y as u32 * width as u32 ) as u32 + x as u32;
-                                                //This is a special case. The map editor treats the
-                                                //main road as terrain
-                                                if tile.contains("main_road") {
-                                                    tiles[index as usize].blocked = false;
-                                                }
-                                                //TODO doors & windows
-                                                if tile.contains("roads") || tile.contains("walls") { 
-                                                    objects.push(Box::new(RoadWall::new(tile.clone(), &tile_mappings, index)));
-                                                } else { 
-                                                    objects.push(Box::new(Item::new(tile.clone(), index)));
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            } else if name.local_name == "int" {
-                                let mut name = String::new(); 
-                                let mut val = 0;
-                                for attr in attributes {
-                                    if attr.name.local_name == "name" {
-                                        name = attr.value;
-                                    } else if attr.name.local_name == "value" {
-                                        val = attr.value.parse::<u8>().unwrap();
+                                        //Clipped so nothing hangs off the edge of the map
+                                        let size = (cmp::min(step_w, width - x), cmp::min(step_h, height - y));
+                                        objects.push(GameMap::create_object(&tile, index, size, width, &tile_mappings));
+                                        y += step_h;
                                     }
-                                }
-                                if teleporter && name == "x" {
-                                    teleporter_x = val;
-                                } else if teleporter && name == "y"{
-                                    teleporter_y = val;
-                                } else if name.contains("startX") {
-                                    start_x = val;
-                                } else if name.contains("startY") {
-                                    start_y = val;
+                                    x += step_w;
                                 }
                             }
-                        },
-                        Ok(XmlEvent::EndElement {name}) => {
-                            if name.local_name == "header" {
-                                header = false;
-                                for index in 0..tiles.len() {
-                                    if tiles[index].tile == out_of_bounds_tile {
-                                        tiles[index].blocked = true;
-                                    }
-                                }
-                            } else if name.local_name == "arch" && teleporter {
-                                println!("teleporter: {}", teleporter_index);
-                                let t_x = teleporter_index % width as u32;
-                                let t_y = teleporter_index / width as u32;
-                                for x in 0..teleporter_width {
-                                    for y in 0..teleporter_height {
-                                        println!("map {} index {} default {}", teleporter_map,
-                                                 teleporter_index, teleporter_use_default);
-                                        let index = (t_y as u32 + y as u32) as u32 * width as u32 + (t_x as u32 +x as u32) as u32;
-                                        teleporters.insert(teleporter_index,
-                                                           Teleporter::new(teleporter_map.clone(),
-                                                                              index,
-                                                                              teleporter_use_default,
-                                                                              teleporter_x,
-                                                                              teleporter_y));
-                                    }
+                            open_properties.push(PropertyFrame::new(String::new(), first_object..objects.len()));
+                        }
+                    } else if name.local_name == "int" {
+                        let name = attribute(&attributes, "name");
+                        let value = attribute(&attributes, "value");
+                        let val = match value.parse::<i64>() {
+                            Ok(v) => v,
+                            Err(_) => {
+                                return Err(MapError::bad_value("int", "value", &value, position));
+                            },
+                        };
+                        if header && (name.contains("startX") || name.contains("startY")) {
+                            let coord = match value.parse::<u32>() {
+                                Ok(v) => v,
+                                Err(_) => {
+                                    return Err(MapError::bad_value("int", "value", &value, position));
+                                },
+                            };
+                            if name.contains("startX") {
+                                start_x = coord;
+                            } else {
+                                start_y = coord;
+                            }
+                        }
+                        match open_properties.last_mut() {
+                            Some(frame) => {
+                                frame.properties.insert(&name, Property::Int(val));
+                            },
+                            None => {},
+                        }
+                    }
+                },
+                Ok(XmlEvent::EndElement {name}) => {
+                    if name.local_name == "header" {
+                        header = false;
+                        match open_properties.pop() {
+                            Some(frame) => {
+                                map_header.properties = frame.properties;
+                            },
+                            None => {},
+                        }
+                        map_header.width = width;
+                        map_header.height = height;
+                        for index in 0..tiles.len() {
+                            if *tiles[index].tile == map_header.oob_terrain {
+                                tiles[index].blocked = true;
+                            }
+                        }
+                    } else if name.local_name == "bean" {
+                        //The bottom frame is always an arch or the header, so anything above it is a bean
+                        if open_properties.len() > 1 {
+                            let bean = open_properties.pop().unwrap();
+                            let parent = open_properties.last_mut().unwrap();
+                            parent.properties.insert(&bean.name, Property::Bean(bean.properties));
+                        }
+                    } else if name.local_name == "arch" && !header {
+                        let frame = match open_properties.pop() {
+                            Some(frame) => frame,
+                            None => {
+                                continue;
+                            },
+                        };
+                        for object in objects[frame.objects.clone()].iter_mut() {
+                            object.set_properties(frame.properties.clone());
+                        }
+                        if teleporter {
+                            println!("teleporter: {}", teleporter_index);
+                            //The editor puts the map, x & y in a destination bean
+                            let ref properties = frame.properties;
+                            let destination = properties.get_bean("destination").unwrap_or(properties);
+                            let map = destination.get_string("map").unwrap_or("").to_string();
+                            let use_default = properties.get_bool("ask-map") || destination.get_bool("ask-map");
+                            let teleporter_x = match teleporter_coordinate(destination, "x", position) {
+                                Ok(x) => x,
+                                Err(e) => {
+                                    return Err(e);
+                                },
+                            };
+                            let teleporter_y = match teleporter_coordinate(destination, "y", position) {
+                                Ok(y) => y,
+                                Err(e) => {
+                                    return Err(e);
+                                },
+                            };
+                            let t_x = teleporter_index % width;
+                            let t_y = teleporter_index / width;
+                            for x in 0..teleporter_width {
+                                for y in 0..teleporter_height {
+                                    println!("map {} index {} default {}", map,
+                                             teleporter_index, use_default);
+                                    let index = (t_y + y) * width + t_x + x;
+                                    let mut t = Teleporter::new(map.clone(),
+                                                                index,
+                                                                use_default,
+                                                                teleporter_x,
+                                                                teleporter_y);
+                                    t.set_properties(properties.clone());
+                                    teleporters.insert(index, t);
                                 }
-
-                                //Reset teleporter values
-                                teleporter = false;
-                                teleporter_index = 0;
-                                teleporter_use_default = false;
-                                teleporter_x = 1;
-                                teleporter_y = 1;
-                                teleporter_height = 1;
-                                teleporter_width = 1;
-                                teleporter_map ="".to_string();
                             }
-                        },
-                        _ => {
 
-                        },
-                    }
-                }
-                //Telling roads & walls to draw based on surrounding tiles
-                let mut roads = vec![false; tiles.len()];
-                let mut wall = vec![false; tiles.len()];
-                let len = objects.len();
-                for i in 0..len {
-                    let ref mut object = objects[i];
-                    match object.get_type() {
-                        ControllableType::Road => {
-                            roads[object.get_location() as usize] = true;
-                        },
-                        ControllableType::Wall => {
-                            wall[object.get_location() as usize] = true;
-                        },
-                        _ => {},
+                            //Reset teleporter values
+                            teleporter = false;
+                            teleporter_index = 0;
+                            teleporter_height = 1;
+                            teleporter_width = 1;
+                        }
                     }
+                },
+                _ => {
+
+                },
+            }
+        }
+        GameMap::connect_objects(&mut objects, width, height);
+        let oob_art = Arc::new(aliases::resolve(map_header.oob_terrain()));
+        Ok(GameMap{
+            width: width,
+            height: height,
+            tiles: Arc::new(tiles),
+            objects: Arc::new(objects),
+            teleporter: teleporters,
+            header: map_header,
+            name: path.to_string(),
+            asked: HashMap::new(),
+            roles: HashMap::new(),
+            tile_mappings: tile_mappings,
+            oob_art: oob_art,
+            clicked: HashMap::new(),
+            undo: vec![],
+            redo: vec![],
+            start_x : start_x,
+            start_y : start_y,
+        })
+    }
+
+    ///Makes the object for an arch. Roads, walls & doors are always one tile. Other objects
+    ///cover size tiles, going right & down from index.
+    pub fn create_object(tile: &str, index: u32, size: (u32, u32), width: u32, tile_mappings: &HashMap<String, i16>) -> Box<Controllable> {
+        let connected = tile.contains("roads") || tile.contains("walls");
+        //TODO windows
+        if connected && tile.starts_with("walls/") && tile.to_lowercase().contains("door") {
+            Box::new(Door::new(tile.to_string(), tile_mappings, index))
+        } else if connected {
+            Box::new(RoadWall::new(tile.to_string(), tile_mappings, index))
+        } else if size == (1, 1) {
+            Box::new(Item::new(tile.to_string(), index))
+        } else {
+            Box::new(Item::sized(tile.to_string(), index, size, width))
+        }
+    }
+
+    ///Works out the art for every object & tile again, after the art aliases are re-read. Unlike
+    ///reload this keeps edits that haven't been saved, & doors stay as they are.
+    pub fn refresh_art(&mut self) -> Result<(), String> {
+        match Arc::get_mut(&mut self.objects) {
+            Some(objects) => {
+                for object in objects.iter_mut() {
+                    object.refresh_art(&self.tile_mappings);
                 }
-                for i in 0..len {
-                    let ref mut object = objects[i];
-                    match object.get_type() {
-                        ControllableType::Road => {
-                            object.modify_connected_tiles(width, height, &roads);
-                        },
-                        ControllableType::Wall=>{
-                            object.modify_connected_tiles(width, height, &wall);
-                        },
-                        _ => {},
-                    }
+                GameMap::connect_objects(objects, self.width, self.height);
+                let mut paths = TerrainPaths::new();
+                for tile in Arc::make_mut(&mut self.tiles).iter_mut() {
+                    tile.art = paths.tile(&tile.tile).art;
                 }
-                Ok(GameMap{
-                    width: width,
-                    height: height,
-                    tiles: Arc::new(tiles),
-                    objects: Arc::new(objects),
-                    teleporter: teleporters,
-                    start_x : start_x,
-                    start_y : start_y,
-                })
+                self.oob_art = Arc::new(aliases::resolve(self.header.oob_terrain()));
+                Ok(())
             },
+            None => {
+                Err("Map is in use".to_string())
+            },
+        }
+    }
+
+    ///Tells roads & walls to draw based on surrounding tiles
+    fn connect_objects(objects: &mut Vec<Box<Controllable>>, width: u32, height: u32) {
+        let size = width as usize * height as usize;
+        let mut roads = vec![false; size];
+        let mut wall = vec![false; size];
+        let len = objects.len();
+        for i in 0..len {
+            let ref mut object = objects[i];
+            match object.get_type() {
+                ControllableType::Road => {
+                    roads[object.get_location() as usize] = true;
+                },
+                //Doors sit in walls, so the walls on either side still join up
+                ControllableType::Wall | ControllableType::Door => {
+                    wall[object.get_location() as usize] = true;
+                },
+                _ => {},
+            }
+        }
+        for i in 0..len {
+            let ref mut object = objects[i];
+            match object.get_type() {
+                ControllableType::Road => {
+                    object.modify_connected_tiles(width, height, &roads);
+                },
+                ControllableType::Wall | ControllableType::Door =>{
+                    object.modify_connected_tiles(width, height, &wall);
+                },
+                _ => {},
+            }
         }
     }
 
     /// Adds the command from the client to the user object
-    pub fn push_command(&mut self, token: mio::Token, command: String) {
+    pub fn push_command(&mut self, token: mio::Token, command: Command) {
         println!("push command");
         match self.find_player_with_token(token.clone()) {
             Some(index) => {
@@ -380,24 +629,26 @@ impl GameMap {
                     Some(objects) => {
                         match objects.get_mut(index) {
                             Some(ref mut p) => {
-                                println!("Command {}", command);
-                                if command.starts_with("mouse") {
-                                    let parts: Vec<&str> = command.split_whitespace().collect();
-                                    //Mouse click x,y
-                                    let mx = parts[1].parse::<i32>().unwrap();
-                                    let my = parts[2].parse::<i32>().unwrap();
-                                    //old x,y
-                                    let oy = p.get_location() / (self.width as u32);
-                                    let ox = p.get_location() % (self.width as u32);
-                                    //change in x,y. -6 cause user is always in middle of screen, no matter the click.
-                                    let dx = if ox as i32 + mx > 6 { ox + mx as u32 -6 } else {0};
-                                    let dy = if oy as i32 + my > 6 { oy + my as u32 -6 } else {0};
-                                    println!("Move to x{} y{}", dx, dy);
-                                    let end = dy * self.width as u32 + dx;
-                                    //tiles[start as usize].user.unwrap().set_movement(end.clone());
-                                    p.set_movement(end.clone());
-                                } else {
-                                    p.push_command(command);
+                                println!("Command {:?}", command);
+                                match command {
+                                    Command::Mouse(mx, my) => {
+                                        //old x,y
+                                        let oy = p.get_location() / self.width;
+                                        let ox = p.get_location() % self.width;
+                                        //change in x,y. -6 cause user is always in middle of screen, no matter the click.
+                                        let dx = if ox as i32 + mx > 6 { ox + mx as u32 -6 } else {0};
+                                        let dy = if oy as i32 + my > 6 { oy + my as u32 -6 } else {0};
+                                        println!("Move to x{} y{}", dx, dy);
+                                        let end = dy * self.width + dx;
+                                        //tiles[start as usize].user.unwrap().set_movement(end.clone());
+                                        p.set_movement(end.clone());
+                                        if dx < self.width && dy < self.height {
+                                            self.clicked.insert(token.as_usize(), end);
+                                        }
+                                    },
+                                    _ => {
+                                        p.push_command(command);
+                                    },
                                 }
                             },
                             None => {},
@@ -435,25 +686,20 @@ impl GameMap {
         //Looping through all tiles
         let width = self.width;
         let height = self.height;
-        let mut blocked: Vec<bool> = vec![false; self.tiles.len()];
+        let mut blocked: Vec<bool> = self.tiles.iter().map(|t| t.blocked).collect();
         match Arc::get_mut(&mut self.objects) {
             Some(ref mut objects) => {
-                let len = objects.len();
-                for i in 0..len {
-                    //O(n^2 * m) to find blockages
-                    for i in 0..len {
-                        let ref o = objects[i];
-                        let index = o.get_location();
+                //Built once a tick, not once an object, since big maps can't walk every tile
+                //for every object
+                for o in objects.iter() {
+                    for index in footprint(&**o, width) {
                         if !blocked[index as usize] {
                             blocked[index as usize] = o.does_block_index(index);
                         }
                     }
-                    let tiles_len = self.tiles.len();
-                    for i in 0..tiles_len {
-                        if !blocked[i] {
-                            blocked[i] = self.tiles[i].blocked;
-                        }
-                    }
+                }
+                let len = objects.len();
+                for i in 0..len {
                     //I hate that I generate a list of blocked tiles. I would rather 
                     //pass a reference to the map objects, but I could not figure
                     //out how to do that in rust, since it does not allow any
@@ -468,6 +714,13 @@ impl GameMap {
                         },
                         None => {},
                     }
+                    //Wherever it moved to is taken for everything after it this tick. The spot
+                    //it left stays blocked until the next tick, same as before.
+                    for index in footprint(&*objects[i], width) {
+                        if !blocked[index as usize] {
+                            blocked[index as usize] = objects[i].does_block_index(index);
+                        }
+                    }
                 }
             },
             None =>{
@@ -477,24 +730,58 @@ impl GameMap {
         retval
     }
 
-    ///Checks for any players on teleporters. Returns a vector of tuples containing
-    ///the player token, the map name and a start x,y if specified by the teleporter
-    pub fn do_teleports(&self) -> Vec<(mio::Token, String, Option<(u8,u8)>)> {
+    ///Checks for any players on teleporters. Teleporters to somewhere else on this map move the
+    ///player right away. The rest are returned for the game loop, either to join another map or
+    ///to ask the player where they want to go. Players who walked off a teleporter that asked
+    ///have the question taken back.
+    pub fn do_teleports(&mut self) -> Vec<Teleport> {
         let mut retval = vec![];
-        let ref objects = self.objects;
-        let len = objects.len();
-        for i in 0..len {
-            let ref index = objects[i].get_location();
-            match objects[i].get_token() {
-                Some(token) => {
-                    match self.teleporter.get(index) {
-                        Some(tele) => {
-                            retval.push(tele.teleport(token.clone()))
-                        },
-                        None => {},
-                    }
+        let mut moves = vec![];
+        let mut asked = HashMap::new();
+        {
+            let ref objects = self.objects;
+            let len = objects.len();
+            for i in 0..len {
+                let ref index = objects[i].get_location();
+                match objects[i].get_token() {
+                    Some(token) => {
+                        match self.teleporter.get(index) {
+                            Some(tele) => {
+                                if tele.asks() {
+                                    asked.insert(token.as_usize(), *index);
+                                    if self.asked.get(&token.as_usize()) != Some(index) {
+                                        retval.push(Teleport::Ask(token, tele.map.clone()));
+                                    }
+                                } else if Game::loop_name(&tele.map) == self.name {
+                                    let (x, y) = match tele.target() {
+                                        Some(target) => target,
+                                        None => self.start(),
+                                    };
+                                    moves.push((i, x, y));
+                                } else {
+                                    retval.push(Teleport::Join(token, tele.map.clone(), tele.target()));
+                                }
+                            },
+                            None => {},
+                        }
+                        if !asked.contains_key(&token.as_usize()) && self.asked.contains_key(&token.as_usize()) {
+                            retval.push(Teleport::Cancel(token));
+                        }
+                    },
+                    None => {},
+                }
+            }
+        }
+        self.asked = asked;
+        for (i, x, y) in moves {
+            let spot = self.find_open(x, y);
+            match (spot, Arc::get_mut(&mut self.objects)) {
+                (Some(open), Some(objects)) => {
+                    objects[i].set_location(open);
+                    //Drops wherever they were walking to, so they don't walk back
+                    objects[i].set_movement(open);
                 },
-                None => {},
+                _ => {},
             }
         }
         retval
@@ -529,10 +816,14 @@ impl GameMap {
             Some(index) => {
                 match self.objects.get(index) {
                     Some(ref p) => {
-                        let x = p.get_location() % self.width as u32;
-                        let y = p.get_location() / self.width as u32;
+                        let x = p.get_location() % self.width;
+                        let y = p.get_location() / self.width;
                         let (view_x, view_y) = p.get_viewport();
-                        Some(MapScreen::new(self, x, y, view_x, view_y))
+                        let role = match self.roles.get(&token.as_usize()) {
+                            Some(role) => *role,
+                            None => Role::Player,
+                        };
+                        Some(MapScreen::new(self, x, y, view_x, view_y, token, role))
                     },
                     None => {
                         return None;
@@ -545,8 +836,9 @@ impl GameMap {
         }
     }
 
-    /// Adds a player to the map. Puts it at the starting location.
-    pub fn add_player(&mut self, token: mio::Token, name:String, index: Option<(u8, u8)>) {
+    /// Adds a player to the map. Puts it at the starting location, or the given x,y. If that
+    /// spot is taken it uses the closest open tile instead, unless the player is a ghost.
+    pub fn add_player(&mut self, token: mio::Token, name:String, index: Option<(u32, u32)>, role: Role, ghost: Ghost) {
         println!("Add Player");
         let startx;
         let starty;
@@ -560,74 +852,562 @@ impl GameMap {
                 starty = self.start_y;
             },
         }
-        self.add_player_at(&mut Player::new(name, token), startx, starty, Direction::All);
-    }
-
-    ///Recursively searches for an open location. Sadly this is a horrible algorithm, and will build characters
-    ///all in one direction before it tries any of the others. Ugly. What really kills it is that
-    ///it exectures on the game loop & stops everything while it searches. If the x direction is
-    ///full, it will take forever.
-    fn add_player_at(&mut self, player: &mut Player, x: u8, y: u8, direction: Direction) -> bool {
-        println!("adding at {} {}", x, y);
-        let index = y as u32 * self.width as u32 + x as u32;
-        if x < self.width && y < self.height {
-            let mut is_open = true;
-            {
+        let spot = if ghost != Ghost::Off && startx < self.width && starty < self.height {
+            Some(starty * self.width + startx)
+        } else {
+            self.find_open(startx, starty)
+        };
+        match spot {
+            Some(open) => {
+                println!("adding at {} {}", open % self.width, open / self.width);
+                let mut player = Player::new(name, token);
+                player.set_location(open);
+                player.ghost = ghost;
+                self.roles.insert(token.as_usize(), role);
                 match Arc::get_mut(&mut self.objects) {
                     Some(objects) => {
-                        for i in 0..objects.len() {
-                            if objects[i].does_block_index(index) {
-                                is_open = false;
-                                break;
-                            }
-                        }
+                        objects.push(Box::new(player));
                     },
-                    None => {},
+                    None =>{},
+                }
+            },
+            None => {
+                println!("No open tile for player");
+            },
+        }
+    }
+
+    /// Returns the x,y of the player with the given token
+    pub fn get_position(&self, token: mio::Token) -> Option<(u32, u32)> {
+        match self.find_player_with_token(token) {
+            Some(index) => {
+                let location = self.objects[index].get_location();
+                Some((location % self.width, location / self.width))
+            },
+            None => {
+                None
+            },
+        }
+    }
+
+    ///Re-parses the map file and swaps it in place. Every player is carried over to the new map at
+    ///the same x,y, or the closest open tile if that spot is now blocked or off the map. If the new
+    ///map has no open tile at all, they are put on the start point anyway, so nobody is left
+    ///without a player. Returns the tokens of the players that had to go on the start point.
+    pub fn reload(&mut self, path: &str) -> Result<Vec<mio::Token>, String> {
+        let mut fresh = match GameMap::new(path) {
+            Ok(map) => {
+                map
+            },
+            Err(e) => {
+                return Err(e.to_string());
+            },
+        };
+        let mut players = vec![];
+        match Arc::get_mut(&mut self.objects) {
+            Some(objects) => {
+                let mut i = 0;
+                while i < objects.len() {
+                    if objects[i].get_token().is_some() {
+                        players.push(objects.remove(i));
+                    } else {
+                        i = i + 1;
+                    }
                 }
+            },
+            None => {
+                return Err("Map is in use".to_string());
+            },
+        }
+        let mut tokens = vec![];
+        for mut player in players.into_iter() {
+            let x = player.get_location() % self.width;
+            let y = player.get_location() / self.width;
+            let index = match fresh.find_open(x, y) {
+                Some(index) => {
+                    index
+                },
+                None => {
+                    println!("No room for player on reloaded map, using the start point");
+                    match player.get_token() {
+                        Some(token) => {
+                            tokens.push(token);
+                        },
+                        None => {},
+                    }
+                    let (start_x, start_y) = fresh.start();
+                    cmp::min(start_y, fresh.height - 1) * fresh.width + cmp::min(start_x, fresh.width - 1)
+                },
+            };
+            player.set_location(index);
+            //Nothing else has the new map yet
+            Arc::get_mut(&mut fresh.objects).unwrap().push(player);
+        }
+        fresh.roles = self.roles.clone();
+        *self = fresh;
+        Ok(tokens)
+    }
+
+    ///Gets the x,y new players start at
+    pub fn start(&self) -> (u32, u32) {
+        (self.start_x, self.start_y)
+    }
+
+    ///Checks if a tile can be stood on. It has to have open terrain and nothing blocking it.
+    pub fn is_open(&self, index: u32) -> bool {
+        if index as usize >= self.tiles.len() || self.tiles[index as usize].blocked {
+            return false;
+        }
+        for object in self.objects.iter() {
+            if object.does_block_index(index) {
+                return false;
             }
-            if is_open {
-                player.set_location(index);
-                match Arc::get_mut(&mut self.objects) {
-                    Some(objects) => {
-                        objects.push(Box::new(player.clone()));
-                    },
-                    None =>{},
+        }
+        true
+    }
+
+    ///Searches outward from x,y one ring at a time, returning the index of the closest open tile.
+    ///Coordinates off the edge of the map are pulled back onto it first.
+    pub fn find_open(&self, x: u32, y: u32) -> Option<u32> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+        let width = self.width as i32;
+        let height = self.height as i32;
+        let x = if x as i32 >= width { width - 1 } else { x as i32 };
+        let y = if y as i32 >= height { height - 1 } else { y as i32 };
+        let max = if width > height { width } else { height };
+        for radius in 0..max {
+            for dy in -radius..(radius + 1) {
+                for dx in -radius..(radius + 1) {
+                    //Only the outer ring, the inside has already been checked
+                    if dx.abs() != radius && dy.abs() != radius {
+                        continue;
+                    }
+                    let cx = x + dx;
+                    let cy = y + dy;
+                    if cx < 0 || cy < 0 || cx >= width || cy >= height {
+                        continue;
+                    }
+                    let index = cy as u32 * self.width + cx as u32;
+                    if self.is_open(index) {
+                        return Some(index);
+                    }
                 }
-                true
-            } else {
-                match direction {
-                     Direction::All => { 
-                         (x > 0 && self.add_player_at(player, x-1, y, Direction::West))
-                             ||  self.add_player_at(player, x+1, y, Direction::East) 
-                             || (y > 0 && self.add_player_at(player, x, y-1, Direction::North))
-                             || self.add_player_at(player, x, y+1, Direction::South)
-                     },
-                     Direction::East=> { 
-                          self.add_player_at(player, x + 1, y, Direction::East)
-                     },
-                     Direction::West=> { 
-                          x > 0 && self.add_player_at(player, x - 1, y, Direction::West)
-                         
-                     },
-                     Direction::South=> { 
-                          self.add_player_at(player, x, y+1, Direction::South)
-                     },
-                     Direction::North=> { 
-                         y > 0 && self.add_player_at(player, x, y-1, Direction::North)
-                     },
-                     _ => {
-                         false
-                     },
+            }
+        }
+        None
+    }
+
+    ///Describes what is on a tile: dx,dy away from the player, or the last one they clicked.
+    ///Without a target it checks the player's own tile and then the ones next to it. Reading only
+    ///works on tiles next to the player, and prefers objects with something written on them.
+    pub fn look(&self, token: mio::Token, target: Option<EditTarget>, read: bool) -> Result<String, String> {
+        let location = match self.find_player_with_token(token) {
+            Some(index) => {
+                self.objects[index].get_location()
+            },
+            None => {
+                return Err("You aren't on the map".to_string());
+            },
+        };
+        let x = (location % self.width) as i64;
+        let y = (location / self.width) as i64;
+        let indexes = match target {
+            Some(target) => {
+                let index = self.edit_target(token, target)?;
+                let dx = (index % self.width) as i64 - x;
+                let dy = (index / self.width) as i64 - y;
+                if read && (dx.abs() > 1 || dy.abs() > 1) {
+                    return Err("You need to be next to it to read it".to_string());
+                }
+                vec![index]
+            },
+            None => {
+                let offsets = [(0, 0), (0, -1), (0, 1), (1, 0), (-1, 0), (-1, -1), (1, -1), (-1, 1), (1, 1)];
+                offsets.iter()
+                    .map(|&(dx, dy)| (x + dx, y + dy))
+                    .filter(|&(x, y)| x >= 0 && y >= 0 && x < self.width as i64 && y < self.height as i64)
+                    .map(|(x, y)| y as u32 * self.width + x as u32)
+                    .collect()
+            },
+        };
+        let role = match self.roles.get(&token.as_usize()) {
+            Some(role) => *role,
+            None => Role::Player,
+        };
+        let mut seen = None;
+        for index in indexes.iter() {
+            for object in self.objects.iter() {
+                if object.get_token() == Some(token) || !object.is_visible(self, role)
+                    || !footprint(&**object, self.width).contains(index) {
+                    continue;
+                }
+                if read && GameMap::writing(&**object).is_some() {
+                    return Ok(GameMap::describe(&**object, true));
+                }
+                if seen.is_none() {
+                    seen = Some(GameMap::describe(&**object, read));
+                }
+            }
+        }
+        match (seen, target) {
+            (Some(text), _) => {
+                Ok(text)
+            },
+            (None, _) if read => {
+                Err("There is nothing to read there".to_string())
+            },
+            (None, Some(_)) => {
+                Ok(format!("You see {}.", GameMap::name_from_path(&self.tiles[indexes[0] as usize].tile)))
+            },
+            (None, None) => {
+                Ok("There is nothing here".to_string())
+            },
+        }
+    }
+
+    ///Opens or closes the door dx,dy away from the player, or the first one next to them. A door
+    ///can't be closed on someone standing in it.
+    pub fn open_door(&mut self, token: mio::Token, target: Option<(i32, i32)>, open: bool) -> Result<String, String> {
+        let location = match self.find_player_with_token(token) {
+            Some(index) => {
+                self.objects[index].get_location()
+            },
+            None => {
+                return Err("You aren't on the map".to_string());
+            },
+        };
+        let offsets = match target {
+            Some((dx, dy)) => {
+                //Compared without abs, which overflows on i32::MIN
+                if dx < -1 || dx > 1 || dy < -1 || dy > 1 {
+                    return Err("You need to be next to the door".to_string());
                 }
+                vec![(dx, dy)]
+            },
+            None => {
+                vec![(0, -1), (0, 1), (1, 0), (-1, 0), (-1, -1), (1, -1), (-1, 1), (1, 1)]
+            },
+        };
+        let role = match self.roles.get(&token.as_usize()) {
+            Some(role) => *role,
+            None => Role::Player,
+        };
+        let x = (location % self.width) as i64;
+        let y = (location / self.width) as i64;
+        let mut doors = vec![];
+        for &(dx, dy) in offsets.iter() {
+            let (door_x, door_y) = (x + dx as i64, y + dy as i64);
+            if door_x < 0 || door_y < 0 || door_x >= self.width as i64 || door_y >= self.height as i64 {
+                continue;
             }
+            doors.push(door_y as u32 * self.width + door_x as u32);
+        }
+        let width = self.width;
+        match Arc::get_mut(&mut self.objects) {
+            Some(objects) => {
+                for index in doors {
+                    let door = objects.iter().position(|o| {
+                        match o.get_type() {
+                            ControllableType::Door => o.get_location() == index,
+                            _ => false,
+                        }
+                    });
+                    match door {
+                        Some(i) => {
+                            let occupied = objects.iter().any(|o| {
+                                o.get_token().is_some() && footprint(&**o, width).contains(&index)
+                            });
+                            if !open && occupied {
+                                return Err("Something is in the way".to_string());
+                            }
+                            return objects[i].open(open, role);
+                        },
+                        None => {},
+                    }
+                }
+                Err("There is no door there".to_string())
+            },
+            None => {
+                Err("The map is busy, try again".to_string())
+            },
+        }
+    }
+
+    ///The text written on an object, if it has any
+    fn writing(object: &Controllable) -> Option<&str> {
+        let ref properties = object.get_properties();
+        properties.get_string("text").or(properties.get_string("message"))
+    }
+
+    ///Text for look or read. Signs show their text, everything else its description. Anything
+    ///without either is described by its name property or its art.
+    fn describe(object: &Controllable, read: bool) -> String {
+        let ref properties = object.get_properties();
+        let writing = GameMap::writing(object);
+        let description = properties.get_string("description");
+        let text = if read { writing.or(description) } else { description.or(writing) };
+        match text {
+            Some(text) => {
+                text.to_string()
+            },
+            None => {
+                match properties.get_string("name") {
+                    Some(name) => format!("You see {}.", name),
+                    None => format!("You see {}.", GameMap::name_from_path(&object.get_tile())),
+                }
+            },
+        }
+    }
+
+    ///Makes a readable name from an art path, i.e. scenery/sign1 is "a sign"
+    fn name_from_path(path: &str) -> String {
+        let last = path.trim_matches(|c: char| c == '.' || c == '/').rsplit('/').next().unwrap_or("");
+        let name = last.split('.').next().unwrap_or("")
+            //Digits are variations, and capital NSEW the direction a road or wall connects
+            .trim_matches(|c: char| c.is_digit(10) || c == '_' || "NSEW".contains(c))
+            .replace('_', " ");
+        if name.len() == 0 {
+            "something".to_string()
+        } else if name.starts_with(|c: char| "aeiou".contains(c)) {
+            format!("an {}", name)
         } else {
-            false
+            format!("a {}", name)
+        }
+    }
+
+    ///Works out which tile an edit is aimed at
+    fn edit_target(&self, token: mio::Token, target: EditTarget) -> Result<u32, String> {
+        let location = match self.find_player_with_token(token) {
+            Some(index) => {
+                self.objects[index].get_location()
+            },
+            None => {
+                return Err("You aren't on the map".to_string());
+            },
+        };
+        match target {
+            EditTarget::Here => {
+                Ok(location)
+            },
+            EditTarget::Offset(dx, dy) => {
+                let x = (location % self.width) as i64 + dx as i64;
+                let y = (location / self.width) as i64 + dy as i64;
+                if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+                    Err(format!("{} {} is off the map", x, y))
+                } else {
+                    Ok(y as u32 * self.width + x as u32)
+                }
+            },
+            EditTarget::Clicked => {
+                match self.clicked.get(&token.as_usize()) {
+                    Some(index) => Ok(*index),
+                    None => Err("You haven't clicked on the map yet".to_string()),
+                }
+            },
+        }
+    }
+
+    ///Runs paint, place, erase & teleporter. The change shows up for everyone on the next tick,
+    ///and can be taken back with undo.
+    pub fn edit(&mut self, token: mio::Token, command: Command) -> Result<String, String> {
+        let (changes, message) = match command {
+            Command::Paint(terrain, target) => {
+                let index = self.edit_target(token, target)?;
+                //The parser decides terrain the same way
+                if !terrain.contains("terrain") {
+                    return Err(format!("{} isn't terrain. Use place for objects.", terrain));
+                }
+                let mut message = format!("Painted {} at {} {}", terrain, index % self.width, index / self.width);
+                let art = aliases::resolve(&terrain);
+                if !self.tile_mappings.contains_key(&art) {
+                    message.push_str(". There's no art for it, so it shows as a placeholder.");
+                }
+                let tile = MapTile::new(Arc::new(terrain), Arc::new(art));
+                (vec![Change::Terrain(index, tile)], message)
+            },
+            Command::Place(path, target) => {
+                let index = self.edit_target(token, target)?;
+                if path.contains("terrain") {
+                    return Err(format!("{} is terrain. Use paint for terrain.", path));
+                } else if path == "special/teleporter" {
+                    return Err("Use the teleporter command to add teleporters".to_string());
+                }
+                let (x, y) = (index % self.width, index / self.width);
+                let size = if path.contains("roads") || path.contains("walls") {
+                    (1, 1)
+                } else {
+                    let (w, h) = ArchetypeSizes::new().get(&path);
+                    (cmp::min(w, self.width - x), cmp::min(h, self.height - y))
+                };
+                let message = format!("Placed {} at {} {}", path, x, y);
+                let arch = Arch {
+                    path: path,
+                    index: index,
+                    size: size,
+                    properties: Properties::new(),
+                };
+                (vec![Change::Add(arch)], message)
+            },
+            Command::Erase(target) => {
+                let index = self.edit_target(token, target)?;
+                let mut changes = vec![];
+                for object in self.objects.iter() {
+                    if object.get_token().is_none() && footprint(&**object, self.width).contains(&index) {
+                        changes.push(Change::Remove(Arch::from_object(&**object)));
+                    }
+                }
+                if self.teleporter.contains_key(&index) {
+                    changes.push(Change::Teleporter(index, None));
+                }
+                if changes.len() == 0 {
+                    return Err(format!("Nothing to erase at {} {}", index % self.width, index / self.width));
+                }
+                let message = format!("Erased {} thing(s) at {} {}", changes.len(), index % self.width, index / self.width);
+                (changes, message)
+            },
+            Command::Teleporter(map, x, y, target) => {
+                let index = self.edit_target(token, target)?;
+                if map.contains("..") || !Game::valid_instance(&map) {
+                    return Err(format!("{} isn't a map a teleporter can go to", map));
+                }
+                let message = format!("Added a teleporter to {} at {} {}", map, x, y);
+                let teleporter = Teleporter::new(map, index, false, x, y);
+                (vec![Change::Teleporter(index, Some(teleporter))], message)
+            },
+            _ => {
+                return Err("That isn't an edit".to_string());
+            },
+        };
+        let reverse = self.apply_changes(changes)?;
+        self.undo.push(reverse);
+        if self.undo.len() > MAX_UNDO {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+        Ok(message)
+    }
+
+    ///Takes back the last edit. It stays on the list if the map is busy, so undo can be tried again.
+    pub fn undo(&mut self) -> Result<String, String> {
+        match self.undo.last().cloned() {
+            Some(changes) => {
+                let reverse = self.apply_changes(changes)?;
+                self.undo.pop();
+                self.redo.push(reverse);
+                Ok("Undid the last edit".to_string())
+            },
+            None => {
+                Err("There is nothing to undo".to_string())
+            },
+        }
+    }
+
+    ///Puts back the last edit that was undone
+    pub fn redo(&mut self) -> Result<String, String> {
+        match self.redo.last().cloned() {
+            Some(changes) => {
+                let reverse = self.apply_changes(changes)?;
+                self.redo.pop();
+                self.undo.push(reverse);
+                Ok("Redid the last edit".to_string())
+            },
+            None => {
+                Err("There is nothing to redo".to_string())
+            },
+        }
+    }
+
+    ///Makes the changes, and returns the ones that put things back, in the order to run them.
+    ///Either all of them go in or none do.
+    fn apply_changes(&mut self, changes: Vec<Change>) -> Result<Vec<Change>, String> {
+        //The objects can only be changed while nothing else holds them. Nothing can take another
+        //hold while this has the map, so checking once up front means an edit never stops half way.
+        if Arc::get_mut(&mut self.objects).is_none() {
+            return Err("The map is busy, try again".to_string());
+        }
+        let mut reverse = vec![];
+        for change in changes {
+            reverse.push(self.apply_change(change));
         }
+        GameMap::connect_objects(Arc::get_mut(&mut self.objects).unwrap(), self.width, self.height);
+        reverse.reverse();
+        Ok(reverse)
+    }
+
+    ///Makes one change. Only apply_changes calls this, after making sure the objects are free.
+    fn apply_change(&mut self, change: Change) -> Change {
+        match change {
+            Change::Terrain(index, tile) => {
+                let tiles = Arc::make_mut(&mut self.tiles);
+                let old = mem::replace(&mut tiles[index as usize], tile);
+                Change::Terrain(index, old)
+            },
+            Change::Add(arch) => {
+                let mut object = GameMap::create_object(&arch.path, arch.index, arch.size, self.width, &self.tile_mappings);
+                object.set_properties(arch.properties.clone());
+                Arc::get_mut(&mut self.objects).unwrap().push(object);
+                Change::Remove(arch)
+            },
+            Change::Remove(arch) => {
+                let objects = Arc::get_mut(&mut self.objects).unwrap();
+                //The newest match, so undoing a place takes away what was placed
+                match objects.iter().rposition(|o| {
+                    o.get_token().is_none() && o.get_location() == arch.index && o.get_path() == arch.path
+                }) {
+                    Some(i) => {
+                        objects.remove(i);
+                    },
+                    None => {},
+                }
+                Change::Add(arch)
+            },
+            Change::Teleporter(index, teleporter) => {
+                let old = match teleporter {
+                    Some(t) => self.teleporter.insert(index, t),
+                    None => self.teleporter.remove(&index),
+                };
+                Change::Teleporter(index, old)
+            },
+        }
+    }
+
+    ///Builds a report of everything on the tile dx,dy away from the player. Used by inspect to
+    ///debug maps from in game.
+    pub fn inspect(&self, token: mio::Token, dx: i32, dy: i32) -> Result<TileReport, String> {
+        let index = self.edit_target(token, EditTarget::Offset(dx, dy))?;
+        let (x, y) = (index % self.width, index / self.width);
+        let ref tile = self.tiles[index as usize];
+        let terrain = ScreenTerrain::new((*tile.art).clone());
+        let mut objects = vec![];
+        let mut object_blocked = false;
+        for object in self.objects.iter() {
+            if footprint(&**object, self.width).contains(&index) {
+                objects.push((object.get_type().name().to_string(), object.get_path(), object.get_tile(),
+                              object.get_properties().to_string()));
+            }
+            if object.does_block_index(index) {
+                object_blocked = true;
+            }
+        }
+        Ok(TileReport {
+            x: x,
+            y: y,
+            terrain: (*tile.tile).clone(),
+            converted: terrain.tile.clone(),
+            priority: terrain.priority_level(),
+            blocked: tile.blocked,
+            object_blocked: object_blocked,
+            objects: objects,
+            teleporter: self.teleporter.get(&index).map(|t| t.destination()),
+        })
     }
 
     /// Removes a player from the map. 
     pub fn remove_player(&mut self, token: mio::Token) {
         println!("Remove Player");
+        self.roles.remove(&token.as_usize());
         match Arc::get_mut(&mut self.objects) {
             Some(objects) => {
                 let len = objects.len();
@@ -662,22 +1442,158 @@ impl GameMap {
 #[derive(Clone)]
 pub struct MapTile{
     //No position, because position is determined by the position in vector
-    pub tile: String,
+    //Shared with every other tile using the same terrain
+    pub tile: Arc<String>,
+    //The art sent to the client, after aliases. Worked out when the map is read or the aliases are
+    //re-read, so screens don't look it up every tick.
+    pub art: Arc<String>,
     pub blocked: bool,
 }
 
 impl MapTile {
-    fn new(tile: String) -> MapTile {
+    fn new(tile: Arc<String>, art: Arc<String>) -> MapTile {
         MapTile{
             tile: tile,
+            art: art,
             blocked: false,
         }
     }
 }
 
+///One piece of an edit. Applying a change gives back the change that undoes it.
+#[derive(Clone)]
+enum Change {
+    ///Sets the tile at the index
+    Terrain(u32, MapTile),
+    Add(Arch),
+    Remove(Arch),
+    ///Sets or clears the teleporter at the index
+    Teleporter(u32, Option<Teleporter>),
+}
+
+///Enough about an object to make it again. Objects can't be cloned, so undo keeps these.
+#[derive(Clone)]
+struct Arch {
+    path: String,
+    index: u32,
+    size: (u32, u32),
+    properties: Properties,
+}
+
+impl Arch {
+    fn from_object(object: &Controllable) -> Arch {
+        Arch {
+            path: object.get_path(),
+            index: object.get_location(),
+            size: object.get_size(),
+            properties: object.get_properties().clone(),
+        }
+    }
+}
+
+///The properties read so far for an arch or bean that hasn't closed yet
+struct PropertyFrame {
+    //Name of the bean, empty for an arch
+    name: String,
+    properties: Properties,
+    //Objects the arch created, which get the properties when it closes
+    objects: Range<usize>,
+}
+
+impl PropertyFrame {
+    fn new(name: String, objects: Range<usize>) -> PropertyFrame {
+        PropertyFrame {
+            name: name,
+            properties: Properties::new(),
+            objects: objects,
+        }
+    }
+}
+
+///Gets the value of an attribute, or an empty string if it isn't there
+fn attribute(attributes: &[OwnedAttribute], name: &str) -> String {
+    match attributes.iter().find(|a| a.name.local_name == name) {
+        Some(attr) => attr.value.clone(),
+        None => String::new(),
+    }
+}
+
+///Reads the x or y a teleporter sends players to. Missing is 0, same as before properties were
+///kept.
+fn teleporter_coordinate(properties: &Properties, name: &str, position: TextPosition) -> Result<u32, MapError> {
+    let value = properties.get_int(name).unwrap_or(0);
+    if value < 0 || value > u32::max_value() as i64 {
+        Err(MapError::Invalid {
+            element: "arch".to_string(),
+            message: format!("teleporter {} of {} is off the map", name, value),
+            position: position,
+        })
+    } else {
+        Ok(value as u32)
+    }
+}
+
+///Hands out one shared copy of each terrain path & its art while a map is parsed, so a 1000x1000
+///map of grass doesn't hold a million copies of "terrain/grass", or look up its alias a million
+///times.
+struct TerrainPaths {
+    paths: HashMap<String, MapTile>,
+}
+
+impl TerrainPaths {
+    fn new() -> TerrainPaths {
+        TerrainPaths {
+            paths: HashMap::new(),
+        }
+    }
+
+    ///Gets an open tile with the shared copy of a path, resolving its art the first time it is seen
+    fn tile(&mut self, path: &str) -> MapTile {
+        match self.paths.get(path) {
+            Some(tile) => {
+                return tile.clone();
+            },
+            None => {},
+        }
+        let tile = MapTile::new(Arc::new(path.to_string()), Arc::new(aliases::resolve(path)));
+        self.paths.insert(path.to_string(), tile.clone());
+        tile
+    }
+}
+
+///What to do with a player standing on a teleporter that goes to another map
+pub enum Teleport {
+    ///Leave for the map, at the x,y if there is one
+    Join(mio::Token, String, Option<(u32, u32)>),
+    ///Ask the player where to go. The map is the teleporter's, as a suggestion.
+    Ask(mio::Token, String),
+    ///The player stepped off the teleporter that asked, so their answer no longer counts
+    Cancel(mio::Token),
+}
+
+///Everything the server knows about a single tile. The tile ids are looked up by the connection,
+///since that is where the mappings live.
+pub struct TileReport {
+    pub x: u32,
+    pub y: u32,
+    //Path from the map file
+    pub terrain: String,
+    //Path actually sent to the client
+    pub converted: String,
+    pub priority: u32,
+    //Terrain is blocked
+    pub blocked: bool,
+    //Something on the tile blocks it
+    pub object_blocked: bool,
+    //Type, path from the map file, art sent & properties of each object on the tile
+    pub objects: Vec<(String, String, String, String)>,
+    pub teleporter: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct ScreenObject {
     pub tile: String,
+    //Signed offsets from the first visible tile, sent as raw bytes
     pub x: u8,
     pub y: u8,
 }
@@ -686,7 +1602,7 @@ impl ScreenObject {
     ///Creates a new screen object with the given tile, x and y
     fn new(tile: String, x: u8, y:u8) -> ScreenObject{
         ScreenObject{
-            tile: MapScreen::convert_terrain(tile),
+            tile: tile,
             x: x,
             y: y,
         }
@@ -700,33 +1616,36 @@ pub struct ScreenTerrain {
 }
 
 impl ScreenTerrain {
-    ///Creates a new tile struct
+    ///Creates a new tile struct. The art has already been through the aliases.
     fn new(tile: String) -> ScreenTerrain {
         ScreenTerrain {
-            tile: MapScreen::convert_terrain(tile),
+            tile: tile,
         }
     }
 
     ///To get fancy borders the tile has to have a priority assigned. I have
     ///hardcoded some values here.
-    pub fn get_priority(&self) -> u32 {
-        let priority;
+    pub fn priority_level(&self) -> u32 {
         if self.tile.contains("grass") {
-            priority = (2 as u32) << 17; 
+            2
         } else if self.tile.contains("shallow") {
-            priority = (1 as u32) << 17;
+            1
         } else if self.tile.contains("water") {
-            priority = (3 as u32) << 17;
+            3
         } else if self.tile.contains("trees") 
             || self.tile.contains("forest") 
             || self.tile.contains("wall") {
-            priority = (4 as u32) << 17;
+            4
         } else if self.tile.contains("lava") {
-            priority = (10000 as u32) << 17;
+            10000
         } else {
-            priority = (0 as u32) << 17;
+            0
         }
-        priority | (7 as u32) << 29
+    }
+
+    ///The priority as the client wants it, shifted up past the tile number
+    pub fn get_priority(&self) -> u32 {
+        self.priority_level() << 17 | (7 as u32) << 29
     }
 }
 
@@ -746,16 +1665,17 @@ pub struct MapScreen {
 impl MapScreen {
     ///generates a new MapScreen based on the map and a given x & y. This will grab the 15x15
     ///matrix centered on the given x and y. Any spaces beyond the boundaries of the map is replaced
-    ///with "empty" tiles
-    pub fn new(map: &GameMap, x: u32, y: u32, size_x: u8, size_y: u8) -> MapScreen {
+    ///with the map's out of bounds terrain. Hidden objects are skipped, unless the viewer's role can
+    ///see them or it is the viewer's own player.
+    pub fn new(map: &GameMap, x: u32, y: u32, size_x: u8, size_y: u8, viewer: mio::Token, role: Role) -> MapScreen {
         let startx: isize = x as isize -(size_x as isize /2 as isize + 1);
         let starty: isize = y as isize -(size_y as isize /2 as isize + 1);
         let mut ter = Vec::with_capacity((size_x+2) as usize *(size_y+2) as usize);
         let mut obj = vec![];
         //If coords are valid we will actually draw something
-        let empty = ScreenTerrain::new("terrain/empty".to_string());
+        let empty = ScreenTerrain::new((*map.oob_art).clone());
         //creates array of tiles
-        if map.width as u32 > x && map.height as u32 > y {
+        if map.width > x && map.height > y {
             for i in 0..(size_x as isize+2) {
                 for j in 0..(size_y as isize+2) {
                     if startx+i >= 0 && startx+(i as isize) < (map.width as isize) && starty+(j as isize) >= 0 && starty+(j as isize) < (map.height as isize) {
@@ -765,7 +1685,7 @@ impl MapScreen {
                         //clone the map tile
                         let tile = tiles[index as usize].clone();
                         //Add the terrain from the tile
-                        ter.push(ScreenTerrain::new(tile.tile.clone()));
+                        ter.push(ScreenTerrain::new((*tile.art).clone()));
                     } else {
                         ter.push(empty.clone());
                     }
@@ -777,12 +1697,26 @@ impl MapScreen {
         for i in 0..objects.len() {
             let ref object = objects[i];
             let index = object.get_location();
-            let object_x = index % map.width as u32;
-            let object_y = index / map.width as u32;
-            if object_x as isize >= startx && object_x < map.width as u32 && object_y as isize >= starty
-                && object_y < map.height as u32  && object.is_visible(map) {
-                    //Extra -1 is to account for the extra tile off screen.
-                obj.push(ScreenObject::new(object.get_tile(), (object_x as isize - startx -1) as u8 , (object_y as isize - starty -1) as u8));
+            let object_x = index % map.width;
+            let object_y = index / map.width;
+            let (size_w, size_h) = object.get_size();
+            //Anything past the edge of the screen is skipped, since on a big map its screen
+            //position wouldn't fit in the u8. Big objects are drawn once from their top left,
+            //so they are kept as long as any part of them is on screen.
+            let on_screen = (object_x as isize) < startx + size_x as isize + 2
+                && (object_y as isize) < starty + size_y as isize + 2
+                && (object_x + size_w) as isize > startx
+                && (object_y + size_h) as isize > starty;
+            //Wyvern art hangs right & down from its top left tile, the same corner the editor's loc
+            //gives, so a big object is sent once from that corner. Extra -1 is to account for the
+            //extra tile off screen. That already made the border column -1, so the client reads
+            //these as signed bytes, and a corner above or left of the screen goes out negative.
+            let screen_x = object_x as isize - startx - 1;
+            let screen_y = object_y as isize - starty - 1;
+            let fits = screen_x >= i8::MIN as isize && screen_x <= i8::MAX as isize
+                && screen_y >= i8::MIN as isize && screen_y <= i8::MAX as isize;
+            if on_screen && fits && (object.get_token() == Some(viewer) || object.is_visible(map, role)) {
+                obj.push(ScreenObject::new(object.get_tile(), screen_x as i8 as u8, screen_y as i8 as u8));
             }
         }
         MapScreen {
@@ -793,18 +1727,273 @@ impl MapScreen {
         }
     }
 
-    ///These are the terrain tiles I have found where the terrain path from xml
-    ///did not match the image path.
-    fn convert_terrain(tile: String) -> String {
-        if tile == "scenery/sign1" {
-            "indoor/sign1".to_string()
-        } else if tile == "scenery/caveE" {
-            "scenery/cave2".to_string()
-        } else if tile == "terrain/dark_earth" {
-            "terrain/mud".to_string()
-        } else {
-            tile
+}
+
+///A map for tests, from GameMap::test. The header only has what was set, so a bare one is
+///just the size & terrain. The arches start on line 5.
+#[cfg(test)]
+pub struct TestMap {
+    width: u32,
+    height: u32,
+    terrain: String,
+    oob_terrain: Option<String>,
+    start: Option<(u32, u32)>,
+    body: String,
+}
+
+#[cfg(test)]
+impl TestMap {
+    ///Sets the header terrain
+    pub fn terrain(mut self, path: &str) -> TestMap {
+        self.terrain = path.to_string();
+        self
+    }
+
+    ///Sets the oob-terrain. Tiles left as the header terrain are blocked if it is the same.
+    pub fn oob(mut self, path: &str) -> TestMap {
+        self.oob_terrain = Some(path.to_string());
+        self
+    }
+
+    pub fn start(mut self, x: u32, y: u32) -> TestMap {
+        self.start = Some((x, y));
+        self
+    }
+
+    ///Adds arches, written as they would be in the file
+    pub fn arch(mut self, xml: &str) -> TestMap {
+        self.body.push_str(xml);
+        self
+    }
+
+    ///Paints one tile
+    pub fn paint(self, x: u32, y: u32, path: &str) -> TestMap {
+        self.arch(&format!("<arch loc=\"{} {}\" path=\"{}\"/>\n", x, y, path))
+    }
+
+    pub fn xml(&self) -> String {
+        let mut header = String::new();
+        match self.oob_terrain {
+            Some(ref oob) => header.push_str(&format!("  <arch name=\"oob-terrain\" path=\"{}\"/>\n", oob)),
+            None => {},
+        }
+        match self.start {
+            Some((x, y)) => header.push_str(&format!("  <int name=\"startX\" value=\"{}\"/>\n  <int name=\"startY\" value=\"{}\"/>\n", x, y)),
+            None => {},
         }
+        format!("<map>\n<header class=\"DenseMap\" width=\"{}\" height=\"{}\">\n{}  <arch name=\"terrain\" path=\"{}\"/>\n</header>\n{}</map>\n",
+                self.width, self.height, header, self.terrain, self.body)
     }
 
+    ///Parses it as maps/test.map
+    pub fn build(&self) -> GameMap {
+        GameMap::from_xml("maps/test.map", &self.xml()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use game::gamemap::{GameMap, MapScreen, Teleport};
+    use game::characters::item::Item;
+    use game::characters::Ghost;
+    use conn::command::{Command, EditTarget};
+    use conn::roles::Role;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    extern crate mio;
+
+    ///A 20x20 map with a sign at 12,10 & a player at 10,10
+    fn signpost() -> GameMap {
+        let mut map = GameMap::test(20, 20)
+            .arch("<arch loc=\"12 10\" path=\"scenery/sign1\">\n  <string name=\"message\" value=\"Keep out\"/>\n</arch>\n")
+            .build();
+        map.add_player(mio::Token(1), "paladin".to_string(), Some((10, 10)), Role::Player, Ghost::Off);
+        map
+    }
+
+    #[test]
+    fn looks_at_the_clicked_tile() {
+        let mut map = signpost();
+        assert!(map.look(mio::Token(1), Some(EditTarget::Clicked), false).is_err());
+        //The player is drawn at 6,6 on the screen, so this is two tiles right of them
+        map.push_command(mio::Token(1), Command::Mouse(8, 6));
+        let seen = map.look(mio::Token(1), Some(EditTarget::Clicked), false).unwrap();
+        assert_eq!(seen, "Keep out");
+        assert_eq!(map.look(mio::Token(1), Some(EditTarget::Clicked), true),
+                   Err("You need to be next to it to read it".to_string()));
+        assert_eq!(map.look(mio::Token(1), Some(EditTarget::Offset(2, 0)), false), Ok(seen));
+    }
+
+    #[test]
+    fn takes_back_the_question_when_stepping_off_an_ask_map_teleporter() {
+        let mut map = GameMap::test(20, 20)
+            .arch("<arch loc=\"5 5\" path=\"special/teleporter\">\n  <string name=\"map\" value=\"cave\"/>\n  \
+                   <boolean name=\"ask-map\" value=\"true\"/>\n</arch>\n")
+            .build();
+        map.add_player(mio::Token(1), "paladin".to_string(), Some((5, 5)), Role::Player, Ghost::Off);
+        match &map.do_teleports()[..] {
+            [Teleport::Ask(token, ref suggestion)] => {
+                assert_eq!((*token, &suggestion[..]), (mio::Token(1), "cave"));
+            },
+            _ => panic!("should have asked"),
+        }
+        //Standing still doesn't ask again
+        assert_eq!(map.do_teleports().len(), 0);
+        let player = map.find_player_with_token(mio::Token(1)).unwrap();
+        Arc::get_mut(&mut map.objects).unwrap()[player].set_location(5 * 20 + 6);
+        match &map.do_teleports()[..] {
+            [Teleport::Cancel(token)] => assert_eq!(*token, mio::Token(1)),
+            _ => panic!("should have taken the question back"),
+        }
+        assert_eq!(map.do_teleports().len(), 0);
+    }
+
+    ///What is at x,y on a 20 wide map: the terrain & the paths of the objects
+    fn tile_at(map: &GameMap, x: u32, y: u32) -> (String, Vec<String>) {
+        let index = y * 20 + x;
+        let objects = map.objects.iter().filter(|o| o.get_token().is_none() && o.get_location() == index)
+            .map(|o| o.get_path()).collect();
+        ((*map.tiles[index as usize].tile).clone(), objects)
+    }
+
+    #[test]
+    fn undoes_and_redoes_edits() {
+        let mut map = GameMap::test(20, 20).build();
+        map.add_player(mio::Token(1), "builder".to_string(), Some((10, 10)), Role::Admin, Ghost::Off);
+        let builder = mio::Token(1);
+        map.edit(builder, Command::Paint("terrain/water".to_string(), EditTarget::Offset(1, 0))).unwrap();
+        map.edit(builder, Command::Place("scenery/sign1".to_string(), EditTarget::Offset(1, 0))).unwrap();
+        assert_eq!(tile_at(&map, 11, 10), ("terrain/water".to_string(), vec!["scenery/sign1".to_string()]));
+        map.undo().unwrap();
+        assert_eq!(tile_at(&map, 11, 10), ("terrain/water".to_string(), vec![]));
+        map.undo().unwrap();
+        assert_eq!(tile_at(&map, 11, 10), ("terrain/grass".to_string(), vec![]));
+        assert_eq!(map.undo(), Err("There is nothing to undo".to_string()));
+        map.redo().unwrap();
+        map.redo().unwrap();
+        assert_eq!(tile_at(&map, 11, 10), ("terrain/water".to_string(), vec!["scenery/sign1".to_string()]));
+        assert_eq!(map.redo(), Err("There is nothing to redo".to_string()));
+        //A new edit drops what could have been redone
+        map.undo().unwrap();
+        map.edit(builder, Command::Paint("terrain/grass".to_string(), EditTarget::Offset(1, 0))).unwrap();
+        assert_eq!(map.redo(), Err("There is nothing to redo".to_string()));
+    }
+
+    #[test]
+    fn busy_maps_make_no_changes() {
+        let mut map = GameMap::test(20, 20).build();
+        map.add_player(mio::Token(1), "builder".to_string(), Some((10, 10)), Role::Admin, Ghost::Off);
+        let builder = mio::Token(1);
+        map.edit(builder, Command::Place("scenery/sign1".to_string(), EditTarget::Offset(0, 1))).unwrap();
+        //Someone else holding the objects, like a screen being sent
+        let held = map.objects.clone();
+        let busy = Err("The map is busy, try again".to_string());
+        assert_eq!(map.edit(builder, Command::Paint("terrain/water".to_string(), EditTarget::Offset(1, 0))), busy);
+        assert_eq!(tile_at(&map, 11, 10), ("terrain/grass".to_string(), vec![]));
+        assert_eq!(map.undo(), busy);
+        assert_eq!(tile_at(&map, 10, 11), ("terrain/grass".to_string(), vec!["scenery/sign1".to_string()]));
+        drop(held);
+        //The undo wasn't lost
+        map.undo().unwrap();
+        assert_eq!(tile_at(&map, 10, 11), ("terrain/grass".to_string(), vec![]));
+    }
+
+    #[test]
+    fn refreshes_art_without_losing_edits() {
+        let mut map = GameMap::test(20, 20).build();
+        map.add_player(mio::Token(1), "builder".to_string(), Some((10, 10)), Role::Admin, Ghost::Off);
+        map.edit(mio::Token(1), Command::Paint("terrain/water".to_string(), EditTarget::Offset(1, 0))).unwrap();
+        //As if aliases.txt had been different when the well was made
+        let mut well = Item::new("structures/well".to_string(), 5 * 20 + 5);
+        well.tile = "statics/stale_well".to_string();
+        Arc::get_mut(&mut map.objects).unwrap().push(Box::new(well));
+        map.refresh_art().unwrap();
+        let well = map.objects.iter().find(|o| o.get_path() == "structures/well").unwrap();
+        assert_eq!(well.get_tile(), "statics/well");
+        assert_eq!(tile_at(&map, 11, 10).0, "terrain/water");
+        //Terrain is resolved again too
+        Arc::make_mut(&mut map.tiles)[0].art = Arc::new("terrain/stale".to_string());
+        map.edit(mio::Token(1), Command::Paint("terrain/dark_earth".to_string(), EditTarget::Offset(0, 1))).unwrap();
+        map.refresh_art().unwrap();
+        assert_eq!(*map.tiles[0].art, "terrain/grass");
+        assert_eq!(*map.tiles[11 * 20 + 10].art, "terrain/mud");
+        //Nothing can change while a screen is holding the objects
+        let held = map.objects.clone();
+        assert!(map.refresh_art().is_err());
+        drop(held);
+    }
+
+    #[test]
+    fn refuses_doors_far_away() {
+        let mut map = GameMap::test(20, 20).build();
+        map.add_player(mio::Token(1), "paladin".to_string(), Some((10, 10)), Role::Player, Ghost::Off);
+        let far = Err("You need to be next to the door".to_string());
+        assert_eq!(map.open_door(mio::Token(1), Some((i32::min_value(), 0)), true), far);
+        assert_eq!(map.open_door(mio::Token(1), Some((0, i32::max_value())), false), far);
+        assert_eq!(map.open_door(mio::Token(1), Some((1, 0)), true), Err("There is no door there".to_string()));
+    }
+
+    #[test]
+    fn inspects_off_the_map_without_overflowing() {
+        let map = signpost();
+        assert_eq!(map.inspect(mio::Token(1), i32::max_value(), 0).err(), Some("2147483657 10 is off the map".to_string()));
+        assert_eq!(map.inspect(mio::Token(1), 0, i32::min_value()).err(), Some("10 -2147483638 is off the map".to_string()));
+        let report = map.inspect(mio::Token(1), 2, 0).unwrap();
+        assert_eq!((report.x, report.y, report.objects.len()), (12, 10, 1));
+    }
+
+    #[test]
+    fn reloads_onto_the_start_point_when_nothing_is_open() {
+        let mut map = GameMap::test(20, 20).build();
+        map.add_player(mio::Token(1), "paladin".to_string(), Some((10, 10)), Role::Player, Ghost::Off);
+        //Every tile is left as the oob terrain, so nothing is open
+        let path = env::temp_dir().join("moba_reload_blocked.map");
+        let xml = GameMap::test(5, 5).oob("terrain/empty").terrain("terrain/empty").start(3, 9).xml();
+        File::create(&path).unwrap().write_all(xml.as_bytes()).unwrap();
+        let stuck = map.reload(path.to_str().unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(stuck, vec![mio::Token(1)]);
+        //The start is past the bottom, so it is pulled back onto the map
+        assert_eq!(map.get_position(mio::Token(1)), Some((3, 4)));
+    }
+
+    ///Puts a 3x3 object with its top left at x,y
+    fn building(map: &mut GameMap, x: u32, y: u32) {
+        let width = map.width;
+        let item = Item::sized("structures/city2".to_string(), y * width + x, (3, 3), width);
+        Arc::get_mut(&mut map.objects).unwrap().push(Box::new(item));
+    }
+
+    #[test]
+    fn anchors_big_objects_top_left() {
+        let mut map = GameMap::test(20, 20).build();
+        building(&mut map, 5, 5);
+        //The corner & everything right & down from it is covered, nothing up or left
+        assert!(!map.is_open(5 * 20 + 5));
+        assert!(!map.is_open(7 * 20 + 7));
+        assert!(map.is_open(4 * 20 + 5));
+        assert!(map.is_open(5 * 20 + 4));
+        assert!(map.is_open(8 * 20 + 5));
+        //Sent once, from the corner. A 13 wide screen around 10,10 starts at 3,3 & shows from 4,4.
+        let screen = MapScreen::new(&map, 10, 10, 13, 13, mio::Token(0), Role::Player);
+        assert_eq!(screen.objects.len(), 1);
+        assert_eq!((screen.objects[0].x, screen.objects[0].y), (1, 1));
+    }
+
+    #[test]
+    fn sends_signed_offsets_for_corners_off_screen() {
+        let mut map = GameMap::test(20, 20).build();
+        building(&mut map, 2, 1);
+        //Its corner is left of & above the screen, but the rest hangs onto it
+        let screen = MapScreen::new(&map, 10, 10, 13, 13, mio::Token(0), Role::Player);
+        assert_eq!(screen.objects.len(), 1);
+        assert_eq!((screen.objects[0].x as i8, screen.objects[0].y as i8), (-2, -3));
+        //Once all of it is past the edge it isn't sent
+        let screen = MapScreen::new(&map, 12, 10, 13, 13, mio::Token(0), Role::Player);
+        assert_eq!(screen.objects.len(), 0);
+    }
 }
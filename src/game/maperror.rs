@@ -0,0 +1,194 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module has the errors for loading maps. Everything that can go wrong while parsing says
+/// which element & attribute it was, the value, and where it is in the file, so a typo in a hand
+/// edited map can be found without digging.
+
+extern crate xml;
+
+use self::xml::common::TextPosition;
+
+use std::fmt;
+
+/// Everything that can go wrong loading a map
+#[derive(Clone, Debug)]
+pub enum MapError {
+    ///The map file couldn't be opened
+    NotFound(String),
+    ///The xml itself is broken
+    Xml {
+        message: String,
+        position: TextPosition,
+    },
+    ///An attribute has a value that couldn't be read
+    BadValue {
+        element: String,
+        attribute: String,
+        value: String,
+        position: TextPosition,
+    },
+    ///A loc reaches past the edge of the map
+    OutOfBounds {
+        element: String,
+        value: String,
+        width: u32,
+        height: u32,
+        position: TextPosition,
+    },
+    ///Something is wrong with an element as a whole
+    Invalid {
+        element: String,
+        message: String,
+        position: TextPosition,
+    },
+    ///A map from Tiled couldn't be turned into one of ours
+    Tiled(String),
+}
+
+impl MapError {
+    ///Shorthand for the most common error
+    pub fn bad_value(element: &str, attribute: &str, value: &str, position: TextPosition) -> MapError {
+        MapError::BadValue {
+            element: element.to_string(),
+            attribute: attribute.to_string(),
+            value: value.to_string(),
+            position: position,
+        }
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapError::NotFound(ref path) => {
+                write!(f, "Map {} not found", path)
+            },
+            MapError::Xml { ref message, position } => {
+                write!(f, "line {}: broken xml, {}", position, message)
+            },
+            MapError::BadValue { ref element, ref attribute, ref value, position } => {
+                write!(f, "line {}: <{}> has a bad {}=\"{}\"", position, element, attribute, value)
+            },
+            MapError::OutOfBounds { ref element, ref value, width, height, position } => {
+                write!(f, "line {}: <{}> loc=\"{}\" is outside the {}x{} map", position, element, value, width, height)
+            },
+            MapError::Invalid { ref element, ref message, position } => {
+                write!(f, "line {}: <{}> {}", position, element, message)
+            },
+            MapError::Tiled(ref message) => {
+                write!(f, "Tiled map: {}", message)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use game::gamemap::GameMap;
+    use game::maperror::MapError;
+
+    ///Parses a map that should fail & gives back the error
+    fn error(xml: &str) -> MapError {
+        match GameMap::from_xml("maps/broken.map", xml) {
+            Ok(_) => panic!("parsed a broken map:\n{}", xml),
+            Err(e) => e,
+        }
+    }
+
+    ///A 4x3 map with the given arches after the header
+    fn map(body: &str) -> String {
+        GameMap::test(4, 3).arch(body).xml()
+    }
+
+    #[test]
+    fn reports_bad_loc() {
+        let e = error(&map("<arch loc=\"1 1\" path=\"terrain/water\"/>\n<arch loc=\"9 1 zz 1\" path=\"terrain/water\"/>\n"));
+        match e {
+            MapError::BadValue { ref element, ref attribute, ref value, position } => {
+                assert_eq!((&element[..], &attribute[..], &value[..]), ("arch", "loc", "9 1 zz 1"));
+                assert_eq!((position.row, position.column), (5, 0));
+            },
+            ref other => panic!("wrong error {:?}", other),
+        }
+        assert_eq!(e.to_string(), "line 6:1: <arch> has a bad loc=\"9 1 zz 1\"");
+    }
+
+    #[test]
+    fn reports_out_of_bounds() {
+        let e = error(&map("<arch loc=\"2 1 3 1\" path=\"terrain/water\"/>\n"));
+        match e {
+            MapError::OutOfBounds { ref value, width, height, .. } => {
+                assert_eq!((&value[..], width, height), ("2 1 3 1", 4, 3));
+            },
+            ref other => panic!("wrong error {:?}", other),
+        }
+        assert_eq!(e.to_string(), "line 5:1: <arch> loc=\"2 1 3 1\" is outside the 4x3 map");
+    }
+
+    #[test]
+    fn reports_bad_int() {
+        let e = error(&map("<arch loc=\"1 1\" path=\"scenery/willow\">\n  <int name=\"hp\" value=\"lots\"/>\n</arch>\n"));
+        match e {
+            MapError::BadValue { ref element, ref attribute, ref value, .. } => {
+                assert_eq!((&element[..], &attribute[..], &value[..]), ("int", "value", "lots"));
+            },
+            ref other => panic!("wrong error {:?}", other),
+        }
+        assert_eq!(e.to_string(), "line 6:3: <int> has a bad value=\"lots\"");
+    }
+
+    #[test]
+    fn reports_zero_width() {
+        let e = error(&GameMap::test(0, 3).xml());
+        match e {
+            MapError::Invalid { ref element, .. } => assert_eq!(element, "header"),
+            ref other => panic!("wrong error {:?}", other),
+        }
+        assert_eq!(e.to_string(), "line 2:1: <header> needs a width and height above 0");
+    }
+
+    #[test]
+    fn reports_maps_too_big() {
+        let e = error(&GameMap::test(60000, 60000).xml());
+        match e {
+            MapError::Invalid { ref element, .. } => assert_eq!(element, "header"),
+            ref other => panic!("wrong error {:?}", other),
+        }
+        assert_eq!(e.to_string(), "line 2:1: <header> 60000x60000 is too big, maps can have up to 4194304 tiles");
+    }
+
+    #[test]
+    fn reports_arch_before_terrain() {
+        let xml = "<map>\n<header class=\"DenseMap\" width=\"4\" height=\"3\">\n</header>\n\
+                   <arch loc=\"1 1\" path=\"terrain/water\"/>\n</map>\n";
+        let e = error(xml);
+        match e {
+            MapError::Invalid { ref element, .. } => assert_eq!(element, "arch"),
+            ref other => panic!("wrong error {:?}", other),
+        }
+        assert_eq!(e.to_string(), "line 4:1: <arch> terrain/water was placed before the header terrain");
+    }
+
+    #[test]
+    fn reports_broken_xml() {
+        let e = error(&map("<arch loc=\"1 1\" path=\"terrain/water\">\n"));
+        match e {
+            MapError::Xml { .. } => {},
+            ref other => panic!("wrong error {:?}", other),
+        }
+        assert!(e.to_string().starts_with("line "), "{}", e);
+    }
+}
@@ -0,0 +1,95 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module holds the <header> of a map file. The editor puts the size of the map on the
+/// header element, the default & out of bounds terrain in arches, and everything else
+/// (name, danger-level, editor-version, pk, startX...) in properties.
+
+use game::properties::Properties;
+
+use std::fmt;
+
+///Terrain used off the edge of the map when the header doesn't name one
+pub const DEFAULT_OOB_TERRAIN: &'static str = "terrain/empty";
+
+///Everything in a map's header
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapHeader {
+    pub width: u32,
+    pub height: u32,
+    //The terrain every tile starts as
+    pub terrain: String,
+    //What is drawn past the edge of the map. Tiles of this terrain are blocked.
+    pub oob_terrain: String,
+    //Every other property, in the order they were in the file
+    pub properties: Properties,
+}
+
+impl MapHeader {
+    pub fn new() -> MapHeader {
+        MapHeader {
+            width: 0,
+            height: 0,
+            terrain: String::new(),
+            oob_terrain: String::new(),
+            properties: Properties::new(),
+        }
+    }
+
+    ///The name the designer gave the map, if they gave one
+    pub fn name(&self) -> Option<&str> {
+        self.properties.get_string("name")
+    }
+
+    pub fn danger_level(&self) -> Option<i64> {
+        self.properties.get_int("danger-level")
+    }
+
+    pub fn editor_version(&self) -> Option<&str> {
+        self.properties.get_string("editor-version")
+    }
+
+    ///The out of bounds terrain, or terrain/empty for maps that don't set one
+    pub fn oob_terrain(&self) -> &str {
+        if self.oob_terrain.len() > 0 {
+            &self.oob_terrain
+        } else {
+            DEFAULT_OOB_TERRAIN
+        }
+    }
+}
+
+///Writes the header a line at a time, for the mapinfo command
+impl fmt::Display for MapHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Name: {}\n", self.name().unwrap_or("(none)"))?;
+        write!(f, "Size: {}x{}\n", self.width, self.height)?;
+        match self.danger_level() {
+            Some(danger) => write!(f, "Danger level: {}\n", danger)?,
+            None => write!(f, "Danger level: (none)\n")?,
+        }
+        write!(f, "Editor version: {}\n", self.editor_version().unwrap_or("(none)"))?;
+        write!(f, "Terrain: {}\n", self.terrain)?;
+        write!(f, "Out of bounds: {}", self.oob_terrain())?;
+        let others: Vec<String> = self.properties.iter()
+            .filter(|&&(ref name, _)| name != "name" && name != "danger-level" && name != "editor-version")
+            .map(|&(ref name, ref value)| format!("{}={}", name, value))
+            .collect();
+        if others.len() > 0 {
+            write!(f, "\nOther: {}", others.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module writes a GameMap back out as XML the map editor can open. It is the parser in
+/// reverse. Terrain is written as loc rectangles, and objects & teleporters keep the paths and
+/// properties they were read with, so the editor sees what it saved.
+
+use game::gamemap::GameMap;
+use game::properties::{Properties, Property};
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+use xml::escape::escape_str_attribute;
+
+///Class the editor puts on the header of every map
+const HEADER_CLASS: &'static str = "DenseMap";
+
+impl GameMap {
+    ///Writes the whole map as editor XML
+    pub fn to_xml(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<map>\n");
+        self.write_header(&mut out);
+        self.write_terrain(&mut out);
+        self.write_objects(&mut out);
+        out.push_str("</map>\n");
+        out
+    }
+
+    ///Saves the map to a file. It is written next to the file first & then moved over it, so
+    ///nothing reading the map ever sees half of one.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let temp = format!("{}.tmp", path);
+        {
+            let mut file = File::create(&temp)?;
+            file.write_all(self.to_xml().as_bytes())?;
+        }
+        fs::rename(&temp, path)
+    }
+
+    fn write_header(&self, out: &mut String) {
+        let ref header = self.header;
+        let class = header.properties.class().unwrap_or(HEADER_CLASS);
+        out.push_str(&format!("<header class=\"{}\" width=\"{}\" height=\"{}\">\n",
+                              escape_str_attribute(class), self.width, self.height));
+        write_properties(out, &header.properties, 1);
+        if header.oob_terrain.len() > 0 {
+            out.push_str(&format!("  <arch name=\"oob-terrain\" path=\"{}\"/>\n",
+                                  escape_str_attribute(&header.oob_terrain)));
+        }
+        out.push_str(&format!("  <arch name=\"terrain\" path=\"{}\"/>\n", escape_str_attribute(&header.terrain)));
+        out.push_str("</header>\n");
+    }
+
+    ///Writes every tile that isn't the header's terrain. Tiles are gathered into rectangles, first
+    ///going right along the row, then down as far as the whole run matches.
+    fn write_terrain(&self, out: &mut String) {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let ref tiles = self.tiles;
+        //Tiles of the header terrain are only blocked when it is also the out of bounds terrain
+        let default_blocked = self.header.terrain == self.header.oob_terrain;
+        let needed: Vec<bool> = tiles.iter().map(|tile| {
+            *tile.tile != self.header.terrain || tile.blocked != default_blocked
+        }).collect();
+        let mut written = vec![false; tiles.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let index = y * width + x;
+                if !needed[index] || written[index] {
+                    continue;
+                }
+                let ref terrain = tiles[index].tile;
+                let same = |i: usize| needed[i] && !written[i] && tiles[i].tile == *terrain;
+                let mut w = 1;
+                while x + w < width && same(index + w) {
+                    w += 1;
+                }
+                let mut h = 1;
+                while y + h < height && (0..w).all(|dx| same((y + h) * width + x + dx)) {
+                    h += 1;
+                }
+                for dy in 0..h {
+                    for dx in 0..w {
+                        written[(y + dy) * width + x + dx] = true;
+                    }
+                }
+                out.push_str(&format!("<arch loc=\"{} {} {} {}\" path=\"{}\"/>\n",
+                                      x, y, w, h, escape_str_attribute(terrain)));
+            }
+        }
+    }
+
+    ///Writes the objects in the order they were read, then the teleporters from top left to
+    ///bottom right. Players aren't part of the map, so they are left out.
+    fn write_objects(&self, out: &mut String) {
+        for object in self.objects.iter() {
+            if object.get_token().is_some() {
+                continue;
+            }
+            let index = object.get_location();
+            let loc = match object.get_size() {
+                (1, 1) => format!("{} {}", index % self.width, index / self.width),
+                (w, h) => format!("{} {} {} {}", index % self.width, index / self.width, w, h),
+            };
+            write_arch(out, &loc, &object.get_path(), object.get_properties());
+        }
+        let mut teleporters: Vec<(&u32, _)> = self.teleporter.iter().collect();
+        teleporters.sort_by_key(|&(index, _)| *index);
+        for (index, teleporter) in teleporters {
+            let loc = format!("{} {}", index % self.width, index / self.width);
+            write_arch(out, &loc, "special/teleporter", &teleporter.map_properties());
+        }
+    }
+}
+
+///Writes an arch. Ones without properties are closed on the same line, like the editor does.
+pub fn write_arch(out: &mut String, loc: &str, path: &str, properties: &Properties) {
+    if properties.is_empty() {
+        out.push_str(&format!("<arch loc=\"{}\" path=\"{}\"/>\n", loc, escape_str_attribute(path)));
+    } else {
+        out.push_str(&format!("<arch loc=\"{}\" path=\"{}\">\n", loc, escape_str_attribute(path)));
+        write_properties(out, properties, 1);
+        out.push_str("</arch>\n");
+    }
+}
+
+///Writes properties as <string>, <int>, <boolean> & <bean> elements, indented two spaces a level
+pub fn write_properties(out: &mut String, properties: &Properties, depth: usize) {
+    let indent = "  ".repeat(depth);
+    for &(ref name, ref value) in properties.iter() {
+        let name = escape_str_attribute(name);
+        match *value {
+            Property::String(ref s) => {
+                out.push_str(&format!("{}<string name=\"{}\" value=\"{}\"/>\n", indent, name, escape_str_attribute(s)));
+            },
+            Property::Int(i) => {
+                out.push_str(&format!("{}<int name=\"{}\" value=\"{}\"/>\n", indent, name, i));
+            },
+            //The editor leaves the value off ticked boxes
+            Property::Boolean(true) => {
+                out.push_str(&format!("{}<boolean name=\"{}\"/>\n", indent, name));
+            },
+            Property::Boolean(false) => {
+                out.push_str(&format!("{}<boolean name=\"{}\" value=\"false\"/>\n", indent, name));
+            },
+            Property::Bean(ref bean) => {
+                match bean.class() {
+                    Some(class) => {
+                        out.push_str(&format!("{}<bean name=\"{}\" class=\"{}\">\n", indent, name,
+                                              escape_str_attribute(class)));
+                    },
+                    None => {
+                        out.push_str(&format!("{}<bean name=\"{}\">\n", indent, name));
+                    },
+                }
+                write_properties(out, bean, depth + 1);
+                out.push_str(&format!("{}</bean>\n", indent));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use game::gamemap::GameMap;
+
+    fn load(path: &str) -> GameMap {
+        match GameMap::new(path) {
+            Ok(map) => map,
+            Err(e) => panic!("{}: {}", path, e),
+        }
+    }
+
+    fn reparse(map: &GameMap) -> GameMap {
+        match GameMap::from_xml(&map.name, &map.to_xml()) {
+            Ok(map) => map,
+            Err(e) => panic!("written {} didn't parse: {}", map.name, e),
+        }
+    }
+
+    #[test]
+    fn keeps_header() {
+        let map = load("maps/main.map");
+        let copy = reparse(&map);
+        assert_eq!(copy.header, map.header);
+        assert_eq!(copy.start(), map.start());
+        assert_eq!(copy.header.name(), Some("demo"));
+    }
+
+    #[test]
+    fn keeps_terrain() {
+        let map = load("maps/main.map");
+        let copy = reparse(&map);
+        assert_eq!((copy.width, copy.height), (map.width, map.height));
+        for (i, (a, b)) in map.tiles.iter().zip(copy.tiles.iter()).enumerate() {
+            assert_eq!((&*a.tile, a.blocked), (&*b.tile, b.blocked), "tile {}", i);
+        }
+    }
+
+    #[test]
+    fn keeps_objects() {
+        let map = load("maps/main.map");
+        let copy = reparse(&map);
+        let describe = |map: &GameMap| -> Vec<(u32, String, String, (u32, u32), String)> {
+            let mut objects: Vec<_> = map.objects.iter().map(|o| {
+                (o.get_location(), o.get_path(), o.get_tile(), o.get_size(), o.get_properties().to_string())
+            }).collect();
+            objects.sort();
+            objects
+        };
+        assert_eq!(describe(&copy), describe(&map));
+    }
+
+    #[test]
+    fn keeps_teleporters() {
+        let map = load("maps/main.map");
+        let copy = reparse(&map);
+        assert!(map.teleporter.len() > 0);
+        assert_eq!(copy.teleporter.len(), map.teleporter.len());
+        for (index, teleporter) in map.teleporter.iter() {
+            let other = &copy.teleporter[index];
+            assert_eq!(other.map, teleporter.map);
+            assert_eq!(other.target(), teleporter.target());
+            assert_eq!(other.map_properties(), teleporter.map_properties());
+        }
+    }
+
+    #[test]
+    fn writes_stable_xml() {
+        let copy = reparse(&load("maps/main.map"));
+        assert_eq!(reparse(&copy).to_xml(), copy.to_xml());
+    }
+}
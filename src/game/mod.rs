@@ -17,9 +17,15 @@ limitations under the License.*/
 
 extern crate mio;
 
+pub mod aliases;
 pub mod gameloop;
 pub mod gamemap;
 pub mod characters;
+pub mod maperror;
+pub mod mapheader;
+pub mod mapwriter;
+pub mod properties;
+pub mod tiled;
 
 
 use glob::glob;
@@ -28,31 +34,60 @@ use std::collections::HashMap;
 use std::collections::hash_map::Entry::{Vacant, Occupied};
 use std::sync::Arc;
 use std::cell::RefCell;
+use std::fs;
 use std::fs::File;
 use std::io::BufReader;
 use std::sync::mpsc::Sender;
 use std::sync::Mutex;
+use std::time::SystemTime;
 
 use game::gameloop::GameLoop;
+use conn::store::safe_name;
 use conn::server::Msg;
 
+///Instances made with "join <map> private" start with this, followed by the owner's name
+pub const PRIVATE_PREFIX: &'static str = "private-";
+
+lazy_static! {
+    //Map files already found on disk, by name. Teleporters look up their map every tick, so the
+    //disk is only asked about names it hasn't found yet.
+    static ref MAP_FILES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
 
 ///This just has a hashmap of gameloops, and maps of game loops, and also holds all of the tile
 ///mappings
+///
+///Game loops are keyed by map file, optionally followed by #instance. Every instance of a map
+///gets its own loop, so testers in different instances never see each other.
 pub struct Game {
     game_loops: Mutex<HashMap<String, Arc<RefCell<GameLoop>>>>,
     pub mappings: HashMap<String, i16>,
     pub send: Sender<Msg>,
+    //Last modified time for each custom image, so changes can be picked up without a restart
+    image_times: HashMap<String, Option<SystemTime>>,
+    next_tile: i16,
 }
 
 impl Game {
     ///Creates a new game struct. Initilizes a new hashmap, and reads the tile map file.
     pub fn new(send: Sender<Msg>) -> Game {
-        Game {
+        let mappings = Game::create_mappings();
+        let mut next_tile = 0;
+        for tile in mappings.values() {
+            if *tile >= next_tile {
+                next_tile = *tile + 1;
+            }
+        }
+        let mut game = Game {
             game_loops: Mutex::new(HashMap::new()),
-            mappings: Game::create_mappings(),
+            mappings: mappings,
             send: send,
-        }
+            image_times: HashMap::new(),
+            next_tile: next_tile,
+        };
+        //The first pass just records the times of the images that were already mapped
+        game.update_images();
+        game
     }
 
     ///Reads the file with the paths for all images. Assigns tiles by count.
@@ -80,27 +115,161 @@ impl Game {
         m
     }
 
+    ///Looks through images/ for gifs that are new or changed since the last check. New images get
+    ///the next free tile number. Returns the names of every image that changed.
+    pub fn update_images(&mut self) -> Vec<String> {
+        let mut changed = vec![];
+        for entry in glob("images/**/*.gif").unwrap() {
+            match entry {
+                Ok(img) => {
+                    let name = img.file_stem().unwrap().to_str().unwrap().to_string();
+                    let modified = match fs::metadata(&img) {
+                        Ok(meta) => meta.modified().ok(),
+                        Err(_) => None,
+                    };
+                    let is_changed = match self.image_times.get(&name) {
+                        Some(time) => *time != modified,
+                        None => true,
+                    };
+                    if is_changed {
+                        self.image_times.insert(name.clone(), modified);
+                        if !self.mappings.contains_key(&name) {
+                            self.mappings.insert(name.clone(), self.next_tile);
+                            self.next_tile = self.next_tile + 1;
+                        }
+                        changed.push(name);
+                    }
+                },
+                _ => {},
+            }
+        }
+        changed
+    }
+
+    ///Splits a map name like "cave#alice" into the map & the instance
+    pub fn split_instance(map: &str) -> (&str, Option<&str>) {
+        match map.find('#') {
+            Some(i) => {
+                (&map[..i], Some(&map[i+1..]))
+            },
+            None => {
+                (map, None)
+            },
+        }
+    }
+
+    ///Names the instance "join <map> private" makes for a player
+    pub fn private_instance(map: &str, player: &str) -> String {
+        format!("{}#{}{}", map, PRIVATE_PREFIX, safe_name(player))
+    }
+
+    ///Gives the owner of a private instance, as their safe name. Shared maps & named instances
+    ///don't have one.
+    pub fn private_owner(map: &str) -> Option<&str> {
+        match Game::split_instance(map) {
+            (_, Some(instance)) if instance.starts_with(PRIVATE_PREFIX) => {
+                Some(&instance[PRIVATE_PREFIX.len()..])
+            },
+            _ => {
+                None
+            },
+        }
+    }
+
+    ///Checks that an instance name, if there is one, is something sane.
+    pub fn valid_instance(map: &str) -> bool {
+        match Game::split_instance(map) {
+            (_, Some(instance)) => {
+                instance.len() > 0 && instance.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+            },
+            (_, None) => {
+                true
+            },
+        }
+    }
+
+    ///Turns a map name from a user or teleporter into the name of its game loop
+    pub fn loop_name(map: &str) -> String {
+        match Game::split_instance(map) {
+            (name, Some(instance)) => {
+                format!("{}#{}", Game::map_file(name), instance)
+            },
+            (name, None) => {
+                Game::map_file(name)
+            },
+        }
+    }
+
+    ///Finds the file for a map name. Names without an extension are looked for as a .map, then
+    ///as a map from Tiled, so "cave" can be cave.map or cave.tmx. Once found, the file is
+    ///remembered. Names with no file yet are looked for again next time, so new maps show up.
+    pub fn map_file(name: &str) -> String {
+        if name.ends_with(".map") || tiled::is_tiled(name) {
+            return format!("maps/{}", name);
+        }
+        let mut files = MAP_FILES.lock().unwrap();
+        match files.get(name) {
+            Some(path) => {
+                return path.clone();
+            },
+            None => {},
+        }
+        for extension in ["map", "tmx", "json"].iter() {
+            let path = format!("maps/{}.{}", name, extension);
+            if fs::metadata(&path).is_ok() {
+                files.insert(name.to_string(), path.clone());
+                return path;
+            }
+        }
+        format!("maps/{}.map", name)
+    }
+
+    ///Finds a game loop that is already running. Unlike get_or_create_game_loop, this never starts
+    ///a new one.
+    pub fn find_game_loop(&self, map_name: &str) -> Option<Arc<RefCell<GameLoop>>> {
+        let loops = self.game_loops.lock().unwrap();
+        match loops.get(map_name) {
+            Some(game_loop) => {
+                Some(game_loop.clone())
+            },
+            None => {
+                None
+            },
+        }
+    }
+
+    ///Has every running game loop work out its objects' art again, i.e. after the art aliases
+    ///change
+    pub fn refresh_art(&self) {
+        let loops = self.game_loops.lock().unwrap();
+        for game_loop in loops.values() {
+            game_loop.borrow_mut().refresh_art();
+        }
+    }
+
     ///Creates a new game loop with the given name, or finds it already in the hashmap.
-    pub fn get_or_create_game_loop(&mut self, map_name: &str) -> Option<Arc<RefCell<GameLoop>>> {
+    pub fn get_or_create_game_loop(&mut self, map_name: &str) -> Result<Arc<RefCell<GameLoop>>, String> {
         println!("{}", map_name);
         //This can handle all kinds of things. Checks last time user was inside, if too long it recreates. 
         //Checks the hashmap for the Gameloop. If not there, it creates a new one, adds it and returns it.
         let mut loops = self.game_loops.lock().unwrap();
         match loops.entry(map_name.to_string()) {
             Vacant(blank) => {
-                match GameLoop::new(map_name, self.send.clone()) {
-                    Some(game) => {
+                //Instances share the map file, so the instance is dropped when loading
+                let (path, _) = Game::split_instance(map_name);
+                match GameLoop::new(path, self.send.clone()) {
+                    Ok(game) => {
                         let full = Arc::new(RefCell::new(game));
                         blank.insert(full.clone());
-                        Some(full)
+                        Ok(full)
                     },
-                    None =>{
-                        None
+                    Err(e) =>{
+                        Err(e)
                     },
                 }
             },
             Occupied(map) => {
-                Some(map.get().clone())
+                Ok(map.get().clone())
             },
         }
     }
@@ -0,0 +1,143 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module holds the properties a designer can set on an arch in the map editor. They are
+/// the <string>, <int>, <boolean> and <bean> children of an <arch>. Beans are just a named
+/// group of more properties.
+
+use std::fmt;
+
+///A single value from the map file
+#[derive(Clone, Debug, PartialEq)]
+pub enum Property {
+    String(String),
+    Int(i64),
+    Boolean(bool),
+    Bean(Properties),
+}
+
+///The properties on an arch, in the order they were in the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Properties {
+    values: Vec<(String, Property)>,
+    //The class the editor gave a bean or header, i.e. wyvern.lib.Location. Only kept so the
+    //map can be written back out.
+    class: Option<String>,
+}
+
+impl Properties {
+    pub fn new() -> Properties {
+        Properties {
+            values: vec![],
+            class: None,
+        }
+    }
+
+    ///Sets a property. A property that is already there gets replaced in place.
+    pub fn insert(&mut self, name: &str, value: Property) {
+        for entry in self.values.iter_mut() {
+            if entry.0 == name {
+                entry.1 = value;
+                return;
+            }
+        }
+        self.values.push((name.to_string(), value));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Property> {
+        for &(ref key, ref value) in self.values.iter() {
+            if key == name {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    pub fn get_string(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(&Property::String(ref s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn get_int(&self, name: &str) -> Option<i64> {
+        match self.get(name) {
+            Some(&Property::Int(i)) => Some(i),
+            _ => None,
+        }
+    }
+
+    ///Booleans that aren't set are false, same as in the editor
+    pub fn get_bool(&self, name: &str) -> bool {
+        match self.get(name) {
+            Some(&Property::Boolean(b)) => b,
+            _ => false,
+        }
+    }
+
+    pub fn get_bean(&self, name: &str) -> Option<&Properties> {
+        match self.get(name) {
+            Some(&Property::Bean(ref bean)) => Some(bean),
+            _ => None,
+        }
+    }
+
+    pub fn class(&self) -> Option<&str> {
+        match self.class {
+            Some(ref class) => Some(class),
+            None => None,
+        }
+    }
+
+    pub fn set_class(&mut self, class: &str) {
+        self.class = Some(class.to_string());
+    }
+
+    pub fn iter<'a>(&'a self) -> ::std::slice::Iter<'a, (String, Property)> {
+        self.values.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Display for Property {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Property::String(ref s) => write!(f, "\"{}\"", s),
+            Property::Int(i) => write!(f, "{}", i),
+            Property::Boolean(b) => write!(f, "{}", b),
+            Property::Bean(ref bean) => write!(f, "{{{}}}", bean),
+        }
+    }
+}
+
+///Writes the properties as name=value, separated by commas
+impl fmt::Display for Properties {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(ref name, ref value)) in self.values.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
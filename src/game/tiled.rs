@@ -0,0 +1,905 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module loads maps made in Tiled, saved as .tmx or .json. Tiled only knows its tiles by
+/// number, so tiled.txt says which Wyvern art each tile of each tileset is. The Tiled map is
+/// turned into the old editor's XML and read by the normal parser, so a Tiled map works the same
+/// as any other once it is loaded.
+
+use game::gamemap::{GameMap, MAX_TILES};
+use game::maperror::MapError;
+use game::mapwriter::{write_arch, write_properties};
+use game::properties::{Properties, Property};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+use xml::common::Position;
+use xml::attribute::OwnedAttribute;
+use xml::escape::escape_str_attribute;
+use xml::reader::{EventReader, XmlEvent};
+
+///Says which art each Tiled tile is. One "tileset id path" per line, i.e. "outside 3 terrain/grass".
+///The id is the tile's number inside its tileset, starting at 0, as Tiled shows it.
+pub const MAPPING_FILE: &'static str = "tiled.txt";
+
+///Tiled keeps whether a tile is flipped in the top bits of its number
+const FLIP_FLAGS: u32 = 0xE0000000;
+
+///Whether a map file is from Tiled instead of the Wyvern editor
+pub fn is_tiled(path: &str) -> bool {
+    path.ends_with(".tmx") || path.ends_with(".json")
+}
+
+///Loads a Tiled map, using tiled.txt for the art
+pub fn load(path: &str) -> Result<GameMap, MapError> {
+    let tiled = if path.ends_with(".json") {
+        TiledMap::read_json(path)?
+    } else {
+        TiledMap::read_tmx(path)?
+    };
+    let mappings = TileMappings::load(MAPPING_FILE)?;
+    to_game_map(path, &tiled, &mappings)
+}
+
+///Turns a Tiled map into ours. Anything the parser finds wrong is in the XML made from the
+///Tiled map, so its line numbers would point at a file nobody has. Those errors say so instead.
+fn to_game_map(path: &str, tiled: &TiledMap, mappings: &TileMappings) -> Result<GameMap, MapError> {
+    let xml = tiled.to_xml(mappings)?;
+    GameMap::from_xml(path, &xml).map_err(|e| MapError::Tiled(format!("the map made from it is broken, {}", e)))
+}
+
+///The art for each tile of each tileset, from tiled.txt
+pub struct TileMappings {
+    paths: HashMap<(String, u32), String>,
+}
+
+impl TileMappings {
+    ///Reads the mapping file. Blank lines & lines starting with # are skipped. Tileset names can
+    ///have spaces, since the id & path are always the last two things on the line.
+    pub fn load(file: &str) -> Result<TileMappings, MapError> {
+        let reader = match File::open(file) {
+            Ok(f) => BufReader::new(f),
+            Err(_) => {
+                return Err(MapError::Tiled(format!("{} is missing. It says which art each Tiled tile is.", file)));
+            },
+        };
+        let mut paths = HashMap::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => {
+                    break;
+                },
+            };
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let len = parts.len();
+            let id = if len >= 3 { parts[len - 2].parse::<u32>().ok() } else { None };
+            match id {
+                Some(id) => {
+                    paths.insert((parts[..len - 2].join(" "), id), parts[len - 1].to_string());
+                },
+                None => {
+                    return Err(MapError::Tiled(format!("{} line {} should be \"tileset id path\", not \"{}\"",
+                                                       file, number + 1, line)));
+                },
+            }
+        }
+        Ok(TileMappings {
+            paths: paths,
+        })
+    }
+
+    ///Finds the art for a tile number from the map
+    fn path(&self, tilesets: &[Tileset], gid: u32) -> Result<String, String> {
+        let gid = gid & !FLIP_FLAGS;
+        //Tilesets own every number from their first one up to the next tileset's
+        let tileset = tilesets.iter().filter(|t| t.first_gid <= gid).max_by_key(|t| t.first_gid);
+        match tileset {
+            Some(tileset) => {
+                let id = gid - tileset.first_gid;
+                match self.paths.get(&(tileset.name.clone(), id)) {
+                    Some(path) => Ok(path.clone()),
+                    None => Err(format!("tile {} of tileset {} isn't in {}", id, tileset.name, MAPPING_FILE)),
+                }
+            },
+            None => {
+                Err(format!("tile {} isn't in any tileset", gid))
+            },
+        }
+    }
+}
+
+///A tileset the map uses. Tilesets saved in their own file are named after the file.
+struct Tileset {
+    first_gid: u32,
+    name: String,
+}
+
+///An object from an object layer. x, y, width & height are in pixels, like Tiled has them.
+struct TiledObject {
+    name: String,
+    //Tiled calls this type in older versions & class in newer ones
+    kind: String,
+    //0 for objects that aren't a tile
+    gid: u32,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    properties: Properties,
+}
+
+impl TiledObject {
+    fn new() -> TiledObject {
+        TiledObject {
+            name: String::new(),
+            kind: String::new(),
+            gid: 0,
+            x: 0.0,
+            y: 0.0,
+            width: 0.0,
+            height: 0.0,
+            properties: Properties::new(),
+        }
+    }
+}
+
+///Everything the loader uses from a Tiled map, read from either format
+struct TiledMap {
+    width: u32,
+    height: u32,
+    tile_width: u32,
+    tile_height: u32,
+    properties: Properties,
+    tilesets: Vec<Tileset>,
+    //Tile numbers of each tile layer, bottom layer first. 0 is an empty cell.
+    layers: Vec<(String, Vec<u32>)>,
+    objects: Vec<TiledObject>,
+}
+
+impl TiledMap {
+    fn new() -> TiledMap {
+        TiledMap {
+            width: 0,
+            height: 0,
+            tile_width: 32,
+            tile_height: 32,
+            properties: Properties::new(),
+            tilesets: vec![],
+            layers: vec![],
+            objects: vec![],
+        }
+    }
+
+    ///Reads a .tmx file. Errors have the line & column, like ones from .map files.
+    fn read_tmx(path: &str) -> Result<TiledMap, MapError> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(_) => {
+                return Err(MapError::NotFound(path.to_string()));
+            },
+        };
+        TiledMap::parse_tmx(BufReader::new(file))
+    }
+
+    fn parse_tmx<R: Read>(source: R) -> Result<TiledMap, MapError> {
+        let mut tiled = TiledMap::new();
+        let mut parser = EventReader::new(source);
+        //Names of the open elements, so properties can tell what they belong to
+        let mut open: Vec<String> = vec![];
+        let mut layer = String::new();
+        let mut gids: Vec<u32> = vec![];
+        let mut encoding = String::new();
+        let mut compression = String::new();
+        let mut text = String::new();
+        let mut object = TiledObject::new();
+        //A property whose value is in its text instead of an attribute
+        let mut pending: Option<(String, String)> = None;
+        loop {
+            let event = parser.next();
+            let position = parser.position();
+            match event {
+                Ok(XmlEvent::EndDocument) => {
+                    break;
+                },
+                Err(e) => {
+                    return Err(MapError::Xml {
+                        message: e.msg().to_string(),
+                        position: e.position(),
+                    });
+                },
+                Ok(XmlEvent::StartElement {name, attributes, ..}) => {
+                    let parent = open.last().cloned().unwrap_or(String::new());
+                    let element = name.local_name;
+                    if element == "map" {
+                        if attribute(&attributes, "infinite") == "1" {
+                            return Err(MapError::Invalid {
+                                element: element,
+                                message: "is infinite. Turn off Infinite in the map properties.".to_string(),
+                                position: position,
+                            });
+                        }
+                        tiled.width = tmx_number(&attributes, "width", &element, position)? as u32;
+                        tiled.height = tmx_number(&attributes, "height", &element, position)? as u32;
+                        tiled.tile_width = tmx_number(&attributes, "tilewidth", &element, position)? as u32;
+                        tiled.tile_height = tmx_number(&attributes, "tileheight", &element, position)? as u32;
+                    } else if element == "tileset" && parent == "map" {
+                        tiled.tilesets.push(Tileset {
+                            first_gid: tmx_number(&attributes, "firstgid", &element, position)? as u32,
+                            name: tileset_name(&attribute(&attributes, "name"), &attribute(&attributes, "source")),
+                        });
+                    } else if element == "layer" {
+                        layer = attribute(&attributes, "name");
+                        gids.clear();
+                    } else if element == "data" {
+                        encoding = attribute(&attributes, "encoding");
+                        compression = attribute(&attributes, "compression");
+                        text.clear();
+                    } else if element == "tile" && parent == "data" {
+                        gids.push(tmx_number(&attributes, "gid", &element, position)? as u32);
+                    } else if element == "object" {
+                        object = TiledObject::new();
+                        object.name = attribute(&attributes, "name");
+                        object.kind = attribute(&attributes, "type");
+                        if object.kind.len() == 0 {
+                            object.kind = attribute(&attributes, "class");
+                        }
+                        object.gid = tmx_number(&attributes, "gid", &element, position)? as u32;
+                        object.x = tmx_number(&attributes, "x", &element, position)?;
+                        object.y = tmx_number(&attributes, "y", &element, position)?;
+                        object.width = tmx_number(&attributes, "width", &element, position)?;
+                        object.height = tmx_number(&attributes, "height", &element, position)?;
+                    } else if element == "property" {
+                        let name = attribute(&attributes, "name");
+                        let kind = attribute(&attributes, "type");
+                        //Multi-line strings are kept in the text instead of value
+                        if attributes.iter().any(|a| a.name.local_name == "value") {
+                            let value = attribute(&attributes, "value");
+                            tiled.add_property(&open, &mut object, &name, property(&kind, &value));
+                        } else {
+                            pending = Some((name, kind));
+                            text.clear();
+                        }
+                    }
+                    open.push(element);
+                },
+                Ok(XmlEvent::Characters(chars)) => {
+                    text.push_str(&chars);
+                },
+                Ok(XmlEvent::EndElement {name}) => {
+                    open.pop();
+                    let element = name.local_name;
+                    if element == "data" {
+                        if encoding.len() > 0 {
+                            gids = match decode(&text, &encoding, &compression) {
+                                Ok(g) => g,
+                                Err(message) => {
+                                    return Err(MapError::Invalid {
+                                        element: element,
+                                        message: message,
+                                        position: position,
+                                    });
+                                },
+                            };
+                        }
+                    } else if element == "layer" {
+                        tiled.layers.push((layer.clone(), gids.clone()));
+                    } else if element == "object" {
+                        tiled.objects.push(object);
+                        object = TiledObject::new();
+                    } else if element == "property" {
+                        match pending.take() {
+                            Some((name, kind)) => {
+                                let value = text.clone();
+                                tiled.add_property(&open, &mut object, &name, property(&kind, &value));
+                            },
+                            None => {},
+                        }
+                    } else if element == "chunk" {
+                        return Err(MapError::Invalid {
+                            element: element,
+                            message: "is from an infinite map. Turn off Infinite in the map properties.".to_string(),
+                            position: position,
+                        });
+                    }
+                },
+                _ => {},
+            }
+        }
+        Ok(tiled)
+    }
+
+    ///Gives a property from a .tmx to the map or object it is inside. open ends with properties,
+    ///so the owner is the element before that.
+    fn add_property(&mut self, open: &[String], object: &mut TiledObject, name: &str, value: Property) {
+        if open.len() < 2 {
+            return;
+        }
+        let owner = &open[open.len() - 2];
+        if owner == "map" {
+            self.properties.insert(name, value);
+        } else if owner == "object" {
+            object.properties.insert(name, value);
+        }
+    }
+
+    ///Reads a .json map. JSON has no line numbers to give, so errors say what they were reading.
+    fn read_json(path: &str) -> Result<TiledMap, MapError> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut f) => {
+                match f.read_to_string(&mut contents) {
+                    Ok(_) => {},
+                    Err(e) => {
+                        return Err(MapError::Tiled(format!("couldn't read it, {}", e)));
+                    },
+                }
+            },
+            Err(_) => {
+                return Err(MapError::NotFound(path.to_string()));
+            },
+        }
+        TiledMap::parse_json(&contents)
+    }
+
+    fn parse_json(contents: &str) -> Result<TiledMap, MapError> {
+        let json = match Json::from_str(contents) {
+            Ok(json) => json,
+            Err(e) => {
+                return Err(MapError::Tiled(format!("broken json, {}", e)));
+            },
+        };
+        let mut tiled = TiledMap::new();
+        if json.find("infinite").and_then(|i| i.as_boolean()) == Some(true) {
+            return Err(MapError::Tiled("the map is infinite. Turn off Infinite in the map properties.".to_string()));
+        }
+        tiled.width = json_number(&json, "width", "map")? as u32;
+        tiled.height = json_number(&json, "height", "map")? as u32;
+        tiled.tile_width = json_number(&json, "tilewidth", "map")? as u32;
+        tiled.tile_height = json_number(&json, "tileheight", "map")? as u32;
+        tiled.properties = json_properties(&json);
+        match json.find("tilesets").and_then(|t| t.as_array()) {
+            Some(tilesets) => {
+                for tileset in tilesets {
+                    tiled.tilesets.push(Tileset {
+                        first_gid: json_number(tileset, "firstgid", "tileset")? as u32,
+                        name: tileset_name(json_string(tileset, "name"), json_string(tileset, "source")),
+                    });
+                }
+            },
+            None => {},
+        }
+        match json.find("layers") {
+            Some(layers) => {
+                tiled.read_json_layers(layers)?;
+            },
+            None => {},
+        }
+        Ok(tiled)
+    }
+
+    ///Reads JSON layers. Group layers are read in place, so the order is the same as in Tiled.
+    fn read_json_layers(&mut self, layers: &Json) -> Result<(), MapError> {
+        let layers = match layers.as_array() {
+            Some(layers) => layers,
+            None => {
+                return Ok(());
+            },
+        };
+        for layer in layers {
+            let name = json_string(layer, "name").to_string();
+            match json_string(layer, "type") {
+                "tilelayer" => {
+                    let gids = match layer.find("data") {
+                        Some(&Json::Array(ref data)) => {
+                            let mut gids = vec![];
+                            for gid in data {
+                                match gid.as_u64() {
+                                    Some(g) => gids.push(g as u32),
+                                    None => {
+                                        return Err(MapError::Tiled(format!("layer {} has a tile that isn't a number: {}", name, gid)));
+                                    },
+                                }
+                            }
+                            gids
+                        },
+                        Some(&Json::String(ref data)) => {
+                            let encoding = json_string(layer, "encoding");
+                            let compression = json_string(layer, "compression");
+                            match decode(data, encoding, compression) {
+                                Ok(gids) => gids,
+                                Err(message) => {
+                                    return Err(MapError::Tiled(format!("layer {}: {}", name, message)));
+                                },
+                            }
+                        },
+                        _ => {
+                            return Err(MapError::Tiled(format!("layer {} has no data", name)));
+                        },
+                    };
+                    self.layers.push((name, gids));
+                },
+                "objectgroup" => {
+                    match layer.find("objects").and_then(|o| o.as_array()) {
+                        Some(objects) => {
+                            for o in objects {
+                                let mut object = TiledObject::new();
+                                object.name = json_string(o, "name").to_string();
+                                object.kind = json_string(o, "type").to_string();
+                                if object.kind.len() == 0 {
+                                    object.kind = json_string(o, "class").to_string();
+                                }
+                                object.gid = o.find("gid").and_then(|g| g.as_u64()).unwrap_or(0) as u32;
+                                object.x = json_number(o, "x", "object")?;
+                                object.y = json_number(o, "y", "object")?;
+                                object.width = o.find("width").and_then(|w| w.as_f64()).unwrap_or(0.0);
+                                object.height = o.find("height").and_then(|h| h.as_f64()).unwrap_or(0.0);
+                                object.properties = json_properties(o);
+                                self.objects.push(object);
+                            }
+                        },
+                        None => {},
+                    }
+                },
+                "group" => {
+                    match layer.find("layers") {
+                        Some(inner) => {
+                            self.read_json_layers(inner)?;
+                        },
+                        None => {},
+                    }
+                },
+                //Image layers are just pictures, there is nothing to load
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    ///Turns the map into the old editor's XML. Map properties go in the header. terrain &
+    ///oob-terrain properties set the header's terrain, and an object with the type start sets the
+    ///start point.
+    fn to_xml(&self, mappings: &TileMappings) -> Result<String, MapError> {
+        //Checked before anything is made for the tiles, the same way the parser checks its header
+        if self.width == 0 || self.height == 0 {
+            return Err(MapError::Tiled("the map needs a width and height above 0".to_string()));
+        }
+        let size = match self.width.checked_mul(self.height) {
+            Some(size) if size <= MAX_TILES => size as usize,
+            _ => {
+                return Err(MapError::Tiled(format!("the map is {}x{}, which is too big. Maps can have up to {} tiles.",
+                                                   self.width, self.height, MAX_TILES)));
+            },
+        };
+        let tile_width = if self.tile_width == 0 { 32.0 } else { self.tile_width as f64 };
+        let tile_height = if self.tile_height == 0 { 32.0 } else { self.tile_height as f64 };
+        let mut header = Properties::new();
+        let mut terrain = "terrain/empty".to_string();
+        let mut oob_terrain = "terrain/empty".to_string();
+        for &(ref name, ref value) in self.properties.iter() {
+            match (&name[..], value) {
+                ("terrain", &Property::String(ref path)) => {
+                    terrain = path.clone();
+                },
+                ("oob-terrain", &Property::String(ref path)) => {
+                    oob_terrain = path.clone();
+                },
+                _ => {
+                    header.insert(name, value.clone());
+                },
+            }
+        }
+        //Tile layers. Terrain from a higher layer covers the one below it, anything else becomes
+        //an object on that tile.
+        let mut tiles: Vec<Option<String>> = vec![None; size];
+        let mut arches = String::new();
+        for &(ref name, ref gids) in self.layers.iter() {
+            if gids.len() != size {
+                return Err(MapError::Tiled(format!("layer {} has {} tiles, but the map is {}x{}",
+                                                   name, gids.len(), self.width, self.height)));
+            }
+            for (index, gid) in gids.iter().enumerate() {
+                if *gid == 0 {
+                    continue;
+                }
+                let (x, y) = (index % self.width as usize, index / self.width as usize);
+                let path = match mappings.path(&self.tilesets, *gid) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        return Err(MapError::Tiled(format!("layer {} at {} {}: {}", name, x, y, e)));
+                    },
+                };
+                if path.contains("terrain") {
+                    tiles[index] = Some(path);
+                } else {
+                    write_arch(&mut arches, &format!("{} {}", x, y), &path, &Properties::new());
+                }
+            }
+        }
+        //Object layers
+        for object in self.objects.iter() {
+            //Tile objects hang up from their y, everything else hangs down
+            let top = if object.gid > 0 { object.y - object.height } else { object.y };
+            let x = (object.x / tile_width).floor().max(0.0) as u32;
+            let y = (top / tile_height).floor().max(0.0) as u32;
+            let w = ((object.width / tile_width).round() as u32).max(1);
+            let h = ((object.height / tile_height).round() as u32).max(1);
+            let description = if object.name.len() > 0 { object.name.clone() } else { format!("at {} {}", x, y) };
+            if x as u64 + w as u64 > self.width as u64 || y as u64 + h as u64 > self.height as u64 {
+                return Err(MapError::Tiled(format!("object {} is off the {}x{} map", description, self.width, self.height)));
+            }
+            if object.kind == "start" {
+                header.insert("startX", Property::Int(x as i64));
+                header.insert("startY", Property::Int(y as i64));
+            } else if object.gid > 0 {
+                match mappings.path(&self.tilesets, object.gid) {
+                    //The parser sizes objects by their art, so only the corner is needed
+                    Ok(path) => write_arch(&mut arches, &format!("{} {}", x, y), &path, &object.properties),
+                    Err(e) => {
+                        return Err(MapError::Tiled(format!("object {}: {}", description, e)));
+                    },
+                }
+            } else if object.kind == "teleporter" {
+                write_arch(&mut arches, &format!("{} {} {} {}", x, y, w, h), "special/teleporter", &object.properties);
+            } else {
+                //Shapes can still be any arch, by setting a path property
+                let mut properties = object.properties.clone();
+                let path = match properties.get_string("path") {
+                    Some(path) => path.to_string(),
+                    None => {
+                        return Err(MapError::Tiled(format!("object {} needs a tile, the type teleporter or start, or a path property",
+                                                           description)));
+                    },
+                };
+                properties = without(&properties, "path");
+                write_arch(&mut arches, &format!("{} {} {} {}", x, y, w, h), &path, &properties);
+            }
+        }
+        let mut out = String::new();
+        out.push_str("<map>\n");
+        out.push_str(&format!("<header class=\"DenseMap\" width=\"{}\" height=\"{}\">\n", self.width, self.height));
+        write_properties(&mut out, &header, 1);
+        out.push_str(&format!("  <arch name=\"oob-terrain\" path=\"{}\"/>\n", escape_str_attribute(&oob_terrain)));
+        out.push_str(&format!("  <arch name=\"terrain\" path=\"{}\"/>\n", escape_str_attribute(&terrain)));
+        out.push_str("</header>\n");
+        //Terrain goes first, a row at a time, with runs of the same terrain in one arch
+        let width = self.width as usize;
+        for y in 0..self.height as usize {
+            let mut x = 0;
+            while x < width {
+                match tiles[y * width + x] {
+                    Some(ref path) => {
+                        let mut run = 1;
+                        while x + run < width && tiles[y * width + x + run].as_ref() == Some(path) {
+                            run += 1;
+                        }
+                        out.push_str(&format!("<arch loc=\"{} {} {} 1\" path=\"{}\"/>\n", x, y, run, escape_str_attribute(path)));
+                        x += run;
+                    },
+                    None => {
+                        x += 1;
+                    },
+                }
+            }
+        }
+        out.push_str(&arches);
+        out.push_str("</map>\n");
+        Ok(out)
+    }
+}
+
+///Copies properties, leaving one out
+fn without(properties: &Properties, name: &str) -> Properties {
+    let mut copy = Properties::new();
+    for &(ref key, ref value) in properties.iter() {
+        if key != name {
+            copy.insert(key, value.clone());
+        }
+    }
+    copy
+}
+
+///Names a tileset. Ones in their own .tsx file only have the file name in the map.
+fn tileset_name(name: &str, source: &str) -> String {
+    if name.len() > 0 {
+        name.to_string()
+    } else {
+        Path::new(source).file_stem().and_then(|s| s.to_str()).unwrap_or("").to_string()
+    }
+}
+
+///Turns a Tiled property into one of ours. Floats that are whole numbers become ints, since
+///that is all the old editor has.
+fn property(kind: &str, value: &str) -> Property {
+    match kind {
+        "int" => {
+            match value.trim().parse::<i64>() {
+                Ok(i) => Property::Int(i),
+                Err(_) => Property::String(value.to_string()),
+            }
+        },
+        "float" => {
+            match value.trim().parse::<f64>() {
+                Ok(f) if f.fract() == 0.0 => Property::Int(f as i64),
+                _ => Property::String(value.to_string()),
+            }
+        },
+        "bool" => Property::Boolean(value.trim() == "true"),
+        _ => Property::String(value.to_string()),
+    }
+}
+
+///Decodes the tiles of a layer saved as csv or base64, which can also be zlib or gzip compressed
+fn decode(data: &str, encoding: &str, compression: &str) -> Result<Vec<u32>, String> {
+    match encoding {
+        "csv" => {
+            let mut gids = vec![];
+            for value in data.split(',').map(|v| v.trim()).filter(|v| v.len() > 0) {
+                match value.parse::<u32>() {
+                    Ok(gid) => gids.push(gid),
+                    Err(_) => {
+                        return Err(format!("has a tile that isn't a number: {}", value));
+                    },
+                }
+            }
+            Ok(gids)
+        },
+        "base64" => {
+            let bytes = match data.trim().from_base64() {
+                Ok(b) => b,
+                Err(e) => {
+                    return Err(format!("has broken base64, {}", e));
+                },
+            };
+            let mut raw = vec![];
+            let read = match compression {
+                "" => {
+                    raw = bytes;
+                    Ok(0)
+                },
+                "zlib" => ZlibDecoder::new(&bytes[..]).read_to_end(&mut raw),
+                "gzip" => GzDecoder::new(&bytes[..]).and_then(|mut d| d.read_to_end(&mut raw)),
+                other => {
+                    return Err(format!("uses {} compression. Save it with zlib, gzip or none.", other));
+                },
+            };
+            match read {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("couldn't be uncompressed, {}", e));
+                },
+            }
+            //Each tile is a little endian u32
+            Ok(raw.chunks(4).filter(|c| c.len() == 4).map(|c| {
+                c[0] as u32 | (c[1] as u32) << 8 | (c[2] as u32) << 16 | (c[3] as u32) << 24
+            }).collect())
+        },
+        other => {
+            Err(format!("uses the {} layer format. Save it as CSV or Base64.", other))
+        },
+    }
+}
+
+///Gets the value of an attribute, or an empty string if it isn't there
+fn attribute(attributes: &[OwnedAttribute], name: &str) -> String {
+    match attributes.iter().find(|a| a.name.local_name == name) {
+        Some(attr) => attr.value.clone(),
+        None => String::new(),
+    }
+}
+
+///Reads a number from a .tmx attribute. Missing ones are 0, as Tiled leaves out anything that is 0.
+fn tmx_number(attributes: &[OwnedAttribute], name: &str, element: &str, position: ::xml::common::TextPosition) -> Result<f64, MapError> {
+    let value = attribute(attributes, name);
+    if value.len() == 0 {
+        return Ok(0.0);
+    }
+    match value.parse::<f64>() {
+        Ok(n) if n >= 0.0 || name == "x" || name == "y" => Ok(n),
+        _ => Err(MapError::bad_value(element, name, &value, position)),
+    }
+}
+
+fn json_string<'a>(json: &'a Json, name: &str) -> &'a str {
+    json.find(name).and_then(|v| v.as_string()).unwrap_or("")
+}
+
+fn json_number(json: &Json, name: &str, what: &str) -> Result<f64, MapError> {
+    match json.find(name).and_then(|v| v.as_f64()) {
+        Some(n) => Ok(n),
+        None => Err(MapError::Tiled(format!("{} needs a number for {}", what, name))),
+    }
+}
+
+///Reads the properties of a map or object. Newer versions of Tiled save a list of name, type &
+///value. Older ones save an object of names to values.
+fn json_properties(json: &Json) -> Properties {
+    let mut properties = Properties::new();
+    match json.find("properties") {
+        Some(&Json::Array(ref list)) => {
+            for p in list {
+                let value = match p.find("value") {
+                    Some(value) => json_property(value),
+                    None => continue,
+                };
+                properties.insert(json_string(p, "name"), value);
+            }
+        },
+        Some(&Json::Object(ref values)) => {
+            for (name, value) in values.iter() {
+                properties.insert(name, json_property(value));
+            }
+        },
+        _ => {},
+    }
+    properties
+}
+
+fn json_property(value: &Json) -> Property {
+    match *value {
+        Json::Boolean(b) => Property::Boolean(b),
+        Json::I64(i) => Property::Int(i),
+        Json::U64(u) => Property::Int(u as i64),
+        Json::F64(f) if f.fract() == 0.0 => Property::Int(f as i64),
+        Json::String(ref s) => Property::String(s.clone()),
+        ref other => Property::String(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, to_game_map, TiledMap, TileMappings, MAPPING_FILE};
+    use game::gamemap::GameMap;
+    use game::maperror::MapError;
+
+    ///A 4x3 map using the wyvern tileset from tiled.txt. Grass on the left, water on the right &
+    ///trees along the bottom, with a sign at 1 1, the start at 0 0 and a teleporter at 1 0.
+    const TMX: &'static str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<map version=\"1.10\" orientation=\"orthogonal\" width=\"4\" height=\"3\" tilewidth=\"32\" tileheight=\"32\" infinite=\"0\">
+ <properties>
+  <property name=\"oob-terrain\" value=\"terrain/trees2\"/>
+ </properties>
+ <tileset firstgid=\"1\" name=\"wyvern\" tilewidth=\"32\" tileheight=\"32\"/>
+ <layer id=\"1\" name=\"ground\" width=\"4\" height=\"3\">
+  <data encoding=\"csv\">
+GROUND
+</data>
+ </layer>
+ <objectgroup id=\"2\" name=\"things\">
+  <object id=\"1\" gid=\"14\" x=\"32\" y=\"64\" width=\"32\" height=\"32\">
+   <properties>
+    <property name=\"message\" value=\"Wet\"/>
+   </properties>
+  </object>
+  <object id=\"2\" type=\"start\" x=\"0\" y=\"0\"/>
+  <object id=\"3\" type=\"teleporter\" x=\"32\" y=\"0\" width=\"32\" height=\"32\">
+   <properties>
+    <property name=\"map\" value=\"main\"/>
+    <property name=\"x\" type=\"int\" value=\"X\"/>
+    <property name=\"y\" type=\"int\" value=\"15\"/>
+   </properties>
+  </object>
+ </objectgroup>
+</map>
+";
+
+    ///The same map, saved as JSON with the layer in base64
+    const JSON: &'static str = "{\"width\": 4, \"height\": 3, \"tilewidth\": 32, \"tileheight\": 32, \"infinite\": false,
+ \"properties\": [{\"name\": \"oob-terrain\", \"type\": \"string\", \"value\": \"terrain/trees2\"}],
+ \"tilesets\": [{\"firstgid\": 1, \"name\": \"wyvern\"}],
+ \"layers\": [
+  {\"type\": \"tilelayer\", \"name\": \"ground\", \"encoding\": \"base64\",
+   \"data\": \"AQAAAAEAAAAHAAAABwAAAAEAAAABAAAABwAAAAcAAAAFAAAABQAAAAUAAAAFAAAA\"},
+  {\"type\": \"group\", \"name\": \"stuff\", \"layers\": [
+   {\"type\": \"objectgroup\", \"name\": \"things\", \"objects\": [
+    {\"gid\": 14, \"x\": 32, \"y\": 64, \"width\": 32, \"height\": 32,
+     \"properties\": [{\"name\": \"message\", \"type\": \"string\", \"value\": \"Wet\"}]},
+    {\"type\": \"start\", \"x\": 0, \"y\": 0},
+    {\"type\": \"teleporter\", \"x\": 32, \"y\": 0, \"width\": 32, \"height\": 32,
+     \"properties\": [{\"name\": \"map\", \"type\": \"string\", \"value\": \"main\"},
+                    {\"name\": \"x\", \"type\": \"int\", \"value\": 20},
+                    {\"name\": \"y\", \"type\": \"int\", \"value\": 15}]}]}]}]}";
+
+    fn tmx(ground: &str, x: i32) -> TiledMap {
+        let text = TMX.replace("GROUND", ground).replace("value=\"X\"", &format!("value=\"{}\"", x));
+        TiledMap::parse_tmx(text.as_bytes()).unwrap()
+    }
+
+    fn convert(tiled: &TiledMap) -> Result<GameMap, MapError> {
+        to_game_map("maps/inline.tmx", tiled, &TileMappings::load(MAPPING_FILE).unwrap())
+    }
+
+    ///Checks the little map came through, whichever format it was in
+    fn check_small_map(map: &GameMap) {
+        let terrain: Vec<&str> = map.tiles.iter().map(|t| &t.tile[..]).collect();
+        assert_eq!(terrain, vec!["terrain/grass", "terrain/grass", "terrain/water", "terrain/water",
+                                 "terrain/grass", "terrain/grass", "terrain/water", "terrain/water",
+                                 "terrain/trees2", "terrain/trees2", "terrain/trees2", "terrain/trees2"]);
+        assert_eq!(map.start(), (0, 0));
+        let sign: Vec<_> = map.objects.iter().filter(|o| o.get_path() == "scenery/sign1").map(|o| o.get_location()).collect();
+        assert_eq!(sign, vec![5]);
+        let teleporter = map.teleporter.get(&1).unwrap();
+        assert_eq!((&teleporter.map[..], teleporter.target()), ("main", Some((20, 15))));
+    }
+
+    #[test]
+    fn converts_tmx() {
+        let map = convert(&tmx("1,1,7,7,\n1,1,7,7,\n5,5,5,5", 20)).unwrap();
+        check_small_map(&map);
+    }
+
+    #[test]
+    fn converts_json() {
+        let map = convert(&TiledMap::parse_json(JSON).unwrap()).unwrap();
+        check_small_map(&map);
+    }
+
+    #[test]
+    fn loads_the_example_map() {
+        let map = load("maps/meadow.tmx").unwrap();
+        assert_eq!((map.width, map.height), (10, 8));
+        assert_eq!(map.start(), (1, 4));
+        assert_eq!(map.teleporter.get(&(3 * 10 + 9)).unwrap().map, "main");
+    }
+
+    #[test]
+    fn reports_tiles_missing_from_the_mappings() {
+        let error = convert(&tmx("1,1,7,7,\n1,99,7,7,\n5,5,5,5", 20)).err().unwrap();
+        assert_eq!(error.to_string(), "Tiled map: layer ground at 1 1: tile 98 of tileset wyvern isn't in tiled.txt");
+    }
+
+    #[test]
+    fn reports_layers_of_the_wrong_size() {
+        let error = convert(&tmx("1,1,7,7,\n1,1,7,7", 20)).err().unwrap();
+        assert_eq!(error.to_string(), "Tiled map: layer ground has 8 tiles, but the map is 4x3");
+    }
+
+    #[test]
+    fn refuses_maps_too_big() {
+        let mut tiled = TiledMap::new();
+        tiled.width = 100000;
+        tiled.height = 100000;
+        let error = convert(&tiled).err().unwrap();
+        assert_eq!(error.to_string(), "Tiled map: the map is 100000x100000, which is too big. Maps can have up to 4194304 tiles.");
+        //Fits in a u32, but is still far too many tiles
+        tiled.width = 60000;
+        tiled.height = 60000;
+        let error = convert(&tiled).err().unwrap();
+        assert_eq!(error.to_string(), "Tiled map: the map is 60000x60000, which is too big. Maps can have up to 4194304 tiles.");
+    }
+
+    #[test]
+    fn says_parser_errors_are_in_the_converted_map() {
+        let error = convert(&tmx("1,1,7,7,\n1,1,7,7,\n5,5,5,5", -1)).err().unwrap();
+        match error {
+            MapError::Tiled(ref message) => {
+                assert!(message.starts_with("the map made from it is broken, line "), "{}", message);
+                assert!(message.ends_with("teleporter x of -1 is off the map"), "{}", message);
+            },
+            _ => panic!("should be a Tiled error, not {}", error),
+        }
+    }
+}
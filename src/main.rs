@@ -16,22 +16,62 @@ limitations under the License.*/
 
 pub mod game;
 pub mod conn;
+pub mod tools;
 
 extern crate mio;
 extern crate flate2;
 extern crate time;
 extern crate xml;
 extern crate glob;
+extern crate rustc_serialize;
+#[macro_use]
+extern crate lazy_static;
 
 use conn::server::Server;
 
 use mio::tcp::*;
 use std::net::SocketAddr;
+use std::env;
+use std::process;
 
 
 /// This is the source for a MOBA server that is compatible with a preexisting game client. 
 
 fn main() {
+    //With a subcommand this runs one of the map tools instead of the server
+    let args: Vec<String> = env::args().collect();
+    let result = match args.get(1).map(|a| &a[..]) {
+        Some("render") => tools::render::run(&args[2..]),
+        Some("ascii") => tools::ascii::run(&args[2..]),
+        Some("check") => tools::check::run(&args[2..]),
+        Some("--art") => {
+            //Where the stock art is, for sizing objects
+            match args.get(2) {
+                Some(dir) => {
+                    game::characters::item::set_art_dir(dir);
+                    serve();
+                    Ok(())
+                },
+                None => Err("--art needs a directory".to_string()),
+            }
+        },
+        Some(other) => Err(format!("Unknown subcommand {}. Try render, ascii or check, or nothing to start the server.", other)),
+        None => {
+            serve();
+            Ok(())
+        },
+    };
+    match result {
+        Ok(_) => {},
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        },
+    }
+}
+
+///Starts the game server
+fn serve() {
     //This section starts up a tcp socket listening on port 2222, per the client docs
     println!("starting");
     let addr: SocketAddr = "0.0.0.0:2222".parse().unwrap();
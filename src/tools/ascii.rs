@@ -0,0 +1,141 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module prints a map as text, one character per tile. Handy over ssh, and for checking
+/// how parse_tiles read the loc rectangles & oob terrain.
+///
+/// moba ascii <map>
+
+use game::gamemap::GameMap;
+use game::characters::ControllableType;
+use tools::map_path;
+
+///What each character means, in the order they win when a tile has more than one thing on it
+pub const LEGEND: [(char, &'static str); 9] = [
+    ('S', "start point"),
+    ('T', "teleporter"),
+    ('@', "player"),
+    ('D', "door"),
+    ('W', "wall"),
+    ('=', "road"),
+    ('i', "item"),
+    ('#', "blocked terrain"),
+    ('.', "open terrain"),
+];
+
+///Reads the arguments after "ascii" & prints the map
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.len() != 1 {
+        return Err("Usage: moba ascii <map>".to_string());
+    }
+    let path = map_path(&args[0]);
+    let map = GameMap::new(&path).map_err(|e| format!("{}: {}", path, e))?;
+    println!("{} ({}x{})", path, map.width, map.height);
+    print!("{}", render(&map));
+    Ok(())
+}
+
+///Builds the text for a map, with rulers along the top & left and the legend underneath
+pub fn render(map: &GameMap) -> String {
+    let width = map.width;
+    let height = map.height;
+    let mut out = String::new();
+    //Two ruler rows, tens then ones, so wide maps still line up
+    out.push_str("    ");
+    for x in 0..width {
+        out.push(if x % 10 == 0 { digit(x / 10 % 10) } else { ' ' });
+    }
+    out.push('\n');
+    out.push_str("    ");
+    for x in 0..width {
+        out.push(digit(x % 10));
+    }
+    out.push('\n');
+    for y in 0..height {
+        out.push_str(&format!("{:>3} ", y));
+        for x in 0..width {
+            out.push(tile_char(map, y * width + x));
+        }
+        out.push('\n');
+    }
+    out.push('\n');
+    for &(c, meaning) in LEGEND.iter() {
+        out.push_str(&format!("  {} {}\n", c, meaning));
+    }
+    out
+}
+
+///Picks the character for a tile, going down the legend
+fn tile_char(map: &GameMap, index: u32) -> char {
+    let (start_x, start_y) = map.start();
+    if index == start_y * map.width + start_x {
+        return 'S';
+    }
+    if map.teleporter.contains_key(&index) {
+        return 'T';
+    }
+    let mut found = '.';
+    let mut rank = LEGEND.len();
+    for object in map.objects.iter() {
+        if object.get_location() != index {
+            continue;
+        }
+        let c = match object.get_type() {
+            ControllableType::Player => '@',
+            ControllableType::Wall => 'W',
+            ControllableType::Road => '=',
+            ControllableType::Item => 'i',
+            ControllableType::Teleporter => 'T',
+            ControllableType::Door => 'D',
+        };
+        let r = LEGEND.iter().position(|&(l, _)| l == c).unwrap();
+        if r < rank {
+            rank = r;
+            found = c;
+        }
+    }
+    if found == '.' && map.tiles[index as usize].blocked {
+        '#'
+    } else {
+        found
+    }
+}
+
+fn digit(n: u32) -> char {
+    ::std::char::from_digit(n, 10).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use game::gamemap::GameMap;
+
+    ///The character at x,y, skipping the two ruler rows & the row labels
+    fn at(text: &str, x: usize, y: usize) -> char {
+        text.lines().nth(y + 2).unwrap().chars().nth(x + 4).unwrap()
+    }
+
+    #[test]
+    fn marks_start_and_teleporter() {
+        let map = GameMap::new("maps/main.map").unwrap();
+        let text = render(&map);
+        assert_eq!(at(&text, 20, 15), 'S', "\n{}", text);
+        assert_eq!(at(&text, 10, 5), 'T', "\n{}", text);
+        //Only the grid counts, not the legend
+        let grid: String = text.lines().skip(2).take(map.height as usize).map(|l| &l[4..]).collect();
+        assert_eq!(grid.matches('S').count(), 1, "\n{}", text);
+        assert_eq!(grid.matches('T').count(), map.teleporter.len(), "\n{}", text);
+    }
+}
@@ -0,0 +1,244 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module checks maps for problems before anyone has to walk around them. It parses every
+/// map it is given and reports unknown art, broken teleporters & blocked start points. The exit
+/// code is non zero if anything is wrong, so it can gate merges.
+///
+/// moba check [maps/ or map files...]
+
+use game::Game;
+use game::gamemap::GameMap;
+use conn::roles::Role;
+use tools::map_path;
+
+use glob::glob;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+///Reads the arguments after "check", checks every map & prints a report
+pub fn run(args: &[String]) -> Result<(), String> {
+    let targets = if args.len() == 0 { vec!["maps".to_string()] } else { args.to_vec() };
+    let mut paths = vec![];
+    for target in targets.iter() {
+        if Path::new(target).is_dir() {
+            for extension in ["map", "tmx", "json"].iter() {
+                let pattern = Path::new(target).join("**").join(format!("*.{}", extension));
+                for entry in glob(pattern.to_str().unwrap()).unwrap() {
+                    match entry {
+                        Ok(path) => paths.push(path.to_str().unwrap().to_string()),
+                        _ => {},
+                    }
+                }
+            }
+        } else {
+            paths.push(map_path(target));
+        }
+    }
+    if paths.len() == 0 {
+        return Err("No maps found".to_string());
+    }
+    let mut checker = Checker::new();
+    let mut failed = 0;
+    for path in paths.iter() {
+        let problems = checker.check(path);
+        if problems.len() == 0 {
+            println!("{}: OK", path);
+        } else {
+            failed = failed + 1;
+            println!("{}: {} problem(s)", path, problems.len());
+            for problem in problems.iter() {
+                println!("  {}", problem);
+            }
+        }
+    }
+    if failed > 0 {
+        Err(format!("{} of {} maps have problems", failed, paths.len()))
+    } else {
+        println!("All {} maps are OK", paths.len());
+        Ok(())
+    }
+}
+
+///Holds what is shared between maps, so the mappings are only read once and teleporter targets
+///only get parsed the first time they come up
+pub struct Checker {
+    mappings: HashMap<String, i16>,
+    maps: HashMap<String, Result<GameMap, String>>,
+}
+
+impl Checker {
+    pub fn new() -> Checker {
+        Checker {
+            mappings: Game::create_mappings(),
+            maps: HashMap::new(),
+        }
+    }
+
+    ///Parses a map, or gets the copy from the last time it was asked for
+    fn load(&mut self, path: &str) -> Result<GameMap, String> {
+        if !self.maps.contains_key(path) {
+            let map = GameMap::new(path).map_err(|e| e.to_string());
+            self.maps.insert(path.to_string(), map);
+        }
+        self.maps.get(path).unwrap().clone()
+    }
+
+    ///Checks one map. Returns a line for every problem found.
+    pub fn check(&mut self, path: &str) -> Vec<String> {
+        let map = match self.load(path) {
+            Ok(map) => map,
+            Err(e) => {
+                return vec![format!("failed to parse: {}", e)];
+            },
+        };
+        let mut problems = vec![];
+        let width = map.width;
+        //Unknown art. Each path is reported once, at the first place it shows up.
+        let mut unknown: Vec<(String, u32, u32)> = vec![];
+        for (index, tile) in map.tiles.iter().enumerate() {
+            let art = (*tile.art).clone();
+            if !self.mappings.contains_key(&art) && !unknown.iter().any(|u| u.0 == art) {
+                unknown.push((art, index as u32 % width, index as u32 / width));
+            }
+        }
+        for object in map.objects.iter() {
+            if !object.is_visible(&map, Role::Admin) {
+                continue;
+            }
+            let art = object.get_tile();
+            let index = object.get_location();
+            if !self.mappings.contains_key(&art) && !unknown.iter().any(|u| u.0 == art) {
+                unknown.push((art, index % width, index / width));
+            }
+        }
+        for (art, x, y) in unknown {
+            problems.push(format!("unknown art {} at {} {}", art, x, y));
+        }
+        //Start point
+        let (start_x, start_y) = map.start();
+        if start_x >= map.width || start_y >= map.height {
+            problems.push(format!("start point {} {} is off the map", start_x, start_y));
+        } else if !map.is_open(start_y * width + start_x) {
+            problems.push(format!("start point {} {} is blocked", start_x, start_y));
+        }
+        //Teleporters
+        let mut teleporters: Vec<_> = map.teleporter.iter().map(|(i, t)| (*i, t.clone())).collect();
+        teleporters.sort_by_key(|t| t.0);
+        for (index, teleporter) in teleporters {
+            let (x, y) = (index % width, index / width);
+            let (name, _) = Game::split_instance(&teleporter.map);
+            let target = Game::loop_name(name);
+            match self.load(&target) {
+                Ok(other) => {
+                    let (tx, ty) = match teleporter.target() {
+                        Some(spot) => spot,
+                        None => other.start(),
+                    };
+                    if tx >= other.width || ty >= other.height {
+                        problems.push(format!("teleporter at {} {} goes to {} {} on {}, which is off the {}x{} map",
+                                              x, y, tx, ty, teleporter.map, other.width, other.height));
+                    } else if !other.is_open(ty * other.width + tx) {
+                        problems.push(format!("teleporter at {} {} goes to {} {} on {}, which is blocked",
+                                              x, y, tx, ty, teleporter.map));
+                    }
+                },
+                Err(e) => {
+                    problems.push(format!("teleporter at {} {} goes to {}, which can't be loaded: {}",
+                                          x, y, teleporter.map, e));
+                },
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checker;
+    use game::gamemap::GameMap;
+
+    ///A 3x3 grass map
+    fn xml(start: (u32, u32), body: &str) -> String {
+        GameMap::test(3, 3).oob("terrain/empty").start(start.0, start.1).arch(body).xml()
+    }
+
+    ///A 3x3 map that is blocked at x,y. Tiles left as the oob terrain are blocked, so the header
+    ///uses it & every other tile is painted grass.
+    fn blocked_at(start: (u32, u32), x: u32, y: u32, body: &str) -> String {
+        let mut map = GameMap::test(3, 3).oob("terrain/empty").terrain("terrain/empty").start(start.0, start.1);
+        for i in 0..9 {
+            if i != y * 3 + x {
+                map = map.paint(i % 3, i / 3, "terrain/grass");
+            }
+        }
+        map.arch(body).xml()
+    }
+
+    fn teleporter(x: u32, y: u32, map: &str, tx: u32, ty: u32) -> String {
+        format!("<arch loc=\"{} {}\" path=\"special/teleporter\">\n\
+                 <string name=\"map\" value=\"{}\"/><int name=\"x\" value=\"{}\"/><int name=\"y\" value=\"{}\"/>\n\
+                 </arch>\n", x, y, map, tx, ty)
+    }
+
+    ///Checks maps that only live in memory, by putting them where the checker caches parsed maps
+    fn check(maps: &[(&str, String)]) -> Vec<String> {
+        let mut checker = Checker::new();
+        for &(path, ref xml) in maps.iter() {
+            let map = GameMap::from_xml(path, xml).map_err(|e| e.to_string());
+            checker.maps.insert(path.to_string(), map);
+        }
+        checker.check(maps[0].0)
+    }
+
+    #[test]
+    fn passes_a_good_map() {
+        let target = xml((0, 0), "");
+        let problems = check(&[("maps/checkhome.map", xml((0, 0), &teleporter(2, 2, "checktarget", 1, 1))),
+                               ("maps/checktarget.map", target)]);
+        assert_eq!(problems, Vec::<String>::new());
+    }
+
+    #[test]
+    fn finds_unknown_art() {
+        let problems = check(&[("maps/checkhome.map",
+                                xml((0, 0), "<arch loc=\"1 1\" path=\"terrain/no_such_art\"/>\n<arch loc=\"2 2\" path=\"scenery/no_such_art\"/>\n"))]);
+        assert_eq!(problems, vec!["unknown art terrain/no_such_art at 1 1", "unknown art scenery/no_such_art at 2 2"]);
+    }
+
+    #[test]
+    fn finds_missing_teleporter_map() {
+        let problems = check(&[("maps/checkhome.map", xml((0, 0), &teleporter(2, 2, "no_such_map_here", 0, 0)))]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("teleporter at 2 2 goes to no_such_map_here, which can't be loaded"), "{:?}", problems);
+    }
+
+    #[test]
+    fn finds_bad_teleporter_targets() {
+        let target = blocked_at((0, 0), 1, 1, "");
+        let body = format!("{}{}", teleporter(1, 2, "checktarget", 1, 1), teleporter(2, 2, "checktarget", 7, 0));
+        let problems = check(&[("maps/checkhome.map", xml((0, 0), &body)),
+                               ("maps/checktarget.map", target)]);
+        assert_eq!(problems, vec!["teleporter at 1 2 goes to 1 1 on checktarget, which is blocked",
+                                  "teleporter at 2 2 goes to 7 0 on checktarget, which is off the 3x3 map"]);
+    }
+
+    #[test]
+    fn finds_blocked_start() {
+        let problems = check(&[("maps/checkhome.map", blocked_at((1, 1), 1, 1, ""))]);
+        assert_eq!(problems, vec!["start point 1 1 is blocked"]);
+    }
+}
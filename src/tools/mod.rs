@@ -0,0 +1,32 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+///Command line tools for working on maps without starting the server. main picks one of these
+///when it is given a subcommand.
+pub mod render;
+pub mod ascii;
+pub mod check;
+
+use game::Game;
+use game::tiled;
+
+///Lets the tools take either a path to a map file or the short name used by join
+pub fn map_path(map: &str) -> String {
+    if map.ends_with(".map") || tiled::is_tiled(map) {
+        map.to_string()
+    } else {
+        Game::loop_name(map)
+    }
+}
@@ -0,0 +1,360 @@
+/*
+  Copyright 2016 Robert Lathrop
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.*/
+
+/// This module draws a whole map into a png. It uses the same terrain & object art the client
+/// would show, pulled from a local copy of the stock image tree plus the custom images/ directory.
+///
+/// moba render <map> <out.png> [--art <dir>] [--grid] [--blocked] [--teleporters]
+
+extern crate image;
+
+use game::gamemap::GameMap;
+use game::characters::item;
+use conn::roles::Role;
+use tools::map_path;
+
+use glob::glob;
+use self::image::{Rgba, RgbaImage};
+use self::image::imageops;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+///Size of a tile in the stock art
+pub const TILE_SIZE: u32 = 32;
+
+///Largest png render will make. 8192x8192 RGBA is already 256MB, and the biggest maps would be far
+///more than that.
+pub const MAX_PIXELS: u64 = 1 << 26;
+
+const PLACEHOLDER: Rgba<u8> = Rgba { data: [255, 0, 255, 255] };
+const GRID: Rgba<u8> = Rgba { data: [0, 0, 0, 255] };
+const LABEL: Rgba<u8> = Rgba { data: [255, 255, 255, 255] };
+const BLOCKED: Rgba<u8> = Rgba { data: [255, 0, 0, 255] };
+const TELEPORTER: Rgba<u8> = Rgba { data: [0, 128, 255, 255] };
+
+///What to draw on top of the art
+pub struct Options {
+    pub art: String,
+    pub grid: bool,
+    pub blocked: bool,
+    pub teleporters: bool,
+}
+
+///Loads & caches art by the path the client is sent. Stock art is looked up under the art
+///directory, custom images by name anywhere under images/.
+pub struct Art {
+    dir: String,
+    custom: HashMap<String, String>,
+    cache: HashMap<String, Option<RgbaImage>>,
+    pub missing: Vec<String>,
+}
+
+impl Art {
+    pub fn new(dir: &str) -> Art {
+        let mut custom = HashMap::new();
+        for entry in glob("images/**/*.gif").unwrap() {
+            match entry {
+                Ok(img) => {
+                    let name = img.file_stem().unwrap().to_str().unwrap().to_string();
+                    custom.insert(name, img.to_str().unwrap().to_string());
+                },
+                _ => {},
+            }
+        }
+        Art {
+            dir: dir.to_string(),
+            custom: custom,
+            cache: HashMap::new(),
+            missing: vec![],
+        }
+    }
+
+    ///Finds the file for a path, if there is one
+    pub fn find(&self, path: &str) -> Option<String> {
+        match self.custom.get(path) {
+            Some(file) => {
+                return Some(file.clone());
+            },
+            None => {},
+        }
+        for ext in ["gif", "png"].iter() {
+            let file = format!("{}/{}.{}", self.dir, path, ext);
+            if Path::new(&file).exists() {
+                return Some(file);
+            }
+        }
+        None
+    }
+
+    ///Gets the image for a path. Anything that can't be found is remembered in missing.
+    pub fn get(&mut self, path: &str) -> Option<&RgbaImage> {
+        if !self.cache.contains_key(path) {
+            let loaded = match self.find(path) {
+                Some(file) => {
+                    match image::open(&file) {
+                        Ok(img) => Some(img.to_rgba()),
+                        Err(e) => {
+                            println!("Failed to load {}: {}", file, e);
+                            None
+                        },
+                    }
+                },
+                None => None,
+            };
+            if loaded.is_none() {
+                self.missing.push(path.to_string());
+            }
+            self.cache.insert(path.to_string(), loaded);
+        }
+        self.cache.get(path).unwrap().as_ref()
+    }
+}
+
+///Reads the arguments after "render" & writes the png
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut files = vec![];
+    let mut options = Options {
+        art: item::art_dir(),
+        grid: false,
+        blocked: false,
+        teleporters: false,
+    };
+    let mut i = 0;
+    while i < args.len() {
+        match &args[i][..] {
+            "--grid" => options.grid = true,
+            "--blocked" => options.blocked = true,
+            "--teleporters" => options.teleporters = true,
+            "--art" => {
+                i = i + 1;
+                match args.get(i) {
+                    Some(dir) => options.art = dir.clone(),
+                    None => {
+                        return Err("--art needs a directory".to_string());
+                    },
+                }
+            },
+            arg => files.push(arg.to_string()),
+        }
+        i = i + 1;
+    }
+    if files.len() != 2 {
+        return Err("Usage: moba render <map> <out.png> [--art <dir>] [--grid] [--blocked] [--teleporters]".to_string());
+    }
+    //Objects are sized from the same art that gets drawn
+    item::set_art_dir(&options.art);
+    let path = map_path(&files[0]);
+    let map = GameMap::new(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut art = Art::new(&options.art);
+    let img = render(&map, &mut art, &options).map_err(|e| format!("{}: {}", path, e))?;
+    match img.save(&files[1]) {
+        Ok(_) => {},
+        Err(e) => {
+            return Err(format!("Failed to write {}: {}", files[1], e));
+        },
+    }
+    println!("Rendered {} ({}x{}) to {}", path, map.width, map.height, files[1]);
+    if art.missing.len() > 0 {
+        println!("{} images were not found and show as placeholders:", art.missing.len());
+        for missing in art.missing.iter() {
+            println!("  {}", missing);
+        }
+    }
+    Ok(())
+}
+
+///Draws the map. Terrain goes down first, then every visible object, then the overlays. Maps that
+///would make a png over MAX_PIXELS are refused before anything is allocated.
+pub fn render(map: &GameMap, art: &mut Art, options: &Options) -> Result<RgbaImage, String> {
+    let width = map.width;
+    let height = map.height;
+    let pixels = width as u64 * TILE_SIZE as u64 * height as u64 * TILE_SIZE as u64;
+    if pixels > MAX_PIXELS {
+        return Err(format!("the map is {}x{} tiles, which would be a {}x{} png. Renders can have up to {} pixels \
+                            (8192x8192).", width, height, width as u64 * TILE_SIZE as u64,
+                           height as u64 * TILE_SIZE as u64, MAX_PIXELS));
+    }
+    let mut img = RgbaImage::from_pixel(width * TILE_SIZE, height * TILE_SIZE, Rgba([0, 0, 0, 255]));
+    for (index, tile) in map.tiles.iter().enumerate() {
+        let x = index as u32 % width * TILE_SIZE;
+        let y = index as u32 / width * TILE_SIZE;
+        let ref path = tile.art;
+        match art.get(&path) {
+            Some(terrain) => {
+                imageops::overlay(&mut img, terrain, x, y);
+            },
+            None => {
+                fill(&mut img, x, y, TILE_SIZE, TILE_SIZE, PLACEHOLDER, 255);
+            },
+        }
+    }
+    for object in map.objects.iter() {
+        if !object.is_visible(map, Role::Admin) {
+            continue;
+        }
+        let index = object.get_location();
+        let x = index % width * TILE_SIZE;
+        let y = index / width * TILE_SIZE;
+        let path = object.get_tile();
+        match art.get(&path) {
+            Some(sprite) => {
+                imageops::overlay(&mut img, sprite, x, y);
+            },
+            None => {
+                fill(&mut img, x + 8, y + 8, TILE_SIZE / 2, TILE_SIZE / 2, PLACEHOLDER, 255);
+            },
+        }
+    }
+    if options.blocked {
+        for index in 0..map.tiles.len() as u32 {
+            let blocked = map.tiles[index as usize].blocked
+                || map.objects.iter().any(|o| o.does_block_index(index));
+            if blocked {
+                fill(&mut img, index % width * TILE_SIZE, index / width * TILE_SIZE, TILE_SIZE, TILE_SIZE, BLOCKED, 96);
+            }
+        }
+    }
+    if options.teleporters {
+        for index in map.teleporter.keys() {
+            let x = index % width * TILE_SIZE;
+            let y = index / width * TILE_SIZE;
+            fill(&mut img, x, y, TILE_SIZE, TILE_SIZE, TELEPORTER, 96);
+            outline(&mut img, x, y, TILE_SIZE, TELEPORTER, 2);
+        }
+    }
+    if options.grid {
+        for ty in 0..height {
+            for tx in 0..width {
+                outline(&mut img, tx * TILE_SIZE, ty * TILE_SIZE, TILE_SIZE, GRID, 1);
+                //Labelling every tile is too busy, so only every fifth one gets its coordinates
+                if tx % 5 == 0 && ty % 5 == 0 {
+                    text(&mut img, tx * TILE_SIZE + 2, ty * TILE_SIZE + 2, &format!("{},{}", tx, ty));
+                }
+            }
+        }
+    }
+    Ok(img)
+}
+
+///Blends a color over a rectangle. An alpha of 255 just paints it.
+fn fill(img: &mut RgbaImage, x: u32, y: u32, w: u32, h: u32, color: Rgba<u8>, alpha: u32) {
+    for py in y..(y + h) {
+        for px in x..(x + w) {
+            if px >= img.width() || py >= img.height() {
+                continue;
+            }
+            let mut pixel = *img.get_pixel(px, py);
+            for c in 0..3 {
+                pixel.data[c] = ((pixel.data[c] as u32 * (255 - alpha) + color.data[c] as u32 * alpha) / 255) as u8;
+            }
+            img.put_pixel(px, py, pixel);
+        }
+    }
+}
+
+///Draws a square border of the given thickness
+fn outline(img: &mut RgbaImage, x: u32, y: u32, size: u32, color: Rgba<u8>, thickness: u32) {
+    fill(img, x, y, size, thickness, color, 255);
+    fill(img, x, y + size - thickness, size, thickness, color, 255);
+    fill(img, x, y, thickness, size, color, 255);
+    fill(img, x + size - thickness, y, thickness, size, color, 255);
+}
+
+///3x5 digits for the grid labels, one row per 3 bits from the top. The last one is the comma.
+const GLYPHS: [[u8; 5]; 11] = [
+    [7, 5, 5, 5, 7],
+    [2, 6, 2, 2, 7],
+    [7, 1, 7, 4, 7],
+    [7, 1, 7, 1, 7],
+    [5, 5, 7, 1, 1],
+    [7, 4, 7, 1, 7],
+    [7, 4, 7, 5, 7],
+    [7, 1, 1, 1, 1],
+    [7, 5, 7, 5, 7],
+    [7, 5, 7, 1, 7],
+    [0, 0, 0, 2, 4],
+];
+
+///Writes digits & commas with a dark backing so they show up on any terrain
+fn text(img: &mut RgbaImage, x: u32, y: u32, s: &str) {
+    fill(img, x, y, s.len() as u32 * 4 + 1, 7, GRID, 160);
+    for (i, c) in s.chars().enumerate() {
+        let glyph = match c.to_digit(10) {
+            Some(d) => GLYPHS[d as usize],
+            None => GLYPHS[10],
+        };
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (4 >> col) != 0 {
+                    let px = x + 1 + i as u32 * 4 + col;
+                    let py = y + 1 + row as u32;
+                    if px < img.width() && py < img.height() {
+                        img.put_pixel(px, py, LABEL);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use game::gamemap::GameMap;
+
+    fn options() -> Options {
+        Options {
+            art: "no-such-art".to_string(),
+            grid: false,
+            blocked: false,
+            teleporters: false,
+        }
+    }
+
+    #[test]
+    fn draws_placeholders_for_missing_art() {
+        let map = GameMap::test(3, 2).build();
+        let mut art = Art::new("no-such-art");
+        let img = render(&map, &mut art, &options()).unwrap();
+        assert_eq!((img.width(), img.height()), (3 * TILE_SIZE, 2 * TILE_SIZE));
+        assert_eq!(*img.get_pixel(TILE_SIZE * 2 + 5, TILE_SIZE + 5), PLACEHOLDER);
+        //Each missing image is only listed once
+        assert_eq!(art.missing.len(), 1);
+    }
+
+    #[test]
+    fn shades_blocked_tiles() {
+        //Tiles left as the oob terrain are blocked
+        let map = GameMap::test(2, 1).oob("terrain/empty").terrain("terrain/empty").build();
+        let mut art = Art::new("no-such-art");
+        let mut options = options();
+        options.blocked = true;
+        let img = render(&map, &mut art, &options).unwrap();
+        //Red over the magenta placeholder takes some of the blue out
+        assert_eq!(*img.get_pixel(5, 5), Rgba([255, 0, 159, 255]));
+    }
+
+    #[test]
+    fn refuses_maps_too_big() {
+        let map = GameMap::test(300, 300).build();
+        let mut art = Art::new("no-such-art");
+        assert_eq!(render(&map, &mut art, &options()).unwrap_err(),
+                   "the map is 300x300 tiles, which would be a 9600x9600 png. Renders can have up to 67108864 \
+                    pixels (8192x8192).");
+        //Nothing was looked up
+        assert!(art.missing.is_empty());
+    }
+}